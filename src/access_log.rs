@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use log::info;
+use crate::{ChunkTransaction, TransactionKind};
+
+///Log target access entries are written under, loggers can route it somewhere of its own
+pub const ACCESS_TARGET: &str = "basic_udp::access";
+
+///Most separate ranges kept for one entry, past that the closest ones are merged
+const MAX_RANGES: usize = 32;
+
+///Rolls the requests a client makes for one file up into a single access log entry per transfer
+///
+///A transfer starts with the first request for a file and ends once the client has been quiet about it
+///for the coalescing window, then one entry is logged with everything that was requested and sent
+pub struct AccessLog {
+    window: Duration,
+    entries: Mutex<HashMap<(SocketAddr, &'static str, String), Entry>>,
+}
+
+///Everything known about one transfer so far
+struct Entry {
+    started: Instant,
+    last: Instant,
+    requests: u64,
+    ranges: Ranges,
+    packets: u64,
+    bytes: u64,
+    allowed: bool,
+    errors: u64,
+}
+
+///Sorted, non overlapping chunk ranges
+struct Ranges(Vec<(u64, u64)>);
+
+impl AccessLog {
+    ///Requests for the same file less than window apart belong to the same transfer, a window of 0 logs every request
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    ///Add a serviced transaction to its transfer, failed if servicing it returned an error
+    pub fn record(&self, t: &ChunkTransaction, failed: bool) {
+        let kind = match t.kind {
+            TransactionKind::List(_) => "list",
+            //Closing is bookkeeping, the transfer was already logged through its other requests
+            TransactionKind::SessionClose(_, _) => return,
+            _ => "file",
+        };
+        let now = Instant::now();
+        let mut entries = match self.entries.lock() {
+            Ok(e) => e,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let entry = entries.entry((t.target, kind, t.filename.clone())).or_insert_with(|| Entry {
+            started: now,
+            last: now,
+            requests: 0,
+            ranges: Ranges(Vec::new()),
+            packets: 0,
+            bytes: 0,
+            allowed: false,
+            errors: 0,
+        });
+        entry.last = now;
+        entry.requests += 1;
+        //Hash requests name chunks that aren't sent and node requests name tree nodes, neither belongs in the chunk ranges
+        if !t.hashes && !t.nodes {
+            for (s, e) in t.starts.iter().zip(t.ends.iter()) {
+                entry.ranges.add(*s, *e);
+            }
+        }
+        entry.packets += t.packets_sent;
+        entry.bytes += t.bytes_sent;
+        entry.allowed |= t.allowed;
+        if failed {
+            entry.errors += 1;
+        }
+        if self.window.is_zero() {
+            if let Some(entry) = entries.remove(&(t.target, kind, t.filename.clone())) {
+                emit(t.target, kind, &t.filename, &entry);
+            }
+        }
+    }
+
+    ///Log the transfers that have gone quiet
+    pub fn sweep(&self) {
+        let mut entries = match self.entries.lock() {
+            Ok(e) => e,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let window = self.window;
+        entries.retain(|(peer, kind, filename), entry| {
+            let done = entry.last.elapsed() >= window;
+            if done {
+                emit(*peer, kind, filename, entry);
+            }
+            !done
+        });
+    }
+
+    ///Log every open transfer, for when the server stops
+    pub fn flush(&self) {
+        let mut entries = match self.entries.lock() {
+            Ok(e) => e,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        for ((peer, kind, filename), entry) in entries.drain() {
+            emit(peer, kind, &filename, &entry);
+        }
+    }
+}
+
+fn emit(peer: SocketAddr, kind: &str, filename: &str, entry: &Entry) {
+    let status = if entry.allowed { "allowed" } else { "denied" };
+    let duration_ms = entry.last.duration_since(entry.started).as_millis() as u64;
+    info!(target: ACCESS_TARGET, peer:% = peer, kind = kind, filename = filename, status = status, requests = entry.requests,
+        ranges:% = entry.ranges, packets = entry.packets, bytes = entry.bytes, errors = entry.errors, duration_ms = duration_ms; "Transfer");
+}
+
+impl Ranges {
+    ///Union start..=end into the set
+    fn add(&mut self, start: u64, end: u64) {
+        let (start, end) = (start.min(end), start.max(end));
+        let i = self.0.partition_point(|r| r.1.saturating_add(1) < start);
+        let mut merged = (start, end);
+        while i < self.0.len() && self.0[i].0 <= merged.1.saturating_add(1) {
+            merged = (merged.0.min(self.0[i].0), merged.1.max(self.0[i].1));
+            self.0.remove(i);
+        }
+        self.0.insert(i, merged);
+        //Keep a scattered client from growing an entry forever, close the smallest gap instead
+        while self.0.len() > MAX_RANGES {
+            let mut closest = 0;
+            for j in 1..self.0.len()-1 {
+                if self.0[j+1].0 - self.0[j].1 < self.0[closest+1].0 - self.0[closest].1 {
+                    closest = j;
+                }
+            }
+            let next = self.0.remove(closest+1);
+            self.0[closest].1 = next.1;
+        }
+    }
+}
+
+impl fmt::Display for Ranges {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ranges: Vec<String> = self.0.iter().map(|(s, e)| format!("{}-{}", s, e)).collect();
+        write!(f, "[{}]", ranges.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transaction(kind: TransactionKind, filename: &str, ranges: &[(u64, u64)]) -> ChunkTransaction {
+        let mut t = ChunkTransaction::new(kind, "127.0.0.1:5000".parse().unwrap(), String::from(filename));
+        for (s, e) in ranges.iter() {
+            t.starts.push_back(*s);
+            t.ends.push_back(*e);
+            t.packets_sent += e-s+1;
+            t.bytes_sent += (e-s+1)*504;
+        }
+        t.allowed = true;
+        t
+    }
+
+    #[test]
+    fn range_requests_for_a_file_roll_up_into_one_entry() {
+        let log = AccessLog::new(Duration::from_secs(60));
+        for start in (0..10).rev().map(|i| i*10) {
+            log.record(&transaction(TransactionKind::Chunks, "file.bin", &[(start, start+9)]), start == 50);
+        }
+        log.record(&transaction(TransactionKind::Chunks, "file.bin", &[(150, 159), (120, 129), (130, 139)]), false);
+        //Hashes are of chunks that weren't sent
+        let mut hashes = transaction(TransactionKind::Chunks, "file.bin", &[(500, 600)]);
+        hashes.hashes = true;
+        hashes.packets_sent = 1;
+        hashes.bytes_sent = 504;
+        log.record(&hashes, false);
+        log.record(&transaction(TransactionKind::List(0), "file.bin", &[]), false);
+        log.record(&transaction(TransactionKind::Chunks, "other.bin", &[(0, 0)]), false);
+        log.record(&transaction(TransactionKind::SessionClose(1, 0), "", &[]), false);
+
+        let key = |kind: &'static str, filename: &str| ("127.0.0.1:5000".parse::<SocketAddr>().unwrap(), kind, String::from(filename));
+        let mut entries = log.entries.lock().unwrap();
+        assert_eq!(entries.len(), 3);
+        let entry = entries.get_mut(&key("file", "file.bin")).unwrap();
+        assert_eq!(entry.requests, 12);
+        assert_eq!(entry.ranges.to_string(), "[0-99,120-139,150-159]");
+        assert_eq!(entry.packets, 131);
+        assert_eq!(entry.bytes, 131*504);
+        assert!(entry.allowed);
+        assert_eq!(entry.errors, 1);
+
+        //Only transfers that went quiet for the window are logged and forgotten
+        entry.last = Instant::now().checked_sub(Duration::from_secs(61)).unwrap();
+        drop(entries);
+        log.sweep();
+        let entries = log.entries.lock().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(!entries.contains_key(&key("file", "file.bin")));
+        assert!(entries.contains_key(&key("list", "file.bin")));
+    }
+
+    #[test]
+    fn no_window_logs_every_request() {
+        let log = AccessLog::new(Duration::from_secs(0));
+        log.record(&transaction(TransactionKind::Chunks, "file.bin", &[(0, 9)]), false);
+        assert!(log.entries.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn scattered_ranges_stay_bounded() {
+        let mut ranges = Ranges(Vec::new());
+        for i in 0..MAX_RANGES as u64+8 {
+            ranges.add(i*10, i*10+1);
+        }
+        ranges.add(395, 392);
+        assert_eq!(ranges.0.len(), MAX_RANGES);
+        //Gaps are all the same, the first ones are closed
+        assert_eq!(ranges.0[0], (0, 81));
+        assert_eq!(ranges.0[1], (90, 91));
+        assert_eq!(ranges.0.last(), Some(&(390, 395)));
+    }
+}
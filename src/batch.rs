@@ -0,0 +1,322 @@
+use std::fs::File;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use log::warn;
+use crate::metrics::Metrics;
+#[cfg(target_os = "linux")]
+use crate::mmsg;
+use crate::rate_limit::TokenBucket;
+use crate::read_chunk;
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+use crate::uring::Ring;
+
+///Bytes of packets built before they are sent, bounds how far a batch bursts past the bandwidth limits
+const BATCH_BYTES: usize = 64 * 1024;
+///Datagrams a Receiver takes off a socket at once
+const RECV_PACKETS: usize = 32;
+///errno values of a send UDP segmentation offload can't do
+const EIO: i32 = 5;
+const EINVAL: i32 = 22;
+///Set once a send with UDP segmentation offload failed, every send after it goes without
+static GSO_FAILED: AtomicBool = AtomicBool::new(false);
+
+///Packets built in place one after another and sent together
+///Chunks are read straight into the packet they go out in, so file data is copied once on its way to the socket
+pub(crate) struct PacketBatch {
+    packet_size: usize,
+    buffer: Vec<u8>,
+    lens: Vec<usize>,
+}
+
+impl PacketBatch {
+    pub(crate) fn new(packet_size: usize) -> Self {
+        let packets = (BATCH_BYTES / packet_size).max(1);
+        Self {
+            packet_size,
+            buffer: vec![0; packets*packet_size],
+            lens: Vec::with_capacity(packets),
+        }
+    }
+
+    ///Room for the next packet, it is only part of the batch once it is pushed
+    pub(crate) fn next(&mut self) -> &mut [u8] {
+        let start = self.lens.len()*self.packet_size;
+        &mut self.buffer[start..start+self.packet_size]
+    }
+
+    ///The packet in next is len bytes long
+    pub(crate) fn push(&mut self, len: usize) {
+        self.lens.push(len);
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.lens.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.lens.is_empty()
+    }
+
+    pub(crate) fn is_full(&self) -> bool {
+        (self.lens.len()+1)*self.packet_size > self.buffer.len()
+    }
+
+    ///How many more packets fit
+    pub(crate) fn room(&self) -> usize {
+        self.buffer.len()/self.packet_size - self.lens.len()
+    }
+
+    ///Wait until the bandwidth limits allow the whole batch, returns its bytes
+    fn pace(&self, metrics: &Metrics, bandwidth: &mut TokenBucket, session_bandwidth: Option<&mut TokenBucket>) -> usize {
+        let total: usize = self.lens.iter().sum();
+        if let Some(session_bandwidth) = session_bandwidth {
+            metrics.throttled(session_bandwidth.take(total as u64));
+        }
+        metrics.throttled(bandwidth.take(total as u64));
+        total
+    }
+
+    ///Send every packet to target once the bandwidth limits allow the whole batch, then empty it
+    ///As many as the platform allows go in one call, a full socket buffer is waited out
+    ///Returns how many bytes were sent
+    pub(crate) fn send(&mut self, socket: &UdpSocket, target: SocketAddr, metrics: &Metrics, bandwidth: &mut TokenBucket, session_bandwidth: Option<&mut TokenBucket>) -> io::Result<usize> {
+        self.pace(metrics, bandwidth, session_bandwidth);
+        let mut bytes_sent = 0;
+        let mut next = 0;
+        while next < self.lens.len() {
+            let gso = segmentation();
+            match self.send_from(socket, target, next, gso) {
+                Ok(packets) => {
+                    for len in self.lens[next..next+packets].iter() {
+                        metrics.sent(*len);
+                        bytes_sent += len;
+                    }
+                    next += packets;
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_micros(100)),
+                Err(e) if gso && segmentation_failed(target, &e) => {},
+                Err(e) => {
+                    warn!(peer:% = target, error:% = e; "Unable to send data");
+                    self.lens.clear();
+                    return Err(e);
+                }
+            }
+        }
+        self.lens.clear();
+        Ok(bytes_sent)
+    }
+
+    ///Send packets from next on in one sendmmsg, returns how many went out
+    #[cfg(target_os = "linux")]
+    fn send_from(&self, socket: &UdpSocket, target: SocketAddr, next: usize, gso: bool) -> io::Result<usize> {
+        mmsg::send(socket, target, &self.buffer[next*self.packet_size..], self.packet_size, &self.lens[next..], gso)
+    }
+
+    ///Send the packet at next, returns how many went out
+    #[cfg(not(target_os = "linux"))]
+    fn send_from(&self, socket: &UdpSocket, target: SocketAddr, next: usize, _gso: bool) -> io::Result<usize> {
+        let start = next*self.packet_size;
+        socket.send_to(&self.buffer[start..start+self.lens[next]], target).map(|_| 1)
+    }
+}
+
+///Whether sends may use UDP segmentation offload
+pub(crate) fn segmentation() -> bool {
+    !GSO_FAILED.load(Ordering::Relaxed)
+}
+
+///Whether e is a send UDP segmentation offload couldn't do, if so every send after it goes without
+///Devices without checksum offload and packets bigger than the path MTU can't be segmented
+pub(crate) fn segmentation_failed(target: SocketAddr, e: &io::Error) -> bool {
+    if !matches!(e.raw_os_error(), Some(code) if code == EIO || code == EINVAL) {
+        return false;
+    }
+    if !GSO_FAILED.swap(true, Ordering::Relaxed) {
+        warn!(peer:% = target, error:% = e; "UDP segmentation offload failed, sending packets one by one from now on");
+    }
+    true
+}
+
+///Reads chunks into batches of packets and sends them, a worker services every transaction with one
+///With io_uring the reads of a batch go to the kernel together, and a batch is sent while the next one is filled
+pub struct Pipeline {
+    batches: Vec<PacketBatch>,
+    current: usize,
+    #[cfg(all(feature = "io_uring", target_os = "linux"))]
+    ring: Option<Ring>,
+}
+
+impl Pipeline {
+    pub fn new(packet_size: usize) -> Self {
+        #[cfg(all(feature = "io_uring", target_os = "linux"))]
+        let ring = match Ring::new(2) {
+            Ok(r) => Some(r),
+            Err(e) => {
+                warn!(error:% = e; "Unable to set up io_uring, reading and sending without it");
+                None
+            }
+        };
+        #[cfg(all(feature = "io_uring", target_os = "linux"))]
+        let batches = if ring.is_some() { 2 } else { 1 };
+        #[cfg(not(all(feature = "io_uring", target_os = "linux")))]
+        let batches = 1;
+        Self {
+            batches: (0..batches).map(|_| PacketBatch::new(packet_size)).collect(),
+            current: 0,
+            #[cfg(all(feature = "io_uring", target_os = "linux"))]
+            ring,
+        }
+    }
+
+    ///The batch being filled
+    pub(crate) fn batch(&mut self) -> &mut PacketBatch {
+        &mut self.batches[self.current]
+    }
+
+    ///Read chunks into the packets after those already in the batch, leaving room for the id in front of each
+    ///lens gets how many bytes of every chunk there are, fewer than a chunk only for the last one and 0 past the end
+    pub(crate) fn read(&mut self, file: &File, chunks: &[u64], lens: &mut Vec<usize>) -> io::Result<()> {
+        lens.clear();
+        let batch = &mut self.batches[self.current];
+        let packet_size = batch.packet_size;
+        let start = batch.lens.len()*packet_size;
+        let buffer = &mut batch.buffer[start..start+chunks.len()*packet_size];
+        #[cfg(all(feature = "io_uring", target_os = "linux"))]
+        if let Some(ring) = self.ring.as_mut() {
+            return ring.read(file, chunks, buffer, packet_size, lens);
+        }
+        for (chunk, packet) in chunks.iter().zip(buffer.chunks_exact_mut(packet_size)) {
+            lens.push(read_chunk(file, *chunk, &mut packet[8..])?);
+        }
+        Ok(())
+    }
+
+    ///Send the batch to target once the bandwidth limits allow it and go on with an empty one
+    ///With io_uring it is only handed to the kernel, errors sending it come back from a later send or finish
+    ///Returns how many bytes were sent
+    pub(crate) fn send(&mut self, socket: &UdpSocket, target: SocketAddr, metrics: &Metrics, bandwidth: &mut TokenBucket, session_bandwidth: Option<&mut TokenBucket>) -> io::Result<usize> {
+        #[cfg(all(feature = "io_uring", target_os = "linux"))]
+        if let Some(ring) = self.ring.as_mut() {
+            let batch = &self.batches[self.current];
+            let total = batch.pace(metrics, bandwidth, session_bandwidth);
+            ring.send(self.current, socket, target, &batch.buffer, batch.packet_size, &batch.lens)?;
+            //The next batch is the one sent before this one, it is filled again once the kernel is done with it
+            self.current = (self.current+1) % self.batches.len();
+            self.batches[self.current].lens.clear();
+            ring.wait(self.current, metrics)?;
+            return Ok(total);
+        }
+        self.batches[self.current].send(socket, target, metrics, bandwidth, session_bandwidth)
+    }
+
+    ///Wait for every batch still being sent and empty them all, returns the first error sending them
+    pub(crate) fn finish(&mut self, metrics: &Metrics) -> io::Result<()> {
+        let result = self.wait_all(metrics);
+        for batch in self.batches.iter_mut() {
+            batch.lens.clear();
+        }
+        self.current = 0;
+        result
+    }
+
+    #[cfg(all(feature = "io_uring", target_os = "linux"))]
+    fn wait_all(&mut self, metrics: &Metrics) -> io::Result<()> {
+        let ring = match self.ring.as_mut() {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+        let mut result = Ok(());
+        for index in 0..self.batches.len() {
+            if let Err(e) = ring.wait(index, metrics) {
+                result = result.and(Err(e));
+            }
+        }
+        result
+    }
+
+    #[cfg(not(all(feature = "io_uring", target_os = "linux")))]
+    fn wait_all(&mut self, _metrics: &Metrics) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+///The kernel may still be reading into or sending from the batches, they can't go away before it is done
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        if let Some(ring) = self.ring.as_mut() {
+            ring.drain();
+        }
+    }
+}
+
+///Datagrams taken off a socket several at a time and handed out one by one, in place of recv_from
+///Datagrams it took off a socket are only handed out by it, reading the socket some other way doesn't see them
+pub(crate) struct Receiver {
+    packet_size: usize,
+    buffer: Vec<u8>,
+    lens: Vec<usize>,
+    sources: Vec<SocketAddr>,
+    next: usize,
+}
+
+impl Receiver {
+    ///Datagrams longer than packet_size are cut short like recv_from into a packet_size buffer does
+    pub(crate) fn new(packet_size: usize) -> Self {
+        Self {
+            packet_size,
+            buffer: vec![0; RECV_PACKETS*packet_size],
+            lens: Vec::with_capacity(RECV_PACKETS),
+            sources: Vec::with_capacity(RECV_PACKETS),
+            next: 0,
+        }
+    }
+
+    ///Copy the next datagram into buffer, returns its length and where it came from, WouldBlock if there is none
+    pub(crate) fn recv_from(&mut self, socket: &UdpSocket, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        if self.next == self.lens.len() {
+            self.lens.clear();
+            self.sources.clear();
+            self.next = 0;
+            self.fill(socket)?;
+            if self.lens.is_empty() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+        }
+        let i = self.next;
+        self.next += 1;
+        let len = self.lens[i].min(buffer.len());
+        let start = i*self.packet_size;
+        buffer[0..len].copy_from_slice(&self.buffer[start..start+len]);
+        Ok((len, self.sources[i]))
+    }
+
+    pub(crate) fn recv(&mut self, socket: &UdpSocket, buffer: &mut [u8]) -> io::Result<usize> {
+        self.recv_from(socket, buffer).map(|(len, _)| len)
+    }
+
+    ///Take whatever is waiting on socket with one recvmmsg
+    #[cfg(target_os = "linux")]
+    fn fill(&mut self, socket: &UdpSocket) -> io::Result<()> {
+        mmsg::recv(socket, &mut self.buffer, self.packet_size, &mut self.lens, &mut self.sources)
+    }
+
+    ///Take whatever is waiting on socket one recv_from at a time
+    #[cfg(not(target_os = "linux"))]
+    fn fill(&mut self, socket: &UdpSocket) -> io::Result<()> {
+        for slot in self.buffer.chunks_exact_mut(self.packet_size) {
+            match socket.recv_from(slot) {
+                Ok((len, source)) => {
+                    self.lens.push(len);
+                    self.sources.push(source);
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock && !self.lens.is_empty() => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,443 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::{TryFrom, TryInto};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+use log::{debug, warn};
+use sha2::{Digest, Sha256};
+use crate::config::CacheConfig;
+use crate::delta::HASH_LEN;
+use crate::merkle::Tree;
+use crate::variant;
+use crate::variant::VariantInfo;
+use crate::{pack_u64_into_u8arr, unpack_u8arr_into_u64};
+
+///First bytes of a persisted entry, a new format gets a new one and old files are ignored
+const MAGIC: &[u8; 8] = b"budpc001";
+///Rough bytes of memory an entry takes besides its tree
+const ENTRY_OVERHEAD: usize = 256;
+
+///A version of a file, everything cached about it is looked up by all of these
+///The canonical path tells files apart, the rest changes whenever a file is modified or replaced
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct FileKey {
+    path: PathBuf,
+    dev: u64,
+    ino: u64,
+    modified: u128,
+    len: u64,
+}
+
+impl FileKey {
+    fn new(path: &Path, metadata: &fs::Metadata) -> Self {
+        let modified = match metadata.modified().map(|m| m.duration_since(UNIX_EPOCH)) {
+            Ok(Ok(since_epoch)) => since_epoch.as_nanos(),
+            _ => 0,
+        };
+        #[cfg(unix)]
+        let (dev, ino) = {
+            use std::os::unix::fs::MetadataExt;
+            (metadata.dev(), metadata.ino())
+        };
+        #[cfg(not(unix))]
+        let (dev, ino) = (0, 0);
+        Self {
+            path: path.to_path_buf(),
+            dev,
+            ino,
+            modified,
+            len: metadata.len(),
+        }
+    }
+}
+
+///What is known about a version of a file, filled in as it is needed
+#[derive(Clone, Default)]
+struct Entry {
+    tree: Option<Arc<Tree>>,
+    variant: Option<VariantInfo>,
+    //Its place in the least recently used order
+    used: u64,
+}
+
+impl Entry {
+    fn bytes(&self, key: &FileKey) -> usize {
+        ENTRY_OVERHEAD + key.path.as_os_str().len() + self.tree.as_ref().map_or(0, |t| t.bytes())
+    }
+}
+
+struct Entries {
+    by_key: HashMap<FileKey, Entry>,
+    //Keys by when they were last used, the first goes first
+    order: BTreeMap<u64, FileKey>,
+    clock: u64,
+    bytes: usize,
+}
+
+impl Entries {
+    fn touch(&mut self, key: &FileKey) -> Option<Entry> {
+        self.clock += 1;
+        let clock = self.clock;
+        let entry = self.by_key.get_mut(key)?;
+        self.order.remove(&entry.used);
+        entry.used = clock;
+        self.order.insert(clock, key.clone());
+        Some(entry.clone())
+    }
+}
+
+///What the server learns by reading files whole, so it only does that once per version of a file
+///Entries are keyed by canonical path, device, inode, modification time and size, a changed file is a new entry
+///and the stale one is never looked up again, it makes room once memory_bytes is used up
+///With a directory every entry is also written to a file named after the hash of its path, the newest version wins
+pub(crate) struct FileCache {
+    memory_bytes: usize,
+    directory: Option<PathBuf>,
+    chunk_size: usize,
+    entries: Mutex<Entries>,
+}
+
+impl FileCache {
+    pub(crate) fn new(config: &CacheConfig, chunk_size: usize) -> Self {
+        Self {
+            memory_bytes: usize::try_from(config.memory_bytes).unwrap_or(usize::MAX),
+            directory: config.directory.clone(),
+            chunk_size,
+            entries: Mutex::new(Entries {
+                by_key: HashMap::new(),
+                order: BTreeMap::new(),
+                clock: 0,
+                bytes: 0,
+            }),
+        }
+    }
+
+    ///Chunk hash tree of file, opened from the canonical path, from the cache or built now
+    ///None if the file is empty or was rewritten while it was read
+    pub(crate) fn tree(&self, path: &Path, file: &mut File) -> io::Result<Option<Arc<Tree>>> {
+        let key = FileKey::new(path, &file.metadata()?);
+        if let Some(tree) = self.lookup(&key).and_then(|e| e.tree) {
+            return Ok(Some(tree));
+        }
+        //Built without holding the lock, other files don't have to wait for this one
+        let tree = match Tree::build(file, self.chunk_size)? {
+            Some(t) => Arc::new(t),
+            None => return Ok(None),
+        };
+        if FileKey::new(path, &file.metadata()?) != key {
+            return Ok(None);
+        }
+        debug!(path:? = path, chunks = tree.leaves().len(); "Built chunk hash tree");
+        self.store(key, |e| e.tree = Some(Arc::clone(&tree)));
+        Ok(Some(tree))
+    }
+
+    ///Description of the pre-compressed variant at path, from the cache or read now
+    pub(crate) fn variant(&self, path: &Path) -> io::Result<VariantInfo> {
+        let key = FileKey::new(path, &fs::metadata(path)?);
+        if let Some(info) = self.lookup(&key).and_then(|e| e.variant) {
+            return Ok(info);
+        }
+        let info = variant::describe(path)?;
+        //A variant replaced while it was read is described but not remembered
+        if FileKey::new(path, &fs::metadata(path)?) == key {
+            self.store(key, |e| e.variant = Some(info));
+        }
+        Ok(info)
+    }
+
+    ///Entry of key from memory or else the directory
+    fn lookup(&self, key: &FileKey) -> Option<Entry> {
+        if let Some(entry) = lock(&self.entries).touch(key) {
+            return Some(entry);
+        }
+        let entry = self.load(key)?;
+        //Like in store, one that doesn't fit at all would only push everything else out
+        if entry.bytes(key) > self.memory_bytes {
+            return Some(entry);
+        }
+        let mut entries = lock(&self.entries);
+        if !entries.by_key.contains_key(key) {
+            entries.bytes += entry.bytes(key);
+            entries.by_key.insert(key.clone(), entry);
+        }
+        let entry = entries.touch(key);
+        self.evict(&mut entries);
+        entry
+    }
+
+    ///Fill in the entry of key with update, then write it out if there is a directory
+    fn store<F: FnOnce(&mut Entry)>(&self, key: FileKey, update: F) {
+        let mut entries = lock(&self.entries);
+        let (before, after) = match entries.by_key.get_mut(&key) {
+            Some(entry) => {
+                let before = entry.bytes(&key);
+                update(entry);
+                (before, entry.bytes(&key))
+            },
+            None => {
+                let mut entry = Entry::default();
+                update(&mut entry);
+                let after = entry.bytes(&key);
+                entries.by_key.insert(key.clone(), entry);
+                (0, after)
+            }
+        };
+        entries.bytes = entries.bytes + after - before;
+        let stored = match entries.touch(&key) {
+            Some(s) => s,
+            None => return,
+        };
+        //One that doesn't fit at all would only push everything else out
+        if after > self.memory_bytes {
+            entries.order.remove(&stored.used);
+            entries.by_key.remove(&key);
+            entries.bytes -= after;
+        }
+        self.evict(&mut entries);
+        drop(entries);
+        if let Err(e) = self.persist(&key, &stored) {
+            warn!(path:? = key.path, error:% = e; "Unable to write the cache entry");
+        }
+    }
+
+    ///Drop the least recently used entries until they fit in memory_bytes
+    fn evict(&self, entries: &mut Entries) {
+        while entries.bytes > self.memory_bytes {
+            let key = match entries.order.pop_first() {
+                Some((_, key)) => key,
+                None => break,
+            };
+            if let Some(entry) = entries.by_key.remove(&key) {
+                entries.bytes -= entry.bytes(&key);
+            }
+        }
+    }
+
+    ///Where the entries of path are kept in the directory, None without one
+    fn entry_path(&self, path: &Path) -> Option<PathBuf> {
+        let hash = Sha256::digest(path.as_os_str().as_encoded_bytes());
+        let name: String = hash[0..HASH_LEN].iter().map(|b| format!("{:02x}", b)).collect();
+        Some(self.directory.as_ref()?.join(name))
+    }
+
+    ///Write entry to the directory as MAGIC, u64 chunk size, the key, u64 1 and the variant description or u64 0,
+    ///then u64 leaf count and the leaves
+    ///Written next to where it goes and renamed over it, so a reader never sees half of one
+    fn persist(&self, key: &FileKey, entry: &Entry) -> io::Result<()> {
+        let destination = match self.entry_path(&key.path) {
+            Some(d) => d,
+            None => return Ok(()),
+        };
+        let path_bytes = key.path.as_os_str().as_encoded_bytes();
+        let mut data: Vec<u8> = Vec::new();
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&pack_u64_into_u8arr(self.chunk_size as u64));
+        data.extend_from_slice(&pack_u64_into_u8arr(key.dev));
+        data.extend_from_slice(&pack_u64_into_u8arr(key.ino));
+        data.extend_from_slice(&key.modified.to_be_bytes());
+        data.extend_from_slice(&pack_u64_into_u8arr(key.len));
+        data.extend_from_slice(&pack_u64_into_u8arr(path_bytes.len() as u64));
+        data.extend_from_slice(path_bytes);
+        match &entry.variant {
+            Some(info) => {
+                let mut packed = [0; variant::INFO_LEN];
+                variant::pack_info(info, &mut packed);
+                data.extend_from_slice(&pack_u64_into_u8arr(1));
+                data.extend_from_slice(&packed);
+            },
+            None => data.extend_from_slice(&pack_u64_into_u8arr(0)),
+        }
+        let leaves = entry.tree.as_ref().map_or(&[][..], |t| t.leaves());
+        data.extend_from_slice(&pack_u64_into_u8arr(leaves.len() as u64));
+        for leaf in leaves.iter() {
+            data.extend_from_slice(leaf);
+        }
+        let mut temporary = destination.clone().into_os_string();
+        temporary.push(format!(".{}.tmp", entry.used));
+        fs::write(&temporary, &data)?;
+        fs::rename(&temporary, &destination)
+    }
+
+    ///Entry of key from the directory, None if there is none or it is of another version or chunk size
+    fn load(&self, key: &FileKey) -> Option<Entry> {
+        let data = fs::read(self.entry_path(&key.path)?).ok()?;
+        let mut rest = &data[..];
+        let path_bytes = key.path.as_os_str().as_encoded_bytes();
+        if take(&mut rest, MAGIC.len())? != MAGIC
+            || take_u64(&mut rest)? != self.chunk_size as u64
+            || take_u64(&mut rest)? != key.dev
+            || take_u64(&mut rest)? != key.ino
+            || u128::from_be_bytes(take(&mut rest, 16)?.try_into().ok()?) != key.modified
+            || take_u64(&mut rest)? != key.len {
+            return None;
+        }
+        let path_len = usize::try_from(take_u64(&mut rest)?).ok()?;
+        if take(&mut rest, path_len)? != path_bytes {
+            return None;
+        }
+        let variant = match take_u64(&mut rest)? {
+            0 => None,
+            _ => Some(variant::unpack_info(take(&mut rest, variant::INFO_LEN)?)?),
+        };
+        let leaf_count = usize::try_from(take_u64(&mut rest)?).ok()?;
+        let leaves = take(&mut rest, leaf_count.checked_mul(HASH_LEN)?)?.chunks_exact(HASH_LEN).map(|leaf| {
+            let mut hash = [0; HASH_LEN];
+            hash.copy_from_slice(leaf);
+            hash
+        }).collect();
+        debug!(path:? = key.path, chunks = leaf_count; "Loaded cache entry");
+        Some(Entry {
+            tree: Tree::from_leaves(leaves).map(Arc::new),
+            variant,
+            used: 0,
+        })
+    }
+}
+
+///The next len bytes of data, None if it is cut short
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if data.len() < len {
+        return None;
+    }
+    let (taken, rest) = data.split_at(len);
+    *data = rest;
+    Some(taken)
+}
+
+fn take_u64(data: &mut &[u8]) -> Option<u64> {
+    take(data, 8).map(unpack_u8arr_into_u64)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHUNK_SIZE: usize = 504;
+
+    ///An empty directory
+    fn scratch(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("basic_udp-cache-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn cache(memory_bytes: u64, directory: Option<&Path>) -> FileCache {
+        FileCache::new(&CacheConfig { memory_bytes, directory: directory.map(Path::to_path_buf) }, CHUNK_SIZE)
+    }
+
+    fn key(name: &str) -> FileKey {
+        FileKey { path: PathBuf::from(format!("/srv/{}", name)), dev: 1, ino: 2, modified: 3, len: 4 }
+    }
+
+    fn tree(leaves: usize) -> Arc<Tree> {
+        Arc::new(Tree::from_leaves((0..leaves).map(|i| [i as u8; HASH_LEN]).collect()).unwrap())
+    }
+
+    fn info() -> VariantInfo {
+        VariantInfo { stored_size: 1, stored_hash: [1; 32], decoded_size: 2, decoded_hash: [2; 32] }
+    }
+
+    ///Names of the entries in memory, checking the byte count adds up
+    fn cached(cache: &FileCache) -> Vec<String> {
+        let entries = lock(&cache.entries);
+        assert_eq!(entries.bytes, entries.by_key.iter().map(|(k, e)| e.bytes(k)).sum::<usize>());
+        assert_eq!(entries.order.len(), entries.by_key.len());
+        let mut names: Vec<String> = entries.by_key.keys().map(|k| k.path.file_name().unwrap().to_string_lossy().into_owned()).collect();
+        names.sort_unstable();
+        names
+    }
+
+    #[test]
+    fn least_recently_used_go_first() {
+        //Four leaves are seven hashes, two such entries fit
+        let per_entry = ENTRY_OVERHEAD + "/srv/a".len() + 7*HASH_LEN;
+        let cache = cache(2*per_entry as u64+10, None);
+        cache.store(key("a"), |e| e.tree = Some(tree(4)));
+        cache.store(key("b"), |e| e.tree = Some(tree(4)));
+        assert_eq!(cached(&cache), vec!["a", "b"]);
+        assert!(cache.lookup(&key("a")).is_some());
+        cache.store(key("c"), |e| e.tree = Some(tree(4)));
+        assert_eq!(cached(&cache), vec!["a", "c"]);
+        assert!(cache.lookup(&key("b")).is_none());
+        //Another version of a file is another entry
+        let mut changed = key("a");
+        changed.len += 1;
+        assert!(cache.lookup(&changed).is_none());
+    }
+
+    #[test]
+    fn store_keeps_the_byte_count() {
+        let cache = cache(1 << 20, None);
+        cache.store(key("a"), |e| e.variant = Some(info()));
+        cache.store(key("a"), |e| e.tree = Some(tree(8)));
+        assert_eq!(lock(&cache.entries).bytes, ENTRY_OVERHEAD + 6 + 15*HASH_LEN);
+        //Smaller than before
+        cache.store(key("a"), |e| e.tree = Some(tree(2)));
+        assert_eq!(lock(&cache.entries).bytes, ENTRY_OVERHEAD + 6 + 3*HASH_LEN);
+        cache.store(key("b"), |e| e.tree = Some(tree(1)));
+        cache.store(key("a"), |e| e.tree = None);
+        assert_eq!(lock(&cache.entries).bytes, 2*(ENTRY_OVERHEAD + 6) + HASH_LEN);
+        assert_eq!(cached(&cache), vec!["a", "b"]);
+        assert_eq!(cache.lookup(&key("a")).unwrap().variant, Some(info()));
+    }
+
+    #[test]
+    fn oversize_entries_are_not_kept() {
+        let directory = scratch("oversize");
+        let cache = cache(ENTRY_OVERHEAD as u64+100, Some(&directory));
+        cache.store(key("small"), |e| e.variant = Some(info()));
+        cache.store(key("large"), |e| e.tree = Some(tree(8)));
+        assert_eq!(cached(&cache), vec!["small"]);
+        //Still written out, and read back without pushing anything out
+        assert_eq!(cache.lookup(&key("large")).unwrap().tree.unwrap().leaves(), tree(8).leaves());
+        assert_eq!(cached(&cache), vec!["small"]);
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn entries_persist_for_the_same_version_only() {
+        let directory = scratch("persist");
+        let written = cache(1 << 20, Some(&directory));
+        written.store(key("a"), |e| e.tree = Some(tree(5)));
+        written.store(key("a"), |e| e.variant = Some(info()));
+
+        let read = cache(1 << 20, Some(&directory));
+        let entry = read.lookup(&key("a")).unwrap();
+        assert_eq!(entry.tree.unwrap().root(), tree(5).root());
+        assert_eq!(entry.variant, Some(info()));
+        assert_eq!(cached(&read), vec!["a"]);
+
+        let fresh = || cache(1 << 20, Some(&directory));
+        for change in [|k: &mut FileKey| k.dev += 1, |k: &mut FileKey| k.ino += 1, |k: &mut FileKey| k.modified += 1, |k: &mut FileKey| k.len += 1].iter() {
+            let mut other = key("a");
+            change(&mut other);
+            assert!(fresh().load(&other).is_none());
+        }
+        assert!(FileCache::new(&CacheConfig { memory_bytes: 1 << 20, directory: Some(directory.clone()) }, CHUNK_SIZE+1).load(&key("a")).is_none());
+        assert!(fresh().load(&key("b")).is_none());
+
+        //A newer version replaces the old one, and a cut short entry is ignored
+        let mut newer = key("a");
+        newer.modified += 1;
+        written.store(newer.clone(), |e| e.tree = Some(tree(3)));
+        assert!(fresh().load(&key("a")).is_none());
+        assert_eq!(fresh().load(&newer).unwrap().tree.unwrap().leaves().len(), 3);
+        let entry_path = written.entry_path(&newer.path).unwrap();
+        let data = fs::read(&entry_path).unwrap();
+        fs::write(&entry_path, &data[0..data.len()-1]).unwrap();
+        assert!(fresh().load(&newer).is_none());
+        let _ = fs::remove_dir_all(&directory);
+    }
+}
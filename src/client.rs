@@ -0,0 +1,1698 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::fs::File;
+use std::mem;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs, UdpSocket};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use log::{debug, error, warn};
+use crate::batch::Receiver;
+use crate::compress;
+use crate::compress::Decompressor;
+use crate::config::{MAX_PACKET_SIZE, MIN_PACKET_SIZE};
+use crate::delta;
+use crate::delta::HASH_LEN;
+use crate::fec;
+use crate::fec::{BlockDecoder, FecParams};
+use crate::merkle;
+use crate::range_tree::RangeTree;
+use crate::session;
+use crate::session::OpenResult;
+use crate::upload;
+use crate::upload::{AnnounceResult, Announcement, UploadState};
+use crate::variant;
+use crate::variant::{StreamDecoder, VariantInfo};
+use crate::{compact_chunk_request_packet, list_request_packet, metadata_request_packet, range_chunk_request_packet, unpack_name, unpack_u8arr_into_u64};
+use crate::{RangeRequest, COMPRESS_REQUEST, CONTROL_CHUNK, FEATURE_COMPACT_RANGES, FEATURE_FEC, FEATURE_HASHES, FEATURE_MERKLE, FEATURE_NACK, FILE_CHANGED, MULTICAST_INFO, PACKET_SIZE, REPAIR_CHUNK, VARIANT_REQUEST, VARIANT_ZSTD};
+
+///How far past a missing chunk the transfer has to get before it is reported lost, a little reordering is normal
+const NACK_REORDER: u64 = 3;
+///Retry intervals without anything on a multicast group before the chunks are requested from the server instead
+const GROUP_SILENCE: u32 = 5;
+
+///What happened during a transfer
+///
+///bytes: u64, Bytes of file data written to the sink, or pushed to the server
+///chunks: u64, Distinct chunks transferred
+///duration: Duration, Time from the metadata request until the last byte was written
+///retransmits: u64, Requests sent again because the server went quiet for a retry interval, or chunks pushed again
+///duplicate_packets: u64, Chunks received that had already been received
+///nacks: u64, Reports of lost chunks sent to the server
+///repaired: u64, Lost chunks rebuilt from repair packets instead of being asked for again
+///unchanged: u64, Chunks the local copy already had, found by comparing hashes instead of fetching them
+///rejected: u64, Chunks that didn't match the Merkle tree of the file, dropped and asked for again
+///rtt: Option<Duration>, Round trip time of the metadata exchange
+#[derive(Debug, Clone, Default)]
+pub struct TransferStats {
+    pub bytes: u64,
+    pub chunks: u64,
+    pub duration: Duration,
+    pub retransmits: u64,
+    pub duplicate_packets: u64,
+    pub nacks: u64,
+    pub repaired: u64,
+    pub unchanged: u64,
+    pub rejected: u64,
+    pub rtt: Option<Duration>,
+}
+
+///Snapshot of a transfer in progress handed to a ProgressObserver
+///
+///chunks_done: u64, Chunks of the file that are done, including the ones skipped when resuming
+///chunk_count: u64, Chunks in the whole file
+///bytes_done: u64, Bytes of the file that are done, including the ones skipped when resuming
+///total_bytes: u64, Size of the file, for downloads this is chunk_count*chunk_size so it can be up to a chunk too large
+///rate: f64, Bytes per second transferred since the start of this transfer
+///eta: Option<Duration>, Estimated time left, None until the rate is known
+///retransmits: u64, Requests or chunks sent again so far
+///finished: bool, True for the last report of a successful transfer
+#[derive(Debug, Clone, Default)]
+pub struct Progress {
+    pub chunks_done: u64,
+    pub chunk_count: u64,
+    pub bytes_done: u64,
+    pub total_bytes: u64,
+    pub rate: f64,
+    pub eta: Option<Duration>,
+    pub retransmits: u64,
+    pub finished: bool,
+}
+
+///Gets told how a transfer is doing, at most once per progress interval and once more when it finishes
+///Closures taking a &Progress are observers too
+pub trait ProgressObserver: Send + Sync {
+    fn on_progress(&self, progress: &Progress);
+}
+
+impl<F: Fn(&Progress) + Send + Sync> ProgressObserver for F {
+    fn on_progress(&self, progress: &Progress) {
+        self(progress)
+    }
+}
+
+///The file on the server changed part way through a download, what was received so far mixes versions
+///Carried inside an io::Error, check for it with is_file_changed
+#[derive(Debug)]
+pub struct FileChanged {
+    pub filename: String,
+}
+
+impl fmt::Display for FileChanged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} changed on the server during the transfer", self.filename)
+    }
+}
+
+impl std::error::Error for FileChanged {}
+
+///True if a transfer failed because the file changed on the server, restarting from the first chunk gets a consistent copy
+pub fn is_file_changed(error: &io::Error) -> bool {
+    error.get_ref().map(|e| e.is::<FileChanged>()).unwrap_or(false)
+}
+
+fn file_changed(filename: &str) -> io::Error {
+    io::Error::other(FileChanged { filename: String::from(filename) })
+}
+
+///Session ID and handle of a file opened in a server side session
+type SessionFile = (u64, u64);
+
+///What a metadata or session open response says about a file
+///
+///chunk_count: u64, Chunks in the file, 0 if it is empty or not on the whitelist
+///found: bool, The server hands the file out, tells an empty file from one that isn't on the whitelist
+///epoch: u64, Version of the file to echo in chunk requests, 0 from servers that don't send one
+///features: u64, FEATURE_ bits the server supports, 0 from servers that don't send them
+///fec: Option<FecParams>, How the server protects the file with repair packets, None if it doesn't
+///variant: Option<VariantInfo>, What the pre-compressed variant the rest describes decodes to, None if it describes the file itself
+///root: Option<[u8; HASH_LEN]>, Root of the Merkle tree over the chunks, None from servers that don't send one
+///reply: Vec<u8>, The response itself
+struct FileInfo {
+    chunk_count: u64,
+    found: bool,
+    epoch: u64,
+    features: u64,
+    fec: Option<FecParams>,
+    variant: Option<VariantInfo>,
+    root: Option<[u8; HASH_LEN]>,
+    reply: Vec<u8>,
+}
+
+impl FileInfo {
+    ///A late answer to a repeat of the request this came from, on slow links they arrive among the chunks and mustn't pass for one
+    ///Only the session ID and handle can differ between answers
+    fn is_repeat(&self, packet: &[u8]) -> bool {
+        packet.len() == self.reply.len() && packet.get(0..8) == self.reply.get(0..8) && packet.get(24..) == self.reply.get(24..)
+    }
+}
+
+///Builder for a Client, every setting has a default matching the server defaults
+pub struct ClientBuilder {
+    window: usize,
+    packet_size: usize,
+    retry_interval: Duration,
+    timeout: Duration,
+    progress: Option<Arc<dyn ProgressObserver>>,
+    progress_interval: Duration,
+    sessions: bool,
+    fec: bool,
+    compression: bool,
+    verify: bool,
+    multicast_interface: IpAddr,
+}
+
+///Client for fetching files from and pushing files to a basic_udp server
+///
+///window: usize, How many chunks are held in memory and requested at once
+///packet_size: usize, Has to match the packet size the server was configured with
+///retry_interval: Duration, Re-request missing chunks after this long without hearing from the server
+///timeout: Duration, Give up after this long without hearing from the server
+///progress: Option<Arc<dyn ProgressObserver>>, Told about the progress of every transfer
+///progress_interval: Duration, Least time between two progress reports
+///sessions: bool, Open files in a server side session and request chunks by handle instead of by name
+///fec: bool, Ask servers that send repair packets for them and rebuild lost chunks from them
+///compression: bool, Ask servers that compress chunks for compressed chunks
+///verify: bool, Check every chunk against the Merkle root from servers that send one
+///multicast_interface: IpAddr, Address of the interface multicast groups are joined on, unspecified lets the routing table pick
+#[derive(Clone)]
+pub struct Client {
+    window: usize,
+    packet_size: usize,
+    retry_interval: Duration,
+    timeout: Duration,
+    progress: Option<Arc<dyn ProgressObserver>>,
+    progress_interval: Duration,
+    sessions: bool,
+    fec: bool,
+    compression: bool,
+    verify: bool,
+    multicast_interface: IpAddr,
+}
+
+///Turns transfer stats into progress reports, rate limited to the progress interval
+struct ProgressTracker<'a> {
+    observer: Option<&'a dyn ProgressObserver>,
+    interval: Duration,
+    started: Instant,
+    last_report: Option<Instant>,
+    chunk_count: u64,
+    chunk_size: u64,
+    total_bytes: u64,
+    skipped_chunks: u64,
+    skipped_bytes: u64,
+}
+
+impl<'a> ProgressTracker<'a> {
+    fn update(&mut self, stats: &TransferStats, finished: bool) {
+        let observer = match self.observer {
+            Some(o) => o,
+            None => return,
+        };
+        if !finished {
+            if let Some(last) = self.last_report {
+                if last.elapsed() < self.interval {
+                    return;
+                }
+            }
+        }
+        self.last_report = Some(Instant::now());
+
+        let seconds = self.started.elapsed().as_secs_f64();
+        let rate = if seconds > 0.0 { stats.bytes as f64/seconds } else { 0.0 };
+        //Chunks the local copy already had are done too
+        let bytes_done = (self.skipped_bytes+stats.bytes+stats.unchanged*self.chunk_size).min(self.total_bytes);
+        //Once everything is in, the real size is known
+        let total_bytes = if finished { bytes_done } else { self.total_bytes };
+        let eta = if finished {
+            Some(Duration::from_secs(0))
+        } else if rate > 0.0 {
+            Some(Duration::from_secs_f64((self.total_bytes-bytes_done) as f64/rate))
+        } else {
+            None
+        };
+        observer.on_progress(&Progress {
+            chunks_done: self.skipped_chunks+stats.chunks+stats.unchanged,
+            chunk_count: self.chunk_count,
+            bytes_done,
+            total_bytes,
+            rate,
+            eta,
+            retransmits: stats.retransmits,
+            finished,
+        });
+    }
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Client")
+         .field("window", &self.window)
+         .field("packet_size", &self.packet_size)
+         .field("retry_interval", &self.retry_interval)
+         .field("timeout", &self.timeout)
+         .field("progress", &self.progress.is_some())
+         .field("progress_interval", &self.progress_interval)
+         .field("sessions", &self.sessions)
+         .field("fec", &self.fec)
+         .field("compression", &self.compression)
+         .field("verify", &self.verify)
+         .field("multicast_interface", &self.multicast_interface)
+         .finish()
+    }
+}
+
+///Where received chunks end up, either buffered and written in order or written straight to their offset
+trait ChunkSink {
+    ///A new chunk arrived, index is relative to the start of the current window
+    fn chunk(&mut self, index: usize, absolute: u64, data: &[u8]) -> io::Result<()>;
+    ///Every chunk of the current window has arrived
+    fn window_done(&mut self) -> io::Result<()>;
+    ///Whether the sink can take a pre-compressed variant, decoding it needs the chunks in order
+    fn decodes(&self) -> bool {
+        false
+    }
+    ///The chunks to come are of the variant described by info, decode them on the way out
+    fn decode(&mut self, _info: VariantInfo) -> io::Result<()> {
+        Ok(())
+    }
+    ///Every chunk of the file has arrived, fails if the variant didn't decode to what the server described
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+    ///Whether the sink updates a local copy, chunks it already has don't need to be fetched
+    fn updates(&self) -> bool {
+        false
+    }
+    ///Read what the local copy holds of chunk absolute into buffer, returns how many bytes that is
+    fn local_chunk(&mut self, _absolute: u64, _buffer: &mut [u8]) -> io::Result<usize> {
+        Ok(0)
+    }
+    ///The file is size bytes long, cut off whatever the local copy holds past it
+    fn truncate(&mut self, _size: u64) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+///Buffers a window of chunks and writes them out in order once the window is complete
+///A pre-compressed variant is decoded as it is written, and checked against what it should decode to once it all was
+struct SequentialSink<'a, W: Write> {
+    out: &'a mut W,
+    chunks: Vec<Vec<u8>>,
+    decoder: Option<(StreamDecoder, VariantInfo)>,
+}
+
+///Writes every chunk at its offset as soon as it arrives
+struct SeekableSink<'a, W: Write + Seek> {
+    out: &'a mut W,
+    chunk_size: u64,
+}
+
+///Brings a local copy up to date, writing every chunk that differs at its offset
+struct UpdateSink<'a> {
+    out: &'a mut File,
+    chunk_size: u64,
+}
+
+impl<'a, W: Write> ChunkSink for SequentialSink<'a, W> {
+    fn chunk(&mut self, index: usize, _absolute: u64, data: &[u8]) -> io::Result<()> {
+        self.chunks[index].extend_from_slice(data);
+        Ok(())
+    }
+
+    fn window_done(&mut self) -> io::Result<()> {
+        for chunk in self.chunks.iter_mut() {
+            match self.decoder.as_mut() {
+                Some((decoder, _)) => decoder.write(&chunk[..], self.out)?,
+                None => self.out.write_all(&chunk[..])?,
+            }
+            chunk.clear();
+        }
+        Ok(())
+    }
+
+    fn decodes(&self) -> bool {
+        true
+    }
+
+    fn decode(&mut self, info: VariantInfo) -> io::Result<()> {
+        self.decoder = Some((StreamDecoder::new()?, info));
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let (decoder, expected) = match self.decoder.take() {
+            Some(d) => d,
+            None => return Ok(()),
+        };
+        if decoder.finish()? != expected {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "The pre-compressed variant didn't decode to what the server described"));
+        }
+        Ok(())
+    }
+}
+
+impl<'a, W: Write + Seek> ChunkSink for SeekableSink<'a, W> {
+    fn chunk(&mut self, _index: usize, absolute: u64, data: &[u8]) -> io::Result<()> {
+        self.out.seek(SeekFrom::Start(absolute*self.chunk_size))?;
+        self.out.write_all(data)
+    }
+
+    fn window_done(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> ChunkSink for UpdateSink<'a> {
+    fn chunk(&mut self, _index: usize, absolute: u64, data: &[u8]) -> io::Result<()> {
+        self.out.seek(SeekFrom::Start(absolute*self.chunk_size))?;
+        self.out.write_all(data)
+    }
+
+    fn window_done(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn updates(&self) -> bool {
+        true
+    }
+
+    fn local_chunk(&mut self, absolute: u64, buffer: &mut [u8]) -> io::Result<usize> {
+        self.out.seek(SeekFrom::Start(absolute*self.chunk_size))?;
+        read_full(self.out, buffer)
+    }
+
+    fn truncate(&mut self, size: u64) -> io::Result<()> {
+        self.out.set_len(size)
+    }
+}
+
+impl Default for ClientBuilder {
+    fn default() -> Self {
+        Self {
+            window: 1000,
+            packet_size: PACKET_SIZE,
+            retry_interval: Duration::from_millis(200),
+            timeout: Duration::from_secs(10),
+            progress: None,
+            progress_interval: Duration::from_millis(100),
+            sessions: false,
+            fec: true,
+            compression: true,
+            verify: true,
+            multicast_interface: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        }
+    }
+}
+
+impl ClientBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///How many chunks are held in memory and requested at once
+    pub fn window(mut self, window: usize) -> Self {
+        self.window = window;
+        self
+    }
+
+    ///Has to match the packet_size of the server
+    pub fn packet_size(mut self, packet_size: usize) -> Self {
+        self.packet_size = packet_size;
+        self
+    }
+
+    ///Re-request missing chunks after this long without hearing from the server
+    pub fn retry_interval(mut self, retry_interval: Duration) -> Self {
+        self.retry_interval = retry_interval;
+        self
+    }
+
+    ///Give up after this long without hearing from the server
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    ///Report the progress of every transfer to observer
+    pub fn progress<O: ProgressObserver + 'static>(mut self, observer: O) -> Self {
+        self.progress = Some(Arc::new(observer));
+        self
+    }
+
+    ///Least time between two progress reports
+    pub fn progress_interval(mut self, progress_interval: Duration) -> Self {
+        self.progress_interval = progress_interval;
+        self
+    }
+
+    ///Fetch through a server side session, falls back to stateless requests if the server doesn't allow one
+    pub fn sessions(mut self, sessions: bool) -> Self {
+        self.sessions = sessions;
+        self
+    }
+
+    ///Ask servers that send repair packets for them, on by default, servers without FEC enabled never send any
+    pub fn fec(mut self, fec: bool) -> Self {
+        self.fec = fec;
+        self
+    }
+
+    ///Ask servers that compress chunks for compressed chunks, on by default, it only costs CPU time on both ends
+    pub fn compression(mut self, compression: bool) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    ///Check every chunk against the Merkle root from servers that send one, on by default
+    ///Chunks that don't match are dropped and asked for again, costing a request for the hashes of every window
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    ///Address of the interface to join multicast groups on, by default the routing table picks one
+    pub fn multicast_interface(mut self, multicast_interface: IpAddr) -> Self {
+        self.multicast_interface = multicast_interface;
+        self
+    }
+
+    pub fn build(self) -> io::Result<Client> {
+        if self.packet_size < MIN_PACKET_SIZE || self.packet_size > MAX_PACKET_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("packet size has to be within {}..={}", MIN_PACKET_SIZE, MAX_PACKET_SIZE)));
+        }
+        if self.window == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "window has to be at least 1"));
+        }
+        Ok(Client {
+            window: self.window,
+            packet_size: self.packet_size,
+            retry_interval: self.retry_interval,
+            timeout: self.timeout,
+            progress: self.progress,
+            progress_interval: self.progress_interval,
+            sessions: self.sessions,
+            fec: self.fec,
+            compression: self.compression,
+            verify: self.verify,
+            multicast_interface: self.multicast_interface,
+        })
+    }
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        //The defaults always pass validation
+        ClientBuilder::default().build().unwrap()
+    }
+}
+
+impl Client {
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::new()
+    }
+
+    ///Bytes of file data carried by each chunk
+    pub fn chunk_size(&self) -> u64 {
+        (self.packet_size - mem::size_of::<u64>()) as u64
+    }
+
+    fn tracker(&self, chunk_count: u64, chunk_size: u64, total_bytes: u64, skipped_chunks: u64) -> ProgressTracker<'_> {
+        ProgressTracker {
+            observer: self.progress.as_deref(),
+            interval: self.progress_interval,
+            started: Instant::now(),
+            last_report: None,
+            chunk_count,
+            chunk_size,
+            total_bytes,
+            skipped_chunks,
+            skipped_bytes: skipped_chunks*chunk_size,
+        }
+    }
+
+    ///Ask a server how many chunks a file has, 0 means the file is empty or not on the whitelist
+    pub fn stat(&self, server: &str, filename: &str) -> io::Result<u64> {
+        let server_socket = client_socket()?;
+        let mut recv_buffer: Vec<u8> = vec![0; self.packet_size];
+        let mut stats = TransferStats::default();
+        self.request_metadata(&server_socket, &mut recv_buffer, server, filename, &mut stats, false).map(|info| info.chunk_count)
+    }
+
+    ///List every whitelisted file below directory on a server
+    pub fn list(&self, server: &str, directory: &str) -> io::Result<Vec<String>> {
+        let server_socket = client_socket()?;
+        let mut send_buffer: Vec<u8> = vec![0; self.packet_size];
+        let mut recv_buffer: Vec<u8> = vec![0; self.packet_size];
+        let mut stats = TransferStats::default();
+        let mut files: Vec<String> = Vec::new();
+
+        //Pages are requested one at a time until the server says there are no more
+        let mut page: u64 = 0;
+        loop {
+            let bytes_to_send = list_request_packet(directory, page, &mut send_buffer)?;
+            let br = self.exchange(&server_socket, &send_buffer[0..bytes_to_send], &mut recv_buffer, server, &mut stats,
+                |response| response.len() >= 16 && unpack_u8arr_into_u64(&response[0..8]) == page)?;
+            let page_count = unpack_u8arr_into_u64(&recv_buffer[8..16]);
+
+            let mut byte_counter: usize = 16;
+            while byte_counter < br {
+                match unpack_name(&recv_buffer[byte_counter..br]) {
+                    Some((name, packed_len)) => {
+                        files.push(name);
+                        byte_counter+=packed_len;
+                    },
+                    None => break,
+                }
+            }
+
+            page+=1;
+            if page >= page_count {
+                return Ok(files);
+            }
+        }
+    }
+
+    ///Fetch a whole file, writing it to out in order
+    pub fn fetch_to<W: Write>(&self, server: &str, filename: &str, out: &mut W) -> io::Result<TransferStats> {
+        self.fetch_from(server, filename, out, 0)
+    }
+
+    ///Fetch a file starting at first_chunk, writing it to out in order
+    ///Useful for resuming, out should already hold the first first_chunk*chunk_size() bytes
+    pub fn fetch_from<W: Write>(&self, server: &str, filename: &str, out: &mut W, first_chunk: u64) -> io::Result<TransferStats> {
+        let mut sink = SequentialSink {
+            out,
+            chunks: vec![Vec::new(); self.window],
+            decoder: None,
+        };
+        let stats = self.fetch(server, filename, &mut sink, first_chunk)?;
+        sink.out.flush()?;
+        Ok(stats)
+    }
+
+    ///Fetch a whole file, writing each chunk at its offset in out as soon as it arrives
+    ///Nothing is buffered, so the window only limits how many chunks are requested at once
+    pub fn fetch_to_seekable<W: Write + Seek>(&self, server: &str, filename: &str, out: &mut W) -> io::Result<TransferStats> {
+        let mut sink = SeekableSink {
+            out,
+            chunk_size: self.chunk_size(),
+        };
+        let stats = self.fetch(server, filename, &mut sink, 0)?;
+        sink.out.flush()?;
+        Ok(stats)
+    }
+
+    ///Bring out up to date with a file on the server, fetching only the chunks that differ from what it holds
+    ///Servers that can't hash chunks send all of them, either way out ends up the size of the file
+    pub fn fetch_update(&self, server: &str, filename: &str, out: &mut File) -> io::Result<TransferStats> {
+        let mut sink = UpdateSink {
+            out,
+            chunk_size: self.chunk_size(),
+        };
+        let stats = self.fetch(server, filename, &mut sink, 0)?;
+        sink.out.flush()?;
+        Ok(stats)
+    }
+
+    ///Fetch a whole file into memory
+    pub fn fetch_to_vec(&self, server: &str, filename: &str) -> io::Result<(Vec<u8>, TransferStats)> {
+        let mut data: Vec<u8> = Vec::new();
+        let stats = self.fetch_to(server, filename, &mut data)?;
+        Ok((data, stats))
+    }
+
+    ///Fetch a whole file from the multicast group a server sends it to, writing each chunk at its offset in out as soon as it arrives
+    ///Chunks still missing once the group went round the file, or all of them when nothing arrives on the group, are requested from server
+    ///With verify on, every chunk is checked against the Merkle root from servers that send one
+    pub fn fetch_multicast_to<W: Write + Seek>(&self, server: &str, group: &str, filename: &str, out: &mut W) -> io::Result<TransferStats> {
+        let started = Instant::now();
+        let server_socket = client_socket()?;
+        let group_socket = multicast_socket(group, self.multicast_interface)?;
+        let mut send_buffer: Vec<u8> = vec![0; self.packet_size];
+        let mut recv_buffer: Vec<u8> = vec![0; self.packet_size];
+        let mut stats = TransferStats::default();
+
+        //The version the group is sending has to be the one the server hands out
+        let info = match self.request_metadata(&server_socket, &mut recv_buffer, server, filename, &mut stats, false) {
+            Ok(i) => i,
+            Err(e) => {
+                error!(server:% = server, filename:% = filename, error:% = e; "Unable to request metadata");
+                return Err(e);
+            }
+        };
+        let (chunk_count, epoch) = (info.chunk_count, info.epoch);
+        let compact = info.features & FEATURE_COMPACT_RANGES != 0;
+        let mut fec = match info.fec {
+            Some(params) if self.fec && info.features & FEATURE_FEC != 0 => BlockDecoder::new(params, self.chunk_size() as usize, chunk_count),
+            _ => None,
+        };
+        let request = if fec.is_some() { RangeRequest::WithRepair } else { RangeRequest::Chunks };
+        //The group sends chunks as they are, but what the server sends directly may come compressed
+        let mut decompressor = if self.compression && compact { Decompressor::new(info.features)? } else { None };
+        let compress = decompressor.is_some();
+        let flags = if compress { COMPRESS_REQUEST } else { 0 };
+        let mut scratch: Vec<u8> = vec![0; self.chunk_size() as usize];
+        debug!(server:% = server, group:% = group, filename:% = filename, chunks = chunk_count, fec = fec.is_some(), compression = compress; "Got metadata, listening to the group");
+        if chunk_count == 0 {
+            if !info.found {
+                warn!(server:% = server, filename:% = filename; "The requested file is not on the whitelist of requestable files");
+            }
+            stats.duration = started.elapsed();
+            return Ok(stats)
+        }
+
+        //Chunks from the group are checked like fetched ones, against hashes tied to the root a window at a time
+        let root = if self.verify && compact { info.root } else { None };
+        let mut leaves: Vec<[u8; HASH_LEN]> = Vec::new(); //Checked hashes of every chunk, empty when not verifying
+        if let Some(root) = root {
+            let mut first: u64 = 0;
+            while first < chunk_count {
+                let last = (first+self.window as u64-1).min(chunk_count-1);
+                let mut attempts = 0;
+                loop {
+                    let hashes = self.request_hashes(&server_socket, server, filename, (first, last), &mut stats,
+                        |s, e, buffer| compact_chunk_request_packet(filename, epoch, s, e, RangeRequest::Hashes, flags, buffer))?;
+                    let ids = merkle::proof_nodes(chunk_count, first, last);
+                    let nodes = self.request_nodes(&server_socket, server, filename, &ids, &mut stats,
+                        |s, e, buffer| compact_chunk_request_packet(filename, epoch, s, e, RangeRequest::Nodes, flags, buffer))?;
+                    if merkle::root_from(chunk_count, first, &hashes, &nodes) == Some(root) {
+                        leaves.extend(hashes);
+                        break;
+                    }
+                    attempts += 1;
+                    if attempts == 2 {
+                        error!(server:% = server, filename:% = filename, first = first, last = last; "Chunk hashes don't match the Merkle root");
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("The hashes of chunks {}-{} of {} don't match the Merkle root the server sent", first, last, filename)));
+                    }
+                    warn!(server:% = server, filename:% = filename, first = first, last = last; "Chunk hashes don't match the Merkle root, asking for them again");
+                }
+                first = last+1;
+            }
+            debug!(server:% = server, filename:% = filename, chunks = chunk_count; "Got the hashes of the file");
+        }
+
+        let chunk_size = self.chunk_size();
+        let mut sink = SeekableSink {
+            out,
+            chunk_size,
+        };
+        let mut tracker = self.tracker(chunk_count, chunk_size, chunk_count*chunk_size, 0);
+        tracker.update(&stats, false);
+        //The whole file is one window, the group sends all of it anyway
+        let mut received: Vec<bool> = vec![false; chunk_count as usize];
+        let mut rt: RangeTree = RangeTree::new(0, (chunk_count-1) as usize);
+        let retry_interval = match stats.rtt {
+            Some(rtt) => self.retry_interval.max(rtt*2),
+            None => self.retry_interval,
+        };
+
+        let mut joined_at: Option<u64> = None; //First chunk heard on the group
+        let mut last_group_chunk: u64 = 0;
+        let mut wrapped: bool = false; //The group went past the last chunk since we joined
+        let mut repairing: bool = false; //Requesting what's missing from the server
+        let mut group_heard: Instant = Instant::now();
+        let mut counter: Instant = Instant::now();
+        let mut last_heard: Instant = Instant::now();
+        let mut next: bool = false;
+        loop {
+            if last_heard.elapsed() > self.timeout {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, format!("Nothing from {} or {} for {:?}", group, server, self.timeout)));
+            }
+            //The group is quiet, either nobody sends to it or it doesn't reach us
+            //Once heard it can pause between rounds, so only give up on it well before the timeout
+            let silence = match joined_at {
+                None => retry_interval*GROUP_SILENCE,
+                Some(_) => self.timeout/2,
+            };
+            if !repairing && group_heard.elapsed() > silence {
+                debug!(server:% = server, group:% = group, filename:% = filename; "Nothing on the group, requesting chunks from the server");
+                repairing = true;
+                next = true;
+            }
+            if repairing && (next || counter.elapsed() > retry_interval) {
+                let mut missing: Vec<(u64, u64)> = rt.intervals.iter().map(|xint| (rt.tree_vec[*xint].start as u64, rt.tree_vec[*xint].end as u64)).collect();
+                missing.sort_unstable();
+                let (s, e): (Vec<u64>, Vec<u64>) = missing.into_iter().unzip();
+                let bytes_to_send = if compact {
+                    compact_chunk_request_packet(filename, epoch, &s, &e, request, flags, &mut send_buffer)?
+                } else {
+                    range_chunk_request_packet(filename, epoch, s, e, &mut send_buffer)?
+                };
+                if let Err(e) = server_socket.send_to(&send_buffer[0..bytes_to_send], server) {
+                    error!(server:% = server, error:% = e; "Unable to send data");
+                    return Err(e)
+                }
+                if !next {
+                    stats.retransmits += 1;
+                }
+                counter = Instant::now();
+                next = false;
+            }
+
+            let mut rebuilt: Vec<(u64, Vec<u8>)> = Vec::new();
+            for (socket, from_group) in [(&group_socket, true), (&server_socket, false)] {
+                let br = match socket.recv(&mut recv_buffer) {
+                    Ok(br) if br >= 8 => br,
+                    Ok(_) => continue,
+                    Err(err) => match err.kind() {
+                        io::ErrorKind::WouldBlock => continue,
+                        _ => return Err(err),
+                    }
+                };
+                let (chunkdex, data) = match compress::unpack_chunk(&recv_buffer[0..br], decompressor.as_mut(), &mut scratch) {
+                    Some(c) => c,
+                    None => {
+                        debug!(server:% = server, filename:% = filename; "Unable to decompress a chunk");
+                        continue;
+                    }
+                };
+                if from_group {
+                    group_heard = Instant::now();
+                }
+                if chunkdex == CONTROL_CHUNK {
+                    let code = if br >= 16 { unpack_u8arr_into_u64(&recv_buffer[8..16]) } else { 0 };
+                    let other_version = code == MULTICAST_INFO && br >= 32
+                        && (unpack_u8arr_into_u64(&recv_buffer[16..24]) != chunk_count || unpack_u8arr_into_u64(&recv_buffer[24..32]) != epoch);
+                    if code == FILE_CHANGED || other_version {
+                        warn!(server:% = server, group:% = group, filename:% = filename; "File changed on the server during the transfer");
+                        return Err(file_changed(filename));
+                    }
+                } else if chunkdex & REPAIR_CHUNK != 0 {
+                    if let Some(decoder) = fec.as_mut() {
+                        last_heard = Instant::now();
+                        rebuilt.extend(decoder.repair(chunkdex & !REPAIR_CHUNK, &recv_buffer[8..br]));
+                    }
+                } else if chunkdex < chunk_count {
+                    if from_group {
+                        //Once the group comes back round to where we joined, whatever is still missing was lost
+                        match joined_at {
+                            None => joined_at = Some(chunkdex),
+                            Some(joined) => {
+                                wrapped |= chunkdex < last_group_chunk;
+                                if wrapped && chunkdex >= joined && !repairing {
+                                    debug!(server:% = server, group:% = group, filename:% = filename, ranges = rt.intervals.len(); "Went round the file, requesting what's missing from the server");
+                                    repairing = true;
+                                    next = true;
+                                }
+                            },
+                        }
+                        last_group_chunk = chunkdex;
+                        //The block before the previous one had its repair packets go by already
+                        if let Some(decoder) = fec.as_mut() {
+                            if let Some(done) = decoder.block_end_before(chunkdex).and_then(|c| c.checked_sub(1)).and_then(|c| decoder.block_end_before(c)) {
+                                decoder.forget_through(done);
+                            }
+                        }
+                    } else {
+                        counter = Instant::now();
+                    }
+                    last_heard = Instant::now();
+                    if received[chunkdex as usize] {
+                        stats.duplicate_packets += 1;
+                        continue;
+                    }
+                    if !verified(&leaves, chunkdex as usize, data) {
+                        stats.rejected += 1;
+                        warn!(server:% = server, group:% = group, filename:% = filename, chunk = chunkdex; "Chunk doesn't match the Merkle tree");
+                        continue;
+                    }
+                    rt.add_packet(chunkdex as usize);
+                    received[chunkdex as usize] = true;
+                    sink.chunk(0, chunkdex, data)?;
+                    stats.chunks += 1;
+                    stats.bytes += data.len() as u64;
+                    tracker.update(&stats, false);
+                    if let Some(decoder) = fec.as_mut() {
+                        rebuilt.extend(decoder.chunk(chunkdex, data));
+                    }
+                }
+            }
+            for (chunk, data) in rebuilt {
+                if received[chunk as usize] {
+                    continue;
+                }
+                if !verified(&leaves, chunk as usize, &data) {
+                    stats.rejected += 1;
+                    warn!(server:% = server, group:% = group, filename:% = filename, chunk = chunk; "Rebuilt chunk doesn't match the Merkle tree");
+                    continue;
+                }
+                rt.add_packet(chunk as usize);
+                received[chunk as usize] = true;
+                sink.chunk(0, chunk, &data)?;
+                stats.chunks += 1;
+                stats.bytes += data.len() as u64;
+                stats.repaired += 1;
+                tracker.update(&stats, false);
+            }
+
+            if rt.intervals.is_empty() {
+                tracker.update(&stats, true);
+                break;
+            }
+        }
+        sink.out.flush()?;
+        //Same as a stateless fetch, the last chunks could have been read while the file was being rewritten
+        if epoch != 0 {
+            let check_socket = client_socket()?;
+            let last_info = self.request_metadata(&check_socket, &mut recv_buffer, server, filename, &mut TransferStats::default(), false)?;
+            if last_info.epoch != epoch {
+                warn!(server:% = server, filename:% = filename; "File changed on the server during the transfer");
+                return Err(file_changed(filename));
+            }
+        }
+
+        stats.duration = started.elapsed();
+        Ok(stats)
+    }
+
+    ///Push a file to a server under remote_name, signing the announcement with the server's upload key
+    ///input is read once to hash it and then again for every chunk the server is missing
+    pub fn push<R: Read + Seek>(&self, server: &str, remote_name: &str, input: &mut R, key: &[u8]) -> io::Result<TransferStats> {
+        let started = Instant::now();
+        let server_socket = client_socket()?;
+        let mut send_buffer: Vec<u8> = vec![0; self.packet_size];
+        let mut recv_buffer: Vec<u8> = vec![0; self.packet_size];
+        let mut stats = TransferStats::default();
+
+        input.seek(SeekFrom::Start(0))?;
+        let (hash, size) = upload::hash_reader(input)?;
+        let announcement = Announcement {
+            name: String::from(remote_name),
+            size,
+            hash,
+            timestamp: upload::unix_time(),
+        };
+        let bytes_to_send = upload::announce_packet(&announcement, key, &mut send_buffer)?;
+        self.exchange(&server_socket, &send_buffer[0..bytes_to_send], &mut recv_buffer, server, &mut stats,
+            |response| response.len() >= 40 && unpack_u8arr_into_u64(&response[0..8]) == upload::UPLOAD_ANNOUNCE)?;
+        let upload_id = unpack_u8arr_into_u64(&recv_buffer[8..16]);
+        let chunk_count = unpack_u8arr_into_u64(&recv_buffer[16..24]);
+        let chunk_size = unpack_u8arr_into_u64(&recv_buffer[24..32]);
+        let result = unpack_u8arr_into_u64(&recv_buffer[32..40]);
+        if upload_id == 0 || result != AnnounceResult::Accepted as u64 {
+            let reason = match result {
+                r if r == AnnounceResult::Unauthorized as u64 => "the key or the clock is wrong",
+                r if r == AnnounceResult::InvalidName as u64 => "the name isn't allowed",
+                r if r == AnnounceResult::TooLarge as u64 => "the file is too large",
+                r if r == AnnounceResult::Exists as u64 => "the file already exists",
+                r if r == AnnounceResult::Disabled as u64 => "uploads are disabled",
+                r if r == AnnounceResult::NoSpace as u64 => "the server is out of space",
+                _ => "the server failed to set it up",
+            };
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("Upload of {} refused, {}", remote_name, reason)));
+        }
+        if chunk_size as usize+3*mem::size_of::<u64>() > self.packet_size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("Server uses a packet size of {}, but the client is set to {}", chunk_size as usize+3*mem::size_of::<u64>(), self.packet_size)));
+        }
+
+        let mut tracker = self.tracker(chunk_count, chunk_size, size, 0);
+        tracker.update(&stats, false);
+        //Everything is missing to begin with, after that the server tells us what it still needs
+        let mut missing: Vec<(u64, u64)> = if chunk_count > 0 { vec![(0, chunk_count-1)] } else { Vec::new() };
+        let mut sent: Vec<bool> = vec![false; chunk_count as usize];
+        let mut chunk: Vec<u8> = vec![0; chunk_size as usize];
+        loop {
+            //Send up to a window worth of missing chunks, then ask where the server stands
+            let mut budget = self.window;
+            for (s, e) in missing.iter() {
+                for index in *s..=*e {
+                    if budget == 0 || index >= chunk_count {
+                        break;
+                    }
+                    input.seek(SeekFrom::Start(index*chunk_size))?;
+                    let bytes_read = read_full(input, &mut chunk)?;
+                    let bytes_to_send = upload::upload_chunk_packet(upload_id, index, &chunk[0..bytes_read], &mut send_buffer);
+                    server_socket.send_to(&send_buffer[0..bytes_to_send], server)?;
+                    if sent[index as usize] {
+                        stats.retransmits += 1;
+                    } else {
+                        sent[index as usize] = true;
+                        stats.chunks += 1;
+                        stats.bytes += bytes_read as u64;
+                    }
+                    tracker.update(&stats, false);
+                    budget -= 1;
+                }
+            }
+
+            let bytes_to_send = upload::status_request_packet(upload_id, &mut send_buffer);
+            let br = self.exchange(&server_socket, &send_buffer[0..bytes_to_send], &mut recv_buffer, server, &mut stats,
+                |response| response.len() >= 32 && unpack_u8arr_into_u64(&response[0..8]) == upload::UPLOAD_STATUS
+                    && unpack_u8arr_into_u64(&response[8..16]) == upload_id)?;
+            let state = unpack_u8arr_into_u64(&recv_buffer[16..24]);
+            if state == UploadState::Complete as u64 {
+                tracker.update(&stats, true);
+                break;
+            } else if state == UploadState::HashMismatch as u64 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Upload of {} failed verification on the server", remote_name)));
+            } else if state == UploadState::Exists as u64 {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} was created on the server while it was uploaded", remote_name)));
+            } else if state == UploadState::Verifying as u64 {
+                //Nothing is missing, the server is hashing the file
+                missing.clear();
+                thread::sleep(Duration::from_millis(50));
+                continue;
+            } else if state != UploadState::Receiving as u64 {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("Server forgot about the upload of {}", remote_name)));
+            }
+
+            let range_count = unpack_u8arr_into_u64(&recv_buffer[24..32]) as usize;
+            missing.clear();
+            for i in 0..range_count {
+                let offset = 32+16*i;
+                if offset+16 > br {
+                    break;
+                }
+                missing.push((unpack_u8arr_into_u64(&recv_buffer[offset..offset+8]), unpack_u8arr_into_u64(&recv_buffer[offset+8..offset+16])));
+            }
+        }
+
+        stats.duration = started.elapsed();
+        Ok(stats)
+    }
+
+    ///Request a file by requesting all of its chunks a window at a time, limiting the amount of the file stored in RAM at any moment
+    fn fetch<S: ChunkSink>(&self, server: &str, filename: &str, sink: &mut S, first_chunk: u64) -> io::Result<TransferStats> {
+        let started = Instant::now();
+        let server_socket = client_socket()?; //Create the socket using provided params
+        let mut send_buffer: Vec<u8> = vec![0; self.packet_size];
+        let mut recv_buffer: Vec<u8> = vec![0; self.packet_size];
+        //Chunks stream in, take them off the socket several at a time
+        let mut receiver = Receiver::new(self.packet_size);
+        let chunk_mem_limit = self.window;
+        let mut stats = TransferStats::default();
+
+        //A pre-compressed variant can only be taken whole and in order, by sinks that decode it
+        let variants = self.compression && first_chunk == 0 && sink.decodes();
+        //GOOD, this method handles repeating requests in a reasonable timeframe
+        let opened = if self.sessions {
+            self.open_session(&server_socket, &mut recv_buffer, server, filename, &mut stats, variants)
+        } else {
+            self.request_metadata(&server_socket, &mut recv_buffer, server, filename, &mut stats, variants).map(|info| (info, None))
+        };
+        let (info, session) = match opened {
+            Ok(c) => c,
+            Err(e) => {
+                error!(server:% = server, filename:% = filename, error:% = e; "Unable to request metadata");
+                return Err(e);
+            }
+        };
+
+        let (chunk_count, epoch) = (info.chunk_count, info.epoch);
+        //Servers that understand compact ranges fit far more of them in a request
+        let compact = info.features & FEATURE_COMPACT_RANGES != 0;
+        //and servers that take NACKs get told about lost chunks as soon as they're noticed
+        let nack = compact && info.features & FEATURE_NACK != 0;
+        //and servers sending repair packets get asked for them, so lost chunks can be rebuilt without asking again
+        let mut fec = match info.fec {
+            Some(params) if self.fec && info.features & FEATURE_FEC != 0 => BlockDecoder::new(params, self.chunk_size() as usize, chunk_count),
+            _ => None,
+        };
+        let request = if fec.is_some() { RangeRequest::WithRepair } else { RangeRequest::Chunks };
+        //and servers compressing chunks get asked for compressed ones, unless what they send is compressed already
+        let mut decompressor = if self.compression && compact && info.variant.is_none() { Decompressor::new(info.features)? } else { None };
+        let compress = decompressor.is_some();
+        //Stateless requests for a variant have to say so, the server only knows the name of the file
+        let flags = match (info.variant, session) {
+            (Some(_), None) => VARIANT_REQUEST,
+            _ if compress => COMPRESS_REQUEST,
+            _ => 0,
+        };
+        if let Some(v) = info.variant {
+            sink.decode(v)?;
+        }
+        //and servers hashing chunks are asked for the hashes of every window first, so chunks the local copy has aren't fetched
+        let update = compact && info.features & FEATURE_HASHES != 0 && sink.updates();
+        //and servers with a Merkle tree send the nodes that tie those hashes to the root, so every chunk is checked as it arrives
+        let root = if self.verify && compact { info.root } else { None };
+        let mut leaves: Vec<[u8; HASH_LEN]> = Vec::new(); //Checked hashes of the chunks of the current window, empty when not verifying
+        let mut scratch: Vec<u8> = vec![0; self.chunk_size() as usize];
+        debug!(server:% = server, filename:% = filename, chunks = chunk_count, chunk_size = self.chunk_size(), compact = compact, nack = nack, fec = fec.is_some(), compression = compress, variant = info.variant.is_some(), update = update, verify = root.is_some(); "Got metadata");
+        if chunk_count == 0 {
+            if info.found {
+                //Empty on the server, so the local copy is too
+                debug!(server:% = server, filename:% = filename; "The requested file is empty");
+                sink.truncate(0)?;
+            } else {
+                warn!(server:% = server, filename:% = filename; "The requested file is not on the whitelist of requestable files");
+            }
+            stats.duration = started.elapsed();
+            return Ok(stats)
+        }
+        if first_chunk >= chunk_count {
+            //Nothing left to fetch
+            stats.duration = started.elapsed();
+            return Ok(stats)
+        }
+
+        let chunk_size = self.chunk_size();
+        //Repair packets only come for blocks requested whole, so windows keep to block boundaries
+        let chunk_mem_limit = match &info.fec {
+            Some(params) if fec.is_some() && chunk_mem_limit as u64 >= params.block_chunks => chunk_mem_limit - chunk_mem_limit%(params.block_chunks as usize),
+            _ => chunk_mem_limit,
+        };
+        let mut tracker = self.tracker(chunk_count, chunk_size, chunk_count*chunk_size, first_chunk);
+        tracker.update(&stats, false);
+        let mut received: Vec<bool> = vec![false; chunk_mem_limit]; //Which chunks of the current window have arrived
+        //Each part is a window of chunks, request from part_start through the lesser of part_start+window or chunkcount
+        let mut part_start: u64 = first_chunk;
+        let mut part_end: u64;
+        if (part_start+chunk_mem_limit as u64) < chunk_count {
+            part_end = part_start+(chunk_mem_limit-1) as u64;
+        } else {
+            part_end = chunk_count-1;
+        }
+
+        let mut rt: RangeTree = RangeTree::new(part_start as usize,part_end as usize);
+
+        //Chunks of a request come back in order, so a gap behind the highest chunk since the last request means loss
+        let mut highest: Option<u64> = None;
+        let mut nacked_until: u64 = part_start; //Chunks below this were received or reported lost
+        //Don't ask again before the first chunk could be back, that only gets everything sent twice on slow links
+        let retry_interval = match stats.rtt {
+            Some(rtt) => self.retry_interval.max(rtt*2),
+            None => self.retry_interval,
+        };
+
+        let mut counter: Instant = Instant::now(); //Counter used to track how long it has been since we requested something
+        let mut last_heard: Instant = Instant::now(); //When we last heard anything useful from the server
+        let mut next: bool = true; //Boolean used to indicate that regardless of the counter, it's time to request a new packet
+        let mut last_len: Option<usize> = None; //Length of the last chunk of the file once it's in
+        loop {
+            //A new window, its hashes have to lead up to the root and the chunks the local copy already has count as received
+            if next && (update || root.is_some()) {
+                //Hashes and nodes can be damaged on the way like any packet, so they get a second chance
+                let mut attempts = 0;
+                let hashes = loop {
+                    let hashes = self.request_hashes(&server_socket, server, filename, (part_start, part_end), &mut stats, |s, e, buffer| match session {
+                        Some((session_id, handle)) => Ok(session::compact_chunk_request_packet(session_id, handle, s, e, RangeRequest::Hashes, flags, buffer)),
+                        None => compact_chunk_request_packet(filename, epoch, s, e, RangeRequest::Hashes, flags, buffer),
+                    })?;
+                    let root = match root {
+                        Some(r) => r,
+                        None => break hashes,
+                    };
+                    let ids = merkle::proof_nodes(chunk_count, part_start, part_end);
+                    let nodes = self.request_nodes(&server_socket, server, filename, &ids, &mut stats, |s, e, buffer| match session {
+                        Some((session_id, handle)) => Ok(session::compact_chunk_request_packet(session_id, handle, s, e, RangeRequest::Nodes, flags, buffer)),
+                        None => compact_chunk_request_packet(filename, epoch, s, e, RangeRequest::Nodes, flags, buffer),
+                    })?;
+                    if merkle::root_from(chunk_count, part_start, &hashes, &nodes) == Some(root) {
+                        break hashes;
+                    }
+                    attempts += 1;
+                    if attempts == 2 {
+                        error!(server:% = server, filename:% = filename, first = part_start, last = part_end; "Chunk hashes don't match the Merkle root");
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("The hashes of chunks {}-{} of {} don't match the Merkle root the server sent", part_start, part_end, filename)));
+                    }
+                    warn!(server:% = server, filename:% = filename, first = part_start, last = part_end; "Chunk hashes don't match the Merkle root, asking for them again");
+                };
+                for (i, hash) in hashes.iter().enumerate().filter(|_| update) {
+                    let chunk = part_start + i as u64;
+                    let bytes = sink.local_chunk(chunk, &mut scratch)?;
+                    if bytes == 0 || delta::chunk_hash(&scratch[0..bytes]) != *hash {
+                        continue;
+                    }
+                    rt.add_packet(chunk as usize);
+                    received[i] = true;
+                    stats.unchanged += 1;
+                    if chunk == chunk_count-1 {
+                        last_len = Some(bytes);
+                    }
+                }
+                debug!(server:% = server, filename:% = filename, first = part_start, last = part_end, ranges = rt.intervals.len(), update = update; "Got the hashes of the window");
+                if root.is_some() {
+                    leaves = hashes;
+                }
+                tracker.update(&stats, false);
+                last_heard = Instant::now();
+            }
+            if last_heard.elapsed() > self.timeout {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, format!("No response from {} for {:?}", server, self.timeout)));
+            }
+            //Check a timer and flag to decide if we need to send a request
+            //If we have gone retry_interval without receiving anything, request something
+            //A window the local copy had all of needs nothing
+            if (next || counter.elapsed() > retry_interval) && !rt.intervals.is_empty() {
+                //In order, so the server sends the chunks in order
+                let mut missing: Vec<(u64, u64)> = rt.intervals.iter().map(|xint| (rt.tree_vec[*xint].start as u64, rt.tree_vec[*xint].end as u64)).collect();
+                missing.sort_unstable();
+                let (s, e): (Vec<u64>, Vec<u64>) = missing.into_iter().unzip();
+                highest = None;
+                nacked_until = part_start;
+
+                let bytes_to_send = match session {
+                    Some((session_id, handle)) if compact => session::compact_chunk_request_packet(session_id, handle, &s, &e, request, flags, &mut send_buffer),
+                    Some((session_id, handle)) => session::chunk_request_packet(session_id, handle, &s, &e, &mut send_buffer),
+                    None if compact => compact_chunk_request_packet(filename, epoch, &s, &e, request, flags, &mut send_buffer)?,
+                    None => range_chunk_request_packet(filename,epoch,s,e,&mut send_buffer)?,
+                };
+                match server_socket.send_to(&send_buffer[0..bytes_to_send], server)
+                {
+                    Ok(_) => {
+                        if !next {
+                            stats.retransmits += 1;
+                            debug!(server:% = server, filename:% = filename, ranges = rt.intervals.len(); "Requesting missing chunks again");
+                        }
+                        counter = Instant::now();
+                    },
+                    Err(e) => {
+                        error!(server:% = server, error:% = e; "Unable to send data");
+                        return Err(e)
+                    }
+                }
+                next = false;
+            }
+
+            let mut rebuilt: Vec<(u64, Vec<u8>)> = Vec::new();
+            match receiver.recv(&server_socket, &mut recv_buffer)
+            {
+                //We either get the next packet, miss a packet, or a latecomer arrives
+                Ok(br) => {
+                    //Compressed chunks are decompressed into scratch, data is what the file holds
+                    let (chunkdex, data) = match compress::unpack_chunk(&recv_buffer[0..br], decompressor.as_mut(), &mut scratch) {
+                        Some(c) => c,
+                        None => {
+                            debug!(server:% = server, filename:% = filename; "Unable to decompress a chunk");
+                            continue;
+                        }
+                    };
+                    if chunkdex == CONTROL_CHUNK && br >= 16 && unpack_u8arr_into_u64(&recv_buffer[8..16]) == FILE_CHANGED {
+                        warn!(server:% = server, filename:% = filename; "File changed on the server during the transfer");
+                        return Err(file_changed(filename));
+                    }
+                    //Only keep chunks from the current window that we haven't got yet, latecomers and repeats are dropped
+                    if info.is_repeat(&recv_buffer[0..br]) {
+                        stats.duplicate_packets += 1;
+                    } else if chunkdex != CONTROL_CHUNK && chunkdex & REPAIR_CHUNK != 0 {
+                        //A repair packet, with enough of its block it rebuilds the lost chunks
+                        if let Some(decoder) = fec.as_mut() {
+                            counter = Instant::now();
+                            last_heard = Instant::now();
+                            rebuilt = decoder.repair(chunkdex & !REPAIR_CHUNK, &recv_buffer[8..br]);
+                        }
+                    } else if chunkdex >= part_start && chunkdex <= part_end && !received[(chunkdex-part_start) as usize] && !verified(&leaves, (chunkdex-part_start) as usize, data) {
+                        //Corrupt on the way or on the server, asked for again like a lost chunk
+                        stats.rejected += 1;
+                        warn!(server:% = server, filename:% = filename, chunk = chunkdex; "Chunk doesn't match the Merkle tree");
+                    } else if chunkdex >= part_start && chunkdex <= part_end && !received[(chunkdex-part_start) as usize] {
+                        let index = (chunkdex-part_start) as usize;
+                        rt.add_packet(chunkdex as usize);
+                        //Nailed it, got a chunk
+                        counter = Instant::now();
+                        last_heard = Instant::now();
+                        received[index] = true;
+                        sink.chunk(index, chunkdex, data)?;
+                        if chunkdex == chunk_count-1 {
+                            last_len = Some(data.len());
+                        }
+                        stats.chunks += 1;
+                        stats.bytes += data.len() as u64;
+                        tracker.update(&stats, false);
+                        if let Some(decoder) = fec.as_mut() {
+                            rebuilt = decoder.chunk(chunkdex, data);
+                        }
+
+                        let newest = highest.map_or(chunkdex, |h| h.max(chunkdex));
+                        highest = Some(newest);
+                        //Lost chunks of a block may still be rebuilt until its repair packets went by, which is right after the block
+                        let settled = match &fec {
+                            Some(decoder) => newest.checked_sub(NACK_REORDER).and_then(|c| decoder.block_end_before(c)),
+                            None => newest.checked_sub(NACK_REORDER),
+                        };
+                        if let Some(last) = settled.filter(|last| nack && *last >= nacked_until) {
+                            let (s, e) = missing_ranges(&received, part_start, nacked_until, last);
+                            nacked_until = last+1;
+                            if !s.is_empty() {
+                                let bytes_to_send = match session {
+                                    Some((session_id, handle)) => session::compact_chunk_request_packet(session_id, handle, &s, &e, RangeRequest::Lost, flags, &mut send_buffer),
+                                    None => compact_chunk_request_packet(filename, epoch, &s, &e, RangeRequest::Lost, flags, &mut send_buffer)?,
+                                };
+                                if let Err(e) = server_socket.send_to(&send_buffer[0..bytes_to_send], server) {
+                                    error!(server:% = server, error:% = e; "Unable to send data");
+                                    return Err(e)
+                                }
+                                stats.nacks += 1;
+                                debug!(server:% = server, filename:% = filename, first = s[0], ranges = s.len(); "Reporting lost chunks");
+                            }
+                        }
+                    } else if chunkdex < chunk_count {
+                        stats.duplicate_packets += 1;
+                    }
+                },
+                Err(err) => match err.kind() {
+                    io::ErrorKind::WouldBlock => {},
+                    _ => return Err(err),
+                }
+            }
+            //Rebuilt chunks count like received ones, unless they belong to another window or already arrived
+            for (chunk, data) in rebuilt {
+                if chunk < part_start || chunk > part_end || received[(chunk-part_start) as usize] {
+                    continue;
+                }
+                let index = (chunk-part_start) as usize;
+                if !verified(&leaves, index, &data) {
+                    stats.rejected += 1;
+                    warn!(server:% = server, filename:% = filename, chunk = chunk; "Rebuilt chunk doesn't match the Merkle tree");
+                    continue;
+                }
+                rt.add_packet(chunk as usize);
+                received[index] = true;
+                sink.chunk(index, chunk, &data)?;
+                if chunk == chunk_count-1 {
+                    last_len = Some(data.len());
+                }
+                stats.chunks += 1;
+                stats.bytes += data.len() as u64;
+                stats.repaired += 1;
+                tracker.update(&stats, false);
+            }
+
+            if rt.intervals.is_empty() {
+                //We're done with this bit, write it out and move on
+                sink.window_done()?;
+
+                if part_end == chunk_count-1 {
+                    //We're done!
+                    tracker.update(&stats, true);
+                    break;
+                } else {
+                    //Reinitialize all of our data structures
+                    //Set the new start and end
+                    part_start = part_end+1;
+                    if (part_start+chunk_mem_limit as u64) < chunk_count {
+                        part_end = part_start+ (chunk_mem_limit-1) as u64;
+                    } else {
+                        part_end = chunk_count-1;
+                    }
+                    for r in received.iter_mut() {
+                        *r = false;
+                    }
+                    if let Some(decoder) = fec.as_mut() {
+                        decoder.clear();
+                    }
+                    next = true;
+
+                    rt.reinit(part_start as usize, part_end as usize);
+                }
+            }
+        }
+        //Let the server close the file now instead of waiting for the session to time out, it's fine if this gets lost
+        if let Some((session_id, _)) = session {
+            let bytes_to_send = session::close_request_packet(session_id, 0, &mut send_buffer);
+            let _ = server_socket.send_to(&send_buffer[0..bytes_to_send], server);
+        } else if epoch != 0 {
+            //The last chunks could have been read while the file was being rewritten, make sure it's still the same version
+            //On a new socket so chunks still in flight can't pass for the response
+            let check_socket = client_socket()?;
+            let last_info = self.request_metadata(&check_socket, &mut recv_buffer, server, filename, &mut TransferStats::default(), info.variant.is_some())?;
+            if last_info.epoch != epoch {
+                warn!(server:% = server, filename:% = filename; "File changed on the server during the transfer");
+                return Err(file_changed(filename));
+            }
+        }
+        sink.finish()?;
+        if let Some(len) = last_len {
+            sink.truncate((chunk_count-1)*chunk_size + len as u64)?;
+        }
+
+        stats.duration = started.elapsed();
+        Ok(stats)
+    }
+
+    ///Open filename in a new server side session and return what the server said about it along with the session ID and handle
+    ///Falls back to a plain metadata request when the server can't give us a session
+    ///The server pins the opened file itself, so sessions don't need the epoch and get 0
+    ///variants takes the file's pre-compressed variant if the server has one
+    fn open_session(&self, server_socket: &UdpSocket, recv_buffer: &mut [u8], server: &str, filename: &str, stats: &mut TransferStats, variants: bool) -> io::Result<(FileInfo, Option<SessionFile>)> {
+        let mut send_buffer: Vec<u8> = vec![0; self.packet_size];
+        let flags = if variants { COMPRESS_REQUEST } else { 0 };
+        let bytes_to_send = session::open_request_packet(0, filename, flags, &mut send_buffer)?;
+        let br = self.exchange(server_socket, &send_buffer[0..bytes_to_send], recv_buffer, server, stats,
+            |response| response.len() >= 48 && unpack_u8arr_into_u64(&response[0..8]) == session::SESSION_OPEN)?;
+
+        //u64 ID, session ID, handle, chunk count, chunk size, result and features
+        let session_id = unpack_u8arr_into_u64(&recv_buffer[8..16]);
+        let handle = unpack_u8arr_into_u64(&recv_buffer[16..24]);
+        let chunk_count = unpack_u8arr_into_u64(&recv_buffer[24..32]);
+        let chunk_size = unpack_u8arr_into_u64(&recv_buffer[32..40]) as usize;
+        let result = unpack_u8arr_into_u64(&recv_buffer[40..48]);
+        let features = if br >= 56 { unpack_u8arr_into_u64(&recv_buffer[48..56]) } else { 0 };
+        if result == OpenResult::NotAllowed as u64 {
+            return Ok((FileInfo { chunk_count: 0, found: false, epoch: 0, features, fec: None, variant: None, root: None, reply: recv_buffer[0..br].to_vec() }, None));
+        }
+        if result != OpenResult::Opened as u64 {
+            debug!(server:% = server, filename:% = filename, result = result; "No session, falling back to stateless requests");
+            return self.request_metadata(server_socket, recv_buffer, server, filename, stats, variants).map(|info| (info, None));
+        }
+        if chunk_size+mem::size_of::<u64>() != self.packet_size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("Server uses a packet size of {}, but the client is set to {}", chunk_size+mem::size_of::<u64>(), self.packet_size)));
+        }
+        debug!(server:% = server, filename:% = filename, session = session_id, handle = handle; "Opened session");
+        let (fec, variant, root) = unpack_extras(features, &recv_buffer[56.min(br)..br])?;
+        Ok((FileInfo { chunk_count, found: true, epoch: 0, features, fec, variant, root, reply: recv_buffer[0..br].to_vec() }, Some((session_id, handle))))
+    }
+
+    ///Request metadata for filename and return what the server said about it
+    ///Fails if the server hands out chunks of a different size than the packet size allows for
+    ///variants takes the file's pre-compressed variant if the server has one
+    fn request_metadata(&self, server_socket: &UdpSocket, recv_buffer: &mut [u8], server: &str, filename: &str, stats: &mut TransferStats, variants: bool) -> io::Result<FileInfo> {
+        //Send a metadata request until we have a confirmed response or an error
+        let mut send_buffer: Vec<u8> = vec![0; self.packet_size];
+        let flags = if variants { COMPRESS_REQUEST } else { 0 };
+        let bytes_to_send = metadata_request_packet(filename, flags, &mut send_buffer)?;
+        let br = self.exchange(server_socket, &send_buffer[0..bytes_to_send], recv_buffer, server, stats,
+            |response| response.len() >= 16)?;
+
+        //Metadata responses pass back the chunk count as a u64 in bytes 8-16 and the chunk size in bytes 16-24
+        let chunk_count = unpack_u8arr_into_u64(&recv_buffer[8..16]);
+        if chunk_count != 0 && br >= 24 {
+            let chunk_size = unpack_u8arr_into_u64(&recv_buffer[16..24]) as usize;
+            if chunk_size+mem::size_of::<u64>() != self.packet_size {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("Server uses a packet size of {}, but the client is set to {}", chunk_size+mem::size_of::<u64>(), self.packet_size)));
+            }
+        }
+        //Followed by the epoch in bytes 24-32, the features in bytes 32-40, how the file is protected by repair packets if it is
+        //what the variant decodes to if the rest describes one and the Merkle root if the server sends one
+        let epoch = if br >= 32 { unpack_u8arr_into_u64(&recv_buffer[24..32]) } else { 0 };
+        let features = if br >= 40 { unpack_u8arr_into_u64(&recv_buffer[32..40]) } else { 0 };
+        let (fec, variant, root) = unpack_extras(features, &recv_buffer[40.min(br)..br])?;
+        //Denied requests get a response of zeros, files the server hands out always have an epoch
+        Ok(FileInfo { chunk_count, found: epoch != 0, epoch, features, fec, variant, root, reply: recv_buffer[0..br].to_vec() })
+    }
+
+    ///Ask for the hashes of the chunks from the first through the last of window until all of them arrived
+    ///request packs a request for the hashes of ranges of chunks into a buffer, as many ranges as fit
+    fn request_hashes<F>(&self, server_socket: &UdpSocket, server: &str, filename: &str, window: (u64, u64), stats: &mut TransferStats, request: F) -> io::Result<Vec<[u8; HASH_LEN]>>
+        where F: Fn(&[u64], &[u64], &mut [u8]) -> io::Result<usize>
+    {
+        let (first, last) = window;
+        let mut hashes: Vec<[u8; HASH_LEN]> = vec![[0; HASH_LEN]; (last-first+1) as usize];
+        let mut received: Vec<bool> = vec![false; hashes.len()];
+        let mut remaining = hashes.len();
+        let mut send_buffer: Vec<u8> = vec![0; self.packet_size];
+        let mut recv_buffer: Vec<u8> = vec![0; self.packet_size];
+        let retry_interval = match stats.rtt {
+            Some(rtt) => self.retry_interval.max(rtt*2),
+            None => self.retry_interval,
+        };
+
+        let mut counter: Instant = Instant::now();
+        let mut last_heard: Instant = Instant::now();
+        let mut next: bool = true;
+        while remaining > 0 {
+            if last_heard.elapsed() > self.timeout {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, format!("No response from {} for {:?}", server, self.timeout)));
+            }
+            if next || counter.elapsed() > retry_interval {
+                let (s, e) = missing_ranges(&received, first, first, last);
+                let bytes_to_send = request(&s, &e, &mut send_buffer)?;
+                if let Err(e) = server_socket.send_to(&send_buffer[0..bytes_to_send], server) {
+                    error!(server:% = server, error:% = e; "Unable to send data");
+                    return Err(e)
+                }
+                if !next {
+                    stats.retransmits += 1;
+                    debug!(server:% = server, filename:% = filename, ranges = s.len(); "Requesting missing hashes again");
+                }
+                counter = Instant::now();
+                next = false;
+            }
+
+            match server_socket.recv(&mut recv_buffer)
+            {
+                Ok(br) => {
+                    if br >= 16 && unpack_u8arr_into_u64(&recv_buffer[0..8]) == CONTROL_CHUNK && unpack_u8arr_into_u64(&recv_buffer[8..16]) == FILE_CHANGED {
+                        warn!(server:% = server, filename:% = filename; "File changed on the server during the transfer");
+                        return Err(file_changed(filename));
+                    }
+                    //Chunks of an earlier window still arriving are dropped
+                    let (start, packet_hashes) = match delta::unpack_hashes(&recv_buffer[0..br]) {
+                        Some(h) => h,
+                        None => continue,
+                    };
+                    for (i, hash) in packet_hashes.enumerate() {
+                        let chunk = start.saturating_add(i as u64);
+                        if chunk < first || chunk > last || received[(chunk-first) as usize] {
+                            continue;
+                        }
+                        let index = (chunk-first) as usize;
+                        hashes[index].copy_from_slice(hash);
+                        received[index] = true;
+                        remaining -= 1;
+                        counter = Instant::now();
+                        last_heard = Instant::now();
+                    }
+                },
+                Err(err) => match err.kind() {
+                    io::ErrorKind::WouldBlock => {},
+                    _ => return Err(err),
+                }
+            }
+        }
+        Ok(hashes)
+    }
+
+    ///Ask for the Merkle tree nodes with the IDs in ids until all of them arrived
+    ///request packs a request for ranges of node IDs into a buffer, as many ranges as fit
+    fn request_nodes<F>(&self, server_socket: &UdpSocket, server: &str, filename: &str, ids: &[u64], stats: &mut TransferStats, request: F) -> io::Result<HashMap<u64, [u8; HASH_LEN]>>
+        where F: Fn(&[u64], &[u64], &mut [u8]) -> io::Result<usize>
+    {
+        let mut nodes: HashMap<u64, [u8; HASH_LEN]> = HashMap::new();
+        let mut send_buffer: Vec<u8> = vec![0; self.packet_size];
+        let mut recv_buffer: Vec<u8> = vec![0; self.packet_size];
+        let retry_interval = match stats.rtt {
+            Some(rtt) => self.retry_interval.max(rtt*2),
+            None => self.retry_interval,
+        };
+
+        let mut counter: Instant = Instant::now();
+        let mut last_heard: Instant = Instant::now();
+        let mut next: bool = true;
+        while nodes.len() < ids.len() {
+            if last_heard.elapsed() > self.timeout {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, format!("No response from {} for {:?}", server, self.timeout)));
+            }
+            if next || counter.elapsed() > retry_interval {
+                //Every node is a range of its own, IDs of neighbouring nodes are rarely both needed
+                let missing: Vec<u64> = ids.iter().cloned().filter(|id| !nodes.contains_key(id)).collect();
+                let bytes_to_send = request(&missing, &missing, &mut send_buffer)?;
+                if let Err(e) = server_socket.send_to(&send_buffer[0..bytes_to_send], server) {
+                    error!(server:% = server, error:% = e; "Unable to send data");
+                    return Err(e)
+                }
+                if !next {
+                    stats.retransmits += 1;
+                    debug!(server:% = server, filename:% = filename, nodes = missing.len(); "Requesting missing Merkle tree nodes again");
+                }
+                counter = Instant::now();
+                next = false;
+            }
+
+            match server_socket.recv(&mut recv_buffer)
+            {
+                Ok(br) => {
+                    if br >= 16 && unpack_u8arr_into_u64(&recv_buffer[0..8]) == CONTROL_CHUNK && unpack_u8arr_into_u64(&recv_buffer[8..16]) == FILE_CHANGED {
+                        warn!(server:% = server, filename:% = filename; "File changed on the server during the transfer");
+                        return Err(file_changed(filename));
+                    }
+                    //Chunks and hashes still arriving are dropped
+                    let packet_nodes = match merkle::unpack_nodes(&recv_buffer[0..br]) {
+                        Some(n) => n,
+                        None => continue,
+                    };
+                    for (id, hash) in packet_nodes {
+                        if !ids.contains(&id) || nodes.contains_key(&id) {
+                            continue;
+                        }
+                        let mut node = [0; HASH_LEN];
+                        node.copy_from_slice(hash);
+                        nodes.insert(id, node);
+                        counter = Instant::now();
+                        last_heard = Instant::now();
+                    }
+                },
+                Err(err) => match err.kind() {
+                    io::ErrorKind::WouldBlock => {},
+                    _ => return Err(err),
+                }
+            }
+        }
+        Ok(nodes)
+    }
+
+    ///Send request until a response accepted by is_response arrives, re-sending every retry_interval
+    ///Returns the size of the response in recv_buffer, gives up after timeout
+    ///The round trip time of the attempt that got answered is recorded in stats
+    fn exchange<F: Fn(&[u8]) -> bool>(&self, server_socket: &UdpSocket, request: &[u8], recv_buffer: &mut [u8], server: &str, stats: &mut TransferStats, is_response: F) -> io::Result<usize> {
+        match server_socket.send_to(request, server)
+        {
+            Ok(_) => {},
+            Err(e) => {
+                error!(server:% = server, error:% = e; "Unable to send data");
+                return Err(e)
+            }
+        }
+
+        let started: Instant = Instant::now();
+        let mut counter: Instant = Instant::now();
+        loop
+        {
+            match server_socket.recv(recv_buffer)
+            {
+                Ok(br) => {
+                    if is_response(&recv_buffer[0..br]) {
+                        stats.rtt = Some(counter.elapsed());
+                        return Ok(br)
+                    }
+                },
+                Err(e) => match e.kind() {
+                    io::ErrorKind::WouldBlock => { },
+                    _ => return Err(e),
+                }
+            }
+            if started.elapsed() > self.timeout {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, format!("No response from {} for {:?}", server, self.timeout)));
+            }
+            if counter.elapsed() > self.retry_interval {
+                match server_socket.send_to(request, server)
+                {
+                    Ok(_) => stats.retransmits += 1,
+                    Err(e) => {
+                        error!(server:% = server, error:% = e; "Unable to send data");
+                        return Err(e)
+                    }
+                }
+                counter = Instant::now();
+            }
+        }
+    }
+}
+
+///Ranges of chunks from first through last that haven't been received, received[0] is part_start
+fn missing_ranges(received: &[bool], part_start: u64, first: u64, last: u64) -> (Vec<u64>, Vec<u64>) {
+    let mut starts: Vec<u64> = Vec::new();
+    let mut ends: Vec<u64> = Vec::new();
+    for chunk in first..=last {
+        if received[(chunk-part_start) as usize] {
+            continue;
+        }
+        match ends.last_mut() {
+            Some(end) if *end+1 == chunk => *end = chunk,
+            _ => {
+                starts.push(chunk);
+                ends.push(chunk);
+            },
+        }
+    }
+    (starts, ends)
+}
+
+///Whether chunk index of the window matches its hash in leaves, anything does when leaves is empty because the transfer isn't verified
+fn verified(leaves: &[[u8; HASH_LEN]], index: usize, data: &[u8]) -> bool {
+    leaves.is_empty() || leaves.get(index) == Some(&delta::chunk_hash(data))
+}
+
+///Read until buf is full or the reader runs dry, returns how many bytes were read
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let bytes_read = reader.read(&mut buf[filled..])?;
+        if bytes_read == 0 {
+            break;
+        }
+        filled += bytes_read;
+    }
+    Ok(filled)
+}
+
+///FEC params, variant description and Merkle root of a response
+type Extras = (Option<FecParams>, Option<VariantInfo>, Option<[u8; HASH_LEN]>);
+
+///FEC params, variant description and Merkle root that follow the features of a metadata or session open response, in that order
+///Fails if the response says it describes a variant but doesn't say what it decodes to, or has a root but doesn't send it
+fn unpack_extras(features: u64, data: &[u8]) -> io::Result<Extras> {
+    let (fec, data) = match features & FEATURE_FEC {
+        0 => (None, data),
+        _ => (fec::unpack_params(data), data.get(24..).unwrap_or(&[])),
+    };
+    let (variant, data) = match features & VARIANT_ZSTD {
+        0 => (None, data),
+        _ => match variant::unpack_info(data) {
+            Some(info) => (Some(info), data.get(variant::INFO_LEN..).unwrap_or(&[])),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "Response describes a pre-compressed variant without saying what it decodes to")),
+        },
+    };
+    if features & FEATURE_MERKLE == 0 {
+        return Ok((fec, variant, None));
+    }
+    match data.get(0..merkle::ROOT_LEN) {
+        Some(root) => {
+            let mut hash = [0; HASH_LEN];
+            hash.copy_from_slice(root);
+            Ok((fec, variant, Some(hash)))
+        },
+        None => Err(io::Error::new(io::ErrorKind::InvalidData, "Response has a Merkle tree without sending its root")),
+    }
+}
+
+///Bind a nonblocking socket to the port of group and join the group on interface
+///Only one socket on a host can have the port, so one receiver per host and group
+fn multicast_socket(group: &str, interface: IpAddr) -> io::Result<UdpSocket> {
+    let group = match group.to_socket_addrs()?.next() {
+        Some(g) if g.ip().is_multicast() => g,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a multicast group", group))),
+    };
+    let socket = match (group.ip(), interface) {
+        (IpAddr::V4(ip), IpAddr::V4(interface)) => {
+            let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, group.port()))?;
+            socket.join_multicast_v4(&ip, &interface)?;
+            socket
+        },
+        (IpAddr::V6(ip), _) => {
+            let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, group.port()))?;
+            socket.join_multicast_v6(&ip, 0)?;
+            socket
+        },
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "An IPv4 group has to be joined on an IPv4 interface")),
+    };
+    match socket.set_nonblocking(true)
+    {
+        Ok(_) => {},
+        Err(e) => {
+            error!(error:% = e; "Unable to set nonblocking");
+            return Err(e)
+        }
+    }
+    Ok(socket)
+}
+
+///Bind a nonblocking socket locally to any available port, this is an outbound request
+fn client_socket() -> io::Result<UdpSocket> {
+    let server_socket: UdpSocket = match UdpSocket::bind("0.0.0.0:0")
+    {
+        Ok(s) => s,
+        Err(e) => {
+            error!(error:% = e; "Unable to bind a UDP socket");
+            return Err(e);
+        }
+    };
+    //Set to nonblocking
+    match server_socket.set_nonblocking(true)
+    {
+        Ok(_) => {},
+        Err(e) => {
+            error!(error:% = e; "Unable to set nonblocking");
+            return Err(e)
+        }
+    }
+    Ok(server_socket)
+}
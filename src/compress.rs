@@ -0,0 +1,196 @@
+use std::io;
+use crate::config::{CompressionAlgorithm, CompressionConfig};
+use crate::{pack_u64_into_u8arr, unpack_u8arr_into_u64, COMPRESSED_CHUNK, FEATURE_LZ4, FEATURE_ZSTD, REPAIR_CHUNK};
+
+///Feature bit advertising the algorithm chunks are compressed with, 0 if compression is off
+pub(crate) fn feature(config: &CompressionConfig) -> u64 {
+    match config.algorithm {
+        CompressionAlgorithm::None => 0,
+        CompressionAlgorithm::Zstd => FEATURE_ZSTD,
+        CompressionAlgorithm::Lz4 => FEATURE_LZ4,
+    }
+}
+
+///Compresses chunks one at a time, every chunk can be decompressed on its own
+pub(crate) struct Compressor {
+    algorithm: CompressionAlgorithm,
+    zstd: Option<zstd::bulk::Compressor<'static>>,
+    scratch: Vec<u8>,
+}
+
+impl Compressor {
+    ///None if compression is off
+    pub(crate) fn new(config: &CompressionConfig) -> io::Result<Option<Self>> {
+        let zstd = match config.algorithm {
+            CompressionAlgorithm::None => return Ok(None),
+            CompressionAlgorithm::Zstd => Some(zstd::bulk::Compressor::new(config.level)?),
+            CompressionAlgorithm::Lz4 => None,
+        };
+        Ok(Some(Self {
+            algorithm: config.algorithm,
+            zstd,
+            scratch: Vec::new(),
+        }))
+    }
+
+    ///Compresses data into out, returns how many bytes it took
+    ///None if it doesn't fit in out, so an out shorter than data only keeps chunks that got smaller
+    pub(crate) fn compress(&mut self, data: &[u8], out: &mut [u8]) -> Option<usize> {
+        match (self.algorithm, self.zstd.as_mut()) {
+            (CompressionAlgorithm::Zstd, Some(zstd)) => zstd.compress_to_buffer(data, out).ok(),
+            (CompressionAlgorithm::Lz4, _) => {
+                //lz4 wants room for the worst case, so compress aside and keep it if it fits
+                self.scratch.resize(lz4_flex::block::get_maximum_output_size(data.len()), 0);
+                let bytes = lz4_flex::block::compress_into(data, &mut self.scratch).ok()?;
+                let out = out.get_mut(0..bytes)?;
+                out.copy_from_slice(&self.scratch[0..bytes]);
+                Some(bytes)
+            },
+            _ => None,
+        }
+    }
+}
+
+///Decompresses chunks compressed with whichever algorithm the server advertised
+pub(crate) struct Decompressor {
+    algorithm: CompressionAlgorithm,
+    zstd: Option<zstd::bulk::Decompressor<'static>>,
+}
+
+impl Decompressor {
+    ///None if the server doesn't compress chunks
+    pub(crate) fn new(features: u64) -> io::Result<Option<Self>> {
+        let (algorithm, zstd) = if features & FEATURE_ZSTD != 0 {
+            (CompressionAlgorithm::Zstd, Some(zstd::bulk::Decompressor::new()?))
+        } else if features & FEATURE_LZ4 != 0 {
+            (CompressionAlgorithm::Lz4, None)
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(Self {
+            algorithm,
+            zstd,
+        }))
+    }
+
+    ///Decompresses data into out, None if it isn't valid or doesn't fit
+    fn decompress(&mut self, data: &[u8], out: &mut [u8]) -> Option<usize> {
+        match (self.algorithm, self.zstd.as_mut()) {
+            (CompressionAlgorithm::Zstd, Some(zstd)) => zstd.decompress_to_buffer(data, out).ok(),
+            (CompressionAlgorithm::Lz4, _) => lz4_flex::block::decompress_into(data, out).ok(),
+            _ => None,
+        }
+    }
+}
+
+///Puts the header on a packet holding bytes of chunk data after it, the data is compressed in place if that makes it smaller
+///Returns how many bytes are in the packet and whether it was compressed, scratch has to hold a whole chunk
+pub(crate) fn pack_chunk(compressor: Option<&mut Compressor>, chunk: u64, packet: &mut [u8], bytes: usize, scratch: &mut [u8]) -> (usize, bool) {
+    let packed = match compressor {
+        Some(c) if bytes > 1 => c.compress(&packet[8..8+bytes], &mut scratch[0..bytes-1]),
+        _ => None,
+    };
+    match packed {
+        Some(packed_bytes) => {
+            packet[0..8].copy_from_slice(&pack_u64_into_u8arr(chunk | COMPRESSED_CHUNK));
+            packet[8..8+packed_bytes].copy_from_slice(&scratch[0..packed_bytes]);
+            (8+packed_bytes, true)
+        },
+        None => {
+            packet[0..8].copy_from_slice(&pack_u64_into_u8arr(chunk));
+            (8+bytes, false)
+        }
+    }
+}
+
+///Chunk index and file data of a packet, data the server compressed is decompressed into scratch
+///Control and repair packets come back as they are, scratch has to hold a whole chunk
+///None if the packet is too short or was compressed and can't be decompressed
+pub(crate) fn unpack_chunk<'a>(packet: &'a [u8], decompressor: Option<&mut Decompressor>, scratch: &'a mut [u8]) -> Option<(u64, &'a [u8])> {
+    let chunkdex = unpack_u8arr_into_u64(packet.get(0..8)?);
+    if chunkdex & (REPAIR_CHUNK | COMPRESSED_CHUNK) != COMPRESSED_CHUNK {
+        return Some((chunkdex, &packet[8..]));
+    }
+    let bytes = decompressor?.decompress(&packet[8..], scratch)?;
+    Some((chunkdex & !COMPRESSED_CHUNK, &scratch[0..bytes]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHUNK_SIZE: usize = 504;
+
+    fn compressor(algorithm: CompressionAlgorithm) -> Compressor {
+        Compressor::new(&CompressionConfig { algorithm, ..CompressionConfig::default() }).unwrap().unwrap()
+    }
+
+    ///A packet holding data after room for its header, as the server reads it
+    fn read_packet(data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0; 8+CHUNK_SIZE];
+        packet[8..8+data.len()].copy_from_slice(data);
+        packet
+    }
+
+    ///Bytes no compressor can make smaller
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state: u64 = 0x9e3779b97f4a7c15;
+        (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        }).collect()
+    }
+
+    #[test]
+    fn chunks_round_trip() {
+        let text: Vec<u8> = b"basic_udp chunks compress well when they repeat themselves ".iter().cycle().take(CHUNK_SIZE).cloned().collect();
+        for (algorithm, feature) in [(CompressionAlgorithm::Zstd, FEATURE_ZSTD), (CompressionAlgorithm::Lz4, FEATURE_LZ4)].iter() {
+            let mut compressor = compressor(*algorithm);
+            let mut decompressor = Decompressor::new(*feature).unwrap().unwrap();
+            let mut scratch = vec![0; CHUNK_SIZE];
+            //A short last chunk too
+            for data in [&text[..], &text[0..100]].iter() {
+                let mut packet = read_packet(data);
+                let (bytes, packed) = pack_chunk(Some(&mut compressor), 7, &mut packet, data.len(), &mut scratch);
+                assert!(packed);
+                assert!(bytes < 8+data.len());
+                assert_eq!(unpack_u8arr_into_u64(&packet[0..8]), 7 | COMPRESSED_CHUNK);
+                let mut out = vec![0; CHUNK_SIZE];
+                assert_eq!(unpack_chunk(&packet[0..bytes], Some(&mut decompressor), &mut out), Some((7, &data[..])));
+                //Without a decompressor the chunk is unusable
+                assert_eq!(unpack_chunk(&packet[0..bytes], None, &mut out), None);
+            }
+        }
+    }
+
+    #[test]
+    fn incompressible_chunks_are_sent_raw() {
+        let data = noise(CHUNK_SIZE);
+        for algorithm in [CompressionAlgorithm::Zstd, CompressionAlgorithm::Lz4].iter() {
+            let mut compressor = compressor(*algorithm);
+            let mut packet = read_packet(&data);
+            let (bytes, packed) = pack_chunk(Some(&mut compressor), 7, &mut packet, data.len(), &mut vec![0; CHUNK_SIZE]);
+            assert!(!packed);
+            assert_eq!(bytes, 8+data.len());
+            assert_eq!(unpack_u8arr_into_u64(&packet[0..8]), 7);
+            let mut out = vec![0; CHUNK_SIZE];
+            assert_eq!(unpack_chunk(&packet[0..bytes], None, &mut out), Some((7, &data[..])));
+        }
+        //Nothing is compressed with compression off
+        let mut packet = read_packet(&[0; CHUNK_SIZE]);
+        assert_eq!(pack_chunk(None, 7, &mut packet, CHUNK_SIZE, &mut vec![0; CHUNK_SIZE]), (8+CHUNK_SIZE, false));
+        assert!(Compressor::new(&CompressionConfig::default()).unwrap().is_none());
+        assert!(Decompressor::new(0).unwrap().is_none());
+    }
+
+    #[test]
+    fn repair_packets_are_left_alone() {
+        let mut packet = read_packet(b"repair");
+        packet[0..8].copy_from_slice(&pack_u64_into_u8arr(3 | REPAIR_CHUNK | COMPRESSED_CHUNK));
+        let mut out = vec![0; CHUNK_SIZE];
+        assert_eq!(unpack_chunk(&packet[0..14], None, &mut out), Some((3 | REPAIR_CHUNK | COMPRESSED_CHUNK, &b"repair"[..])));
+        assert_eq!(unpack_chunk(&packet[0..7], None, &mut out), None);
+    }
+}
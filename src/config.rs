@@ -0,0 +1,620 @@
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+
+///Smallest packet the protocol can work with, a chunk index plus a reasonable amount of payload
+pub const MIN_PACKET_SIZE: usize = 64;
+///Largest payload a UDP datagram can carry over IPv4
+pub const MAX_PACKET_SIZE: usize = 65507;
+
+///Typed server configuration, deserialized from a TOML file
+///
+///Unknown keys are an error rather than silently falling back to defaults,
+///every key is optional and missing keys take the defaults below
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    ///Addresses to listen on, every worker serves all of them
+    pub bind: Vec<String>,
+    ///Directory relative whitelist patterns and requested filenames are resolved against
+    pub root: PathBuf,
+    ///Path of the whitelist file
+    pub whitelist: PathBuf,
+    ///Size of every packet sent, chunks carry packet_size - 8 bytes of file data
+    pub packet_size: usize,
+    ///Number of threads servicing requests
+    pub workers: usize,
+    pub limits: LimitsConfig,
+    pub security: SecurityConfig,
+    pub upload: UploadConfig,
+    pub sessions: SessionConfig,
+    pub fec: FecConfig,
+    pub compression: CompressionConfig,
+    pub merkle: MerkleConfig,
+    pub cache: CacheConfig,
+    ///Files sent round and round to multicast groups, written as [[multicast]] tables
+    pub multicast: Vec<MulticastConfig>,
+    pub logging: LoggingConfig,
+    pub metrics: MetricsConfig,
+}
+
+///Rate and size limits, 0 disables a limit
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    ///Most chunk packets sent back for a single request
+    pub packets_per_request: u64,
+    ///Outbound bandwidth cap for the whole server, shared evenly between workers
+    pub bytes_per_second: u64,
+    ///Requests accepted per second from a single client address
+    pub requests_per_second: u64,
+    ///Chunks asked for again within this many milliseconds of being sent are still in flight and not sent again, unless reported lost
+    pub duplicate_window_ms: u64,
+}
+
+///Settings restricting who can request what
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityConfig {
+    ///Client networks allowed to talk to the server, e.g. "10.0.0.0/8" or "::1", empty allows everyone
+    pub allowed_clients: Vec<IpNetwork>,
+    ///Refuse any path with a component starting with '.', even if the whitelist matches it
+    pub deny_hidden: bool,
+    ///Longest filename accepted in a request
+    pub max_filename_len: usize,
+}
+
+///Settings for clients pushing files to the server
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UploadConfig {
+    ///Directory uploaded files are written to, uploads are disabled unless this is set
+    pub directory: Option<PathBuf>,
+    ///Shared secret clients sign their upload announcements with
+    pub key: String,
+    ///Largest file accepted in bytes, has to be set when uploads are enabled
+    pub max_size: u64,
+    ///Allow uploads to replace files that already exist
+    pub overwrite: bool,
+}
+
+//The key is a secret, printing a config must not give it away
+impl std::fmt::Debug for UploadConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UploadConfig")
+            .field("directory", &self.directory)
+            .field("key", &"<redacted>")
+            .field("max_size", &self.max_size)
+            .field("overwrite", &self.overwrite)
+            .finish()
+    }
+}
+
+///Optional stateful transfers, a client opens a file once and then asks for chunks by handle
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    ///Accept session requests, the stateless requests keep working either way
+    pub enabled: bool,
+    ///Sessions without any request for this long are closed
+    pub idle_timeout_seconds: u64,
+    ///Most sessions a single client address can have open
+    pub max_per_client: usize,
+    ///Most files a single session can have open
+    pub max_files: usize,
+    ///Outbound bandwidth cap of a single session, 0 for no limit besides limits.bytes_per_second
+    pub bytes_per_second: u64,
+}
+
+///Forward error correction, repair packets sent along with chunks so clients can rebuild lost ones without asking again
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FecConfig {
+    ///Chunks protected together by one set of repair packets
+    pub block_chunks: u64,
+    ///Repair packets sent after every block, as many lost chunks of a block can be rebuilt, 0 disables FEC
+    pub repair_chunks: u64,
+}
+
+///Compression of chunk data for clients that ask for it, pays off for text like logs and JSON
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig {
+    ///Algorithm chunks are compressed with, every chunk on its own
+    pub algorithm: CompressionAlgorithm,
+    ///zstd level, higher compresses better and slower, lz4 has no levels
+    pub level: i32,
+    ///Send foo.zst in place of foo to clients that can decode it, whatever algorithm is set
+    pub variants: bool,
+}
+
+///Merkle trees over the chunks of files, clients check every chunk against the root sent in the metadata
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MerkleConfig {
+    ///Send roots and answer requests for tree nodes, a file is read whole the first time its tree is needed
+    pub enabled: bool,
+}
+
+///What the server learns by reading whole files, chunk hash trees and what pre-compressed variants decode to
+///Kept per version of a file, by canonical path, device, inode, modification time and size
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    ///Bytes kept in memory, a tree takes about 32 bytes per chunk, the least recently used go first
+    pub memory_bytes: u64,
+    ///Directory to also keep them in, one file per path, so a restarted server doesn't read every file again
+    pub directory: Option<PathBuf>,
+}
+
+///How chunk data is compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionAlgorithm {
+    #[default]
+    None,
+    Zstd,
+    Lz4,
+}
+
+///One file sent to a multicast group over and over, receivers fill their gaps from the next round or with unicast requests
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MulticastConfig {
+    ///Name of the file as clients request it, it has to be on the whitelist
+    pub file: String,
+    ///Multicast group and port the file is sent to, e.g. "239.255.0.1:9200"
+    pub group: String,
+    ///Local address of the interface to send from, the routing table picks one if it is unspecified
+    ///or the group is IPv6, where it only has to be of the same address family
+    pub interface: IpAddr,
+    ///Rate the file is sent at, multicast has no flow control so this can't be unlimited
+    pub bytes_per_second: u64,
+    ///Routers the packets may cross, 1 keeps them on the local network, the hop limit of IPv6 groups
+    pub ttl: u32,
+    ///Pause between two rounds
+    pub pause_seconds: u64,
+}
+
+///How much the server logs and in what shape
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    ///Most verbose level logged, -v and -q on the command line take precedence
+    pub level: LogLevel,
+    ///text for people, json for one object per line
+    pub format: LogFormat,
+    ///File access log entries are appended to, otherwise they go to the regular log at info level
+    pub access_log: Option<PathBuf>,
+    ///A client's requests for one file less than this many seconds apart are logged as one transfer, 0 logs every request
+    pub access_coalesce_seconds: u64,
+}
+
+///Optional HTTP endpoint exposing server metrics
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    ///Address to serve Prometheus metrics on, e.g. "127.0.0.1:9100", needs the metrics cargo feature
+    pub listen: Option<String>,
+}
+
+///Log levels as written in the config, from quiet to chatty
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+///Shape of every log line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+///An address and prefix length, parsed from "addr" or "addr/prefix"
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: vec![String::from("127.0.0.1:9001")],
+            root: PathBuf::from("."),
+            whitelist: PathBuf::from("whitelist"),
+            packet_size: crate::PACKET_SIZE,
+            workers: 1,
+            limits: LimitsConfig::default(),
+            security: SecurityConfig::default(),
+            upload: UploadConfig::default(),
+            sessions: SessionConfig::default(),
+            fec: FecConfig::default(),
+            compression: CompressionConfig::default(),
+            merkle: MerkleConfig::default(),
+            cache: CacheConfig::default(),
+            multicast: Vec::new(),
+            logging: LoggingConfig::default(),
+            metrics: MetricsConfig::default(),
+        }
+    }
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            idle_timeout_seconds: 60,
+            max_per_client: 8,
+            max_files: 16,
+            bytes_per_second: 0,
+        }
+    }
+}
+
+impl Default for FecConfig {
+    fn default() -> Self {
+        Self {
+            block_chunks: 32,
+            repair_chunks: 0,
+        }
+    }
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            algorithm: CompressionAlgorithm::None,
+            level: 3,
+            variants: false,
+        }
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            memory_bytes: 64 << 20,
+            directory: None,
+        }
+    }
+}
+
+impl Default for MulticastConfig {
+    fn default() -> Self {
+        Self {
+            file: String::new(),
+            group: String::new(),
+            interface: IpAddr::from([0, 0, 0, 0]),
+            bytes_per_second: 1_000_000,
+            ttl: 1,
+            pause_seconds: 1,
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: LogLevel::default(),
+            format: LogFormat::default(),
+            access_log: None,
+            access_coalesce_seconds: 5,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            packets_per_request: 0,
+            bytes_per_second: 0,
+            requests_per_second: 0,
+            //Shorter than the default client retry interval so a lost chunk is sent again when asked
+            duplicate_window_ms: 100,
+        }
+    }
+}
+
+impl Default for SecurityConfig {
+    fn default() -> Self {
+        Self {
+            allowed_clients: Vec::new(),
+            deny_hidden: false,
+            max_filename_len: 1024,
+        }
+    }
+}
+
+impl ServerConfig {
+    ///Read, parse and validate a config file
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        Self::parse(&text)
+    }
+
+    ///Parse and validate config text
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let config: ServerConfig = match toml::from_str(text) {
+            Ok(c) => c,
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    ///Check the values make sense together and the files they point to exist
+    pub fn validate(&self) -> io::Result<()> {
+        if self.bind.is_empty() {
+            return Err(invalid("bind must list at least one address"));
+        }
+        for address in self.bind.iter() {
+            if address.to_socket_addrs().is_err() {
+                return Err(invalid(&format!("bind address {:?} is not a valid address:port", address)));
+            }
+        }
+        if self.packet_size < MIN_PACKET_SIZE || self.packet_size > MAX_PACKET_SIZE {
+            return Err(invalid(&format!(
+                "packet_size {} is outside of {}..={}",
+                self.packet_size, MIN_PACKET_SIZE, MAX_PACKET_SIZE
+            )));
+        }
+        if self.workers == 0 {
+            return Err(invalid("workers must be at least 1"));
+        }
+        if !self.root.is_dir() {
+            return Err(invalid(&format!("root {:?} is not a directory", self.root)));
+        }
+        if let Err(e) = fs::File::open(&self.whitelist) {
+            return Err(invalid(&format!("whitelist {:?} can't be opened: {}", self.whitelist, e)));
+        }
+        if self.security.max_filename_len == 0 {
+            return Err(invalid("security.max_filename_len must be at least 1"));
+        }
+        if let Some(directory) = &self.upload.directory {
+            if !directory.is_dir() {
+                return Err(invalid(&format!("upload.directory {:?} is not a directory", directory)));
+            }
+            if self.upload.key.len() < 16 {
+                return Err(invalid("upload.key must be at least 16 characters when uploads are enabled"));
+            }
+            if self.upload.max_size == 0 {
+                return Err(invalid("upload.max_size must be at least 1 when uploads are enabled"));
+            }
+        }
+        if self.sessions.enabled {
+            if self.sessions.idle_timeout_seconds == 0 {
+                return Err(invalid("sessions.idle_timeout_seconds must be at least 1"));
+            }
+            if self.sessions.max_per_client == 0 || self.sessions.max_files == 0 {
+                return Err(invalid("sessions.max_per_client and sessions.max_files must be at least 1"));
+            }
+        }
+        if self.fec.repair_chunks != 0 {
+            //Reed-Solomon over GF(256) has room for 256 chunks and repair packets per block
+            if self.fec.block_chunks == 0 || self.fec.block_chunks+self.fec.repair_chunks > 256 {
+                return Err(invalid("fec.block_chunks must be at least 1 and fec.block_chunks + fec.repair_chunks at most 256"));
+            }
+        }
+        if self.compression.algorithm == CompressionAlgorithm::Zstd && !zstd::compression_level_range().contains(&self.compression.level) {
+            return Err(invalid(&format!("compression.level must be within {:?} for zstd", zstd::compression_level_range())));
+        }
+        if self.compression.variants && self.packet_size < crate::variant::MIN_PACKET_SIZE {
+            return Err(invalid(&format!("compression.variants needs a packet_size of at least {}", crate::variant::MIN_PACKET_SIZE)));
+        }
+        if self.merkle.enabled && self.packet_size < crate::merkle::MIN_PACKET_SIZE {
+            return Err(invalid(&format!("merkle.enabled needs a packet_size of at least {}", crate::merkle::MIN_PACKET_SIZE)));
+        }
+        if let Some(directory) = &self.cache.directory {
+            if !directory.is_dir() {
+                return Err(invalid(&format!("cache.directory {:?} is not a directory", directory)));
+            }
+        }
+        for multicast in self.multicast.iter() {
+            if multicast.file.is_empty() {
+                return Err(invalid("multicast.file must name a file"));
+            }
+            match multicast.group_addr() {
+                Some(g) if g.ip().is_multicast() && g.is_ipv4() == multicast.interface.is_ipv4() => {},
+                _ => return Err(invalid(&format!("multicast.group {:?} is not a multicast address:port of the interface's address family", multicast.group))),
+            }
+            if multicast.bytes_per_second == 0 {
+                return Err(invalid("multicast.bytes_per_second must be at least 1"));
+            }
+        }
+        if let Some(access_log) = &self.logging.access_log {
+            let parent = access_log.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+            if !parent.is_dir() {
+                return Err(invalid(&format!("logging.access_log {:?} is not in an existing directory", access_log)));
+            }
+        }
+        if let Some(listen) = &self.metrics.listen {
+            if !cfg!(feature = "metrics") {
+                return Err(invalid("metrics.listen is set but this build doesn't have the metrics feature"));
+            }
+            if listen.to_socket_addrs().is_err() {
+                return Err(invalid(&format!("metrics.listen {:?} is not a valid address:port", listen)));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SecurityConfig {
+    ///Whether a client address may be served
+    pub fn client_allowed(&self, ip: IpAddr) -> bool {
+        self.allowed_clients.is_empty() || self.allowed_clients.iter().any(|n| n.contains(ip))
+    }
+}
+
+impl MulticastConfig {
+    ///The group as an address, None if it isn't one
+    pub fn group_addr(&self) -> Option<SocketAddr> {
+        self.group.to_socket_addrs().ok().and_then(|mut a| a.next())
+    }
+}
+
+impl LogLevel {
+    ///The matching filter for the log facade
+    pub fn filter(self) -> log::LevelFilter {
+        match self {
+            LogLevel::Off => log::LevelFilter::Off,
+            LogLevel::Error => log::LevelFilter::Error,
+            LogLevel::Warn => log::LevelFilter::Warn,
+            LogLevel::Info => log::LevelFilter::Info,
+            LogLevel::Debug => log::LevelFilter::Debug,
+            LogLevel::Trace => log::LevelFilter::Trace,
+        }
+    }
+}
+
+impl IpNetwork {
+    ///Check if ip falls within this network
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix)
+            }
+            //Let IPv4 networks match IPv4 clients arriving on dual stack sockets
+            (IpAddr::V4(net), IpAddr::V6(ip)) => match ip.to_ipv4() {
+                Some(ip) => prefix_matches(&net.octets(), &ip.octets(), self.prefix),
+                None => false,
+            },
+            (IpAddr::V6(_), IpAddr::V4(_)) => false,
+        }
+    }
+}
+
+impl TryFrom<String> for IpNetwork {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (address, prefix) = match value.find('/') {
+            Some(i) => (&value[..i], Some(&value[i + 1..])),
+            None => (&value[..], None),
+        };
+        let addr: IpAddr = match address.parse() {
+            Ok(a) => a,
+            Err(_) => return Err(format!("{:?} is not an IP address", address)),
+        };
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => match p.parse::<u8>() {
+                Ok(p) if p <= max_prefix => p,
+                _ => return Err(format!("{:?} is not a valid prefix length for {}", p, address)),
+            },
+            None => max_prefix,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+fn prefix_matches(net: &[u8], ip: &[u8], prefix: u8) -> bool {
+    let full_bytes = (prefix / 8) as usize;
+    let rest_bits = prefix % 8;
+    if net[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+    if rest_bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - rest_bits);
+    net[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///A root with an empty whitelist and upload directory in it, and the config lines pointing at them
+    fn scratch(name: &str) -> (PathBuf, String) {
+        let directory = std::env::temp_dir().join(format!("basic_udp-config-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(directory.join("incoming")).unwrap();
+        fs::write(directory.join("whitelist"), b"").unwrap();
+        let lines = format!("root = {:?}\nwhitelist = {:?}\n", directory.display().to_string(), directory.join("whitelist").display().to_string());
+        (directory, lines)
+    }
+
+    #[test]
+    fn parse_checks_keys_and_values() {
+        let (directory, base) = scratch("parse");
+        let incoming = directory.join("incoming").display().to_string();
+        //Extra config text and part of the error it should fail with, None if it should parse
+        let cases: Vec<(String, Option<&str>)> = vec![
+            (String::new(), None),
+            (String::from("whitelst = \"whitelist\""), Some("unknown field `whitelst`")),
+            (String::from("[limits]\nrequests_per_secnd = 1"), Some("unknown field `requests_per_secnd`")),
+            (String::from("packet_size = 64"), None),
+            (String::from("packet_size = 63"), Some("packet_size 63 is outside")),
+            (String::from("packet_size = 65507"), None),
+            (String::from("packet_size = 65508"), Some("packet_size 65508 is outside")),
+            (format!("[upload]\ndirectory = {:?}\nkey = \"0123456789abcde\"\nmax_size = 1", incoming), Some("upload.key must be at least 16")),
+            (format!("[upload]\ndirectory = {:?}\nkey = \"0123456789abcdef\"", incoming), Some("upload.max_size must be at least 1")),
+            (format!("[upload]\ndirectory = {:?}\nkey = \"0123456789abcdef\"\nmax_size = 1", incoming), None),
+            (String::from("[upload]\nkey = \"short\""), None),
+            (String::from("[security]\nallowed_clients = [\"10.0.0.0/8\", \"192.168.1.7\", \"fe80::/10\", \"::1/128\"]"), None),
+            (String::from("[security]\nallowed_clients = [\"10.0.0.0/33\"]"), Some("not a valid prefix length")),
+            (String::from("[security]\nallowed_clients = [\"::/129\"]"), Some("not a valid prefix length")),
+            (String::from("[security]\nallowed_clients = [\"10.0.0.0/\"]"), Some("not a valid prefix length")),
+            (String::from("[security]\nallowed_clients = [\"example.com/8\"]"), Some("is not an IP address")),
+        ];
+        for (extra, expected) in cases.iter() {
+            let result = ServerConfig::parse(&format!("{}{}\n", base, extra));
+            match (result, expected) {
+                (Ok(_), None) => {},
+                (Err(e), Some(message)) => assert!(e.to_string().contains(message), "{:?} failed with {:?}", extra, e.to_string()),
+                (Ok(_), Some(message)) => panic!("{:?} parsed, expected {:?}", extra, message),
+                (Err(e), None) => panic!("{:?} failed with {:?}", extra, e.to_string()),
+            }
+        }
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn networks_contain_their_addresses() {
+        let network = |text: &str| IpNetwork::try_from(String::from(text)).unwrap();
+        let ip = |text: &str| text.parse::<IpAddr>().unwrap();
+        //Network, address and whether it is in it
+        let cases = [
+            ("10.0.0.0/8", "10.255.1.2", true),
+            ("10.0.0.0/8", "11.0.0.1", false),
+            ("10.0.0.0/8", "::ffff:10.1.2.3", true),
+            ("192.168.1.7", "192.168.1.7", true),
+            ("192.168.1.7", "192.168.1.8", false),
+            ("192.168.0.0/23", "192.168.1.255", true),
+            ("192.168.0.0/23", "192.168.2.0", false),
+            ("0.0.0.0/0", "203.0.113.9", true),
+            ("fe80::/10", "fe80::1", true),
+            ("fe80::/10", "febf::1", true),
+            ("fe80::/10", "fec0::1", false),
+            ("::1", "::1", true),
+            ("::1", "::2", false),
+            ("::/0", "10.0.0.1", false),
+        ];
+        for (net, address, inside) in cases.iter() {
+            assert_eq!(network(net).contains(ip(address)), *inside, "{} in {}", address, net);
+        }
+    }
+}
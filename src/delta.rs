@@ -0,0 +1,43 @@
+use sha2::{Digest, Sha256};
+use crate::{pack_u64_into_u8arr, unpack_u8arr_into_u64, CHUNK_HASHES, CONTROL_CHUNK};
+
+///Bytes of SHA-256 kept per chunk, plenty to tell versions of a chunk apart
+pub(crate) const HASH_LEN: usize = 16;
+///CONTROL_CHUNK, CHUNK_HASHES and the first chunk come before the hashes
+const HEADER_LEN: usize = 24;
+
+///Hash of a chunk as it is sent in a CHUNK_HASHES packet
+pub(crate) fn chunk_hash(data: &[u8]) -> [u8; HASH_LEN] {
+    let mut hash = [0; HASH_LEN];
+    hash.copy_from_slice(&Sha256::digest(data)[0..HASH_LEN]);
+    hash
+}
+
+///How many hashes fit in a packet of packet_size
+pub(crate) fn hashes_per_packet(packet_size: usize) -> usize {
+    (packet_size - HEADER_LEN)/HASH_LEN
+}
+
+///Populates a buffer with the hashes of the chunks from first on, already written after the header, returns how many bytes are in the packet
+///CONTROL_CHUNK, CHUNK_HASHES, u64 first chunk and then a hash per chunk
+pub(crate) fn hashes_packet(first: u64, count: usize, buffer: &mut [u8]) -> usize {
+    let mut byte_counter: usize = 0;
+    for value in [CONTROL_CHUNK, CHUNK_HASHES, first].iter() {
+        buffer[byte_counter..byte_counter+8].copy_from_slice(&pack_u64_into_u8arr(*value));
+        byte_counter += 8;
+    }
+    byte_counter + count*HASH_LEN
+}
+
+///Where hash i of a packet goes in its buffer
+pub(crate) fn hash_slot(i: usize, buffer: &mut [u8]) -> &mut [u8] {
+    &mut buffer[HEADER_LEN+i*HASH_LEN..HEADER_LEN+(i+1)*HASH_LEN]
+}
+
+///First chunk and hashes of a CHUNK_HASHES packet, None if it is something else
+pub(crate) fn unpack_hashes(packet: &[u8]) -> Option<(u64, impl Iterator<Item = &[u8]>)> {
+    if packet.len() < HEADER_LEN || unpack_u8arr_into_u64(&packet[0..8]) != CONTROL_CHUNK || unpack_u8arr_into_u64(&packet[8..16]) != CHUNK_HASHES {
+        return None;
+    }
+    Some((unpack_u8arr_into_u64(&packet[16..24]), packet[HEADER_LEN..].chunks_exact(HASH_LEN)))
+}
@@ -0,0 +1,151 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+///Remembers which chunks were just sent to each client, so a request repeated while they are still in flight doesn't send them again
+///
+///Chunks are kept for a window that should be shorter than a client's retry interval, a chunk that really got lost
+///is sent again once the client asks after the window
+pub(crate) struct DuplicateFilter {
+    window: Duration,
+    clients: Mutex<HashMap<SocketAddr, HashMap<String, VecDeque<Run>>>>,
+}
+
+///Consecutive chunks sent one after the other, sent is when the last one went out
+struct Run {
+    start: u64,
+    end: u64,
+    sent: Instant,
+}
+
+impl DuplicateFilter {
+    ///A window of 0 sends everything that is asked for
+    pub(crate) fn new(window: Duration) -> Self {
+        Self {
+            window,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    ///Whether chunk of filename went to peer within the window, so it shouldn't be sent again
+    ///Chunks the client reported lost are always sent
+    pub(crate) fn recent(&self, peer: SocketAddr, filename: &str, chunk: u64, lost: bool) -> bool {
+        if lost || self.window.is_zero() {
+            return false;
+        }
+        let now = Instant::now();
+        let clients = lock(&self.clients);
+        let runs = match clients.get(&peer).and_then(|files| files.get(filename)) {
+            Some(r) => r,
+            None => return false,
+        };
+        runs.iter().any(|r| now.duration_since(r.sent) < self.window && r.start <= chunk && chunk <= r.end)
+    }
+
+    ///Remember chunks of filename as sent to peer, only once they were read so chunks that never went out aren't held back
+    pub(crate) fn sent(&self, peer: SocketAddr, filename: &str, chunks: &[u64]) {
+        if self.window.is_zero() || chunks.is_empty() {
+            return;
+        }
+        let now = Instant::now();
+        let mut clients = lock(&self.clients);
+        let files = clients.entry(peer).or_default();
+        let runs = match files.get_mut(filename) {
+            Some(r) => r,
+            None => files.entry(String::from(filename)).or_default(),
+        };
+        while runs.front().map(|r| now.duration_since(r.sent) >= self.window).unwrap_or(false) {
+            runs.pop_front();
+        }
+        for chunk in chunks.iter().copied() {
+            match runs.back_mut() {
+                Some(r) if r.end.checked_add(1) == Some(chunk) => {
+                    r.end = chunk;
+                    r.sent = now;
+                },
+                _ => runs.push_back(Run { start: chunk, end: chunk, sent: now }),
+            }
+        }
+    }
+
+    ///Forget clients that haven't been sent anything within the window
+    pub(crate) fn sweep(&self) {
+        let window = self.window;
+        lock(&self.clients).retain(|_, files| {
+            files.retain(|_, runs| runs.back().map(|r| r.sent.elapsed() < window).unwrap_or(false));
+            !files.is_empty()
+        });
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(m) => m,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(address: &str) -> SocketAddr {
+        address.parse().unwrap()
+    }
+
+    ///Pretend everything sent to peer went out before the window
+    fn age(filter: &DuplicateFilter, peer: SocketAddr) {
+        let long_ago = Instant::now().checked_sub(filter.window*2).unwrap();
+        for runs in lock(&filter.clients).get_mut(&peer).unwrap().values_mut() {
+            for run in runs.iter_mut() {
+                run.sent = long_ago;
+            }
+        }
+    }
+
+    #[test]
+    fn chunks_are_held_back_for_the_window() {
+        let filter = DuplicateFilter::new(Duration::from_secs(10));
+        let client = peer("127.0.0.1:5000");
+        filter.sent(client, "file.bin", &[1, 2, 3, 7]);
+        assert!(filter.recent(client, "file.bin", 2, false));
+        assert!(filter.recent(client, "file.bin", 7, false));
+        assert!(!filter.recent(client, "file.bin", 4, false));
+        assert!(!filter.recent(client, "other.bin", 2, false));
+        assert!(!filter.recent(peer("127.0.0.1:5001"), "file.bin", 2, false));
+        //Chunks reported lost go out again right away
+        assert!(!filter.recent(client, "file.bin", 2, true));
+        //Consecutive chunks share a run
+        assert_eq!(lock(&filter.clients)[&client]["file.bin"].len(), 2);
+
+        age(&filter, client);
+        assert!(!filter.recent(client, "file.bin", 2, false));
+        filter.sent(client, "file.bin", &[8]);
+        assert!(filter.recent(client, "file.bin", 8, false));
+        //Runs past the window are dropped as new ones come in
+        assert_eq!(lock(&filter.clients)[&client]["file.bin"].len(), 1);
+    }
+
+    #[test]
+    fn no_window_holds_nothing_back() {
+        let filter = DuplicateFilter::new(Duration::from_secs(0));
+        let client = peer("127.0.0.1:5000");
+        filter.sent(client, "file.bin", &[1]);
+        assert!(!filter.recent(client, "file.bin", 1, false));
+        assert!(lock(&filter.clients).is_empty());
+    }
+
+    #[test]
+    fn sweep_forgets_quiet_clients() {
+        let filter = DuplicateFilter::new(Duration::from_secs(10));
+        let (quiet, busy) = (peer("127.0.0.1:5000"), peer("127.0.0.1:5001"));
+        filter.sent(quiet, "file.bin", &[1]);
+        filter.sent(busy, "file.bin", &[1]);
+        age(&filter, quiet);
+        filter.sweep();
+        let clients = lock(&filter.clients);
+        assert!(!clients.contains_key(&quiet));
+        assert!(clients.contains_key(&busy));
+    }
+}
@@ -0,0 +1,351 @@
+use std::collections::HashMap;
+use std::io;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use crate::config::FecConfig;
+use crate::{pack_u64_into_u8arr, unpack_u8arr_into_u64, REPAIR_CHUNK};
+
+///How a server protects a file with repair packets, sent after the features of metadata and session open responses
+///
+///block_chunks: u64, Chunks protected together, blocks start at multiples of it
+///repair_chunks: u64, Repair packets sent after every block
+///file_size: u64, Size of the file, rebuilding the last chunk needs its length
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FecParams {
+    pub(crate) block_chunks: u64,
+    pub(crate) repair_chunks: u64,
+    pub(crate) file_size: u64,
+}
+
+///Appends u64 block chunks, u64 repair chunks and u64 file size to a response, returns how many bytes were added
+///Nothing is added if FEC is disabled or they don't fit
+pub(crate) fn pack_params(config: &FecConfig, file_size: u64, buffer: &mut [u8]) -> usize {
+    if config.repair_chunks == 0 || buffer.len() < 24 {
+        return 0;
+    }
+    let mut byte_counter: usize = 0;
+    for value in [config.block_chunks, config.repair_chunks, file_size].iter() {
+        buffer[byte_counter..byte_counter+8].copy_from_slice(&pack_u64_into_u8arr(*value));
+        byte_counter += 8;
+    }
+    byte_counter
+}
+
+///Reads what pack_params added, None if there is nothing usable
+pub(crate) fn unpack_params(data: &[u8]) -> Option<FecParams> {
+    if data.len() < 24 {
+        return None;
+    }
+    let params = FecParams {
+        block_chunks: unpack_u8arr_into_u64(&data[0..8]),
+        repair_chunks: unpack_u8arr_into_u64(&data[8..16]),
+        file_size: unpack_u8arr_into_u64(&data[16..24]),
+    };
+    if params.block_chunks == 0 || params.repair_chunks == 0 || params.block_chunks.saturating_add(params.repair_chunks) > 256 {
+        return None;
+    }
+    Some(params)
+}
+
+///Populates a buffer with repair packet r of block, returns how many bytes are in the packet
+///Its chunk index is REPAIR_CHUNK with block*repair_chunks + r
+pub(crate) fn repair_packet(block: u64, r: u64, repair_chunks: u64, repair: &[u8], buffer: &mut [u8]) -> usize {
+    buffer[0..8].copy_from_slice(&pack_u64_into_u8arr(REPAIR_CHUNK | (block*repair_chunks + r)));
+    buffer[8..8+repair.len()].copy_from_slice(repair);
+    8+repair.len()
+}
+
+///Chunks in block, the last block of a file can be short
+fn data_chunks(block: u64, block_chunks: u64, chunk_count: u64) -> u64 {
+    block_chunks.min(chunk_count - block*block_chunks)
+}
+
+///Codec for a block with data chunks, full blocks reuse the one in cache
+fn codec(cache: &mut Option<ReedSolomon>, data: u64, block_chunks: u64, repair_chunks: u64) -> io::Result<ReedSolomon> {
+    if data == block_chunks {
+        if let Some(c) = cache {
+            return Ok(c.clone());
+        }
+    }
+    let c = match ReedSolomon::new(data as usize, repair_chunks as usize) {
+        Ok(c) => c,
+        Err(e) => return Err(io::Error::other(e)),
+    };
+    if data == block_chunks {
+        *cache = Some(c.clone());
+    }
+    Ok(c)
+}
+
+///Collects chunks as a server sends them and builds the repair packets of every block sent whole and in order
+pub(crate) struct BlockEncoder<'a> {
+    config: &'a FecConfig,
+    chunk_size: usize,
+    chunk_count: u64,
+    codec: Option<ReedSolomon>,
+    shards: Vec<Vec<u8>>,
+    next: Option<u64>,
+}
+
+impl<'a> BlockEncoder<'a> {
+    pub(crate) fn new(config: &'a FecConfig, chunk_size: usize, chunk_count: u64) -> Self {
+        Self {
+            config,
+            chunk_size,
+            chunk_count,
+            codec: None,
+            shards: Vec::new(),
+            next: None,
+        }
+    }
+
+    ///A chunk is being sent, returns its block and the block's repair packets if this was its last chunk
+    ///A block with a chunk that wasn't sent, or sent out of order, gets no repair packets
+    pub(crate) fn sent(&mut self, chunk: u64, data: &[u8]) -> io::Result<Option<(u64, Vec<Vec<u8>>)>> {
+        let block_chunks = self.config.block_chunks;
+        if chunk.is_multiple_of(block_chunks) {
+            self.shards.clear();
+            self.next = Some(chunk);
+        }
+        if self.next != Some(chunk) {
+            self.next = None;
+            return Ok(None);
+        }
+        //Short chunks are padded with zeros, the client pads the same way
+        let mut shard = vec![0; self.chunk_size];
+        shard[..data.len()].copy_from_slice(data);
+        self.shards.push(shard);
+        self.next = Some(chunk+1);
+
+        let block = chunk/block_chunks;
+        let data_count = data_chunks(block, block_chunks, self.chunk_count);
+        if self.shards.len() as u64 != data_count {
+            return Ok(None);
+        }
+        self.next = None;
+        let codec = codec(&mut self.codec, data_count, block_chunks, self.config.repair_chunks)?;
+        let mut repairs = vec![vec![0; self.chunk_size]; self.config.repair_chunks as usize];
+        if let Err(e) = codec.encode_sep(&self.shards, &mut repairs) {
+            return Err(io::Error::other(e));
+        }
+        Ok(Some((block, repairs)))
+    }
+}
+
+///Holds the chunks of a client's blocks until they're complete and rebuilds lost chunks once enough repair packets arrived
+pub(crate) struct BlockDecoder {
+    params: FecParams,
+    chunk_size: usize,
+    chunk_count: u64,
+    codec: Option<ReedSolomon>,
+    blocks: HashMap<u64, Block>,
+}
+
+///Chunks followed by repair packets of one block, emptied once every chunk is there
+struct Block {
+    shards: Vec<Option<Vec<u8>>>,
+    received: u64,
+    present: u64,
+    done: bool,
+}
+
+impl BlockDecoder {
+    ///None if params don't describe a file of chunk_count chunks
+    pub(crate) fn new(params: FecParams, chunk_size: usize, chunk_count: u64) -> Option<Self> {
+        if chunk_count == 0 || params.file_size.div_ceil(chunk_size as u64) != chunk_count {
+            return None;
+        }
+        Some(Self {
+            params,
+            chunk_size,
+            chunk_count,
+            codec: None,
+            blocks: HashMap::new(),
+        })
+    }
+
+    ///Last chunk of the last block that ends at or before chunk, None if there is none
+    pub(crate) fn block_end_before(&self, chunk: u64) -> Option<u64> {
+        let end = (chunk+1)/self.params.block_chunks*self.params.block_chunks;
+        end.checked_sub(1)
+    }
+
+    ///A chunk arrived, returns the chunks of its block that could be rebuilt thanks to it
+    pub(crate) fn chunk(&mut self, chunk: u64, data: &[u8]) -> Vec<(u64, Vec<u8>)> {
+        let block = chunk/self.params.block_chunks;
+        let slot = (chunk%self.params.block_chunks) as usize;
+        let mut shard = vec![0; self.chunk_size];
+        shard[..data.len()].copy_from_slice(data);
+        self.add(block, slot, shard)
+    }
+
+    ///A repair packet arrived, index is its chunk index without REPAIR_CHUNK
+    ///Returns the chunks of its block that could be rebuilt thanks to it
+    pub(crate) fn repair(&mut self, index: u64, data: &[u8]) -> Vec<(u64, Vec<u8>)> {
+        let block = index/self.params.repair_chunks;
+        if data.len() != self.chunk_size || block >= self.chunk_count.div_ceil(self.params.block_chunks) {
+            return Vec::new();
+        }
+        let slot = (data_chunks(block, self.params.block_chunks, self.chunk_count) + index%self.params.repair_chunks) as usize;
+        self.add(block, slot, data.to_vec())
+    }
+
+    ///Forget the blocks ending at or before chunk, their repair packets went by long ago
+    pub(crate) fn forget_through(&mut self, chunk: u64) {
+        let block_chunks = self.params.block_chunks;
+        self.blocks.retain(|block, _| (block+1)*block_chunks > chunk+1);
+    }
+
+    ///Forget every block, for when the transfer moves on to the next window
+    pub(crate) fn clear(&mut self) {
+        self.blocks.clear();
+    }
+
+    fn add(&mut self, block: u64, slot: usize, shard: Vec<u8>) -> Vec<(u64, Vec<u8>)> {
+        let FecParams { block_chunks, repair_chunks, file_size } = self.params;
+        let data_count = data_chunks(block, block_chunks, self.chunk_count);
+        let entry = self.blocks.entry(block).or_insert_with(|| Block {
+            shards: vec![None; (data_count+repair_chunks) as usize],
+            received: 0,
+            present: 0,
+            done: false,
+        });
+        if entry.done || entry.shards[slot].is_some() {
+            return Vec::new();
+        }
+        entry.shards[slot] = Some(shard);
+        entry.present += 1;
+        if (slot as u64) < data_count {
+            entry.received += 1;
+        }
+        if entry.received == data_count {
+            //Nothing left to rebuild, only remember the block is done
+            entry.done = true;
+            entry.shards = Vec::new();
+            return Vec::new();
+        }
+        if entry.present < data_count {
+            return Vec::new();
+        }
+
+        let codec = match codec(&mut self.codec, data_count, block_chunks, repair_chunks) {
+            Ok(c) => c,
+            Err(_) => return Vec::new(),
+        };
+        let missing: Vec<usize> = (0..data_count as usize).filter(|i| entry.shards[*i].is_none()).collect();
+        if codec.reconstruct_data(&mut entry.shards).is_err() {
+            return Vec::new();
+        }
+        let mut rebuilt = Vec::new();
+        for i in missing {
+            let chunk = block*block_chunks + i as u64;
+            if let Some(mut data) = entry.shards[i].take() {
+                //The last chunk of the file is only as long as what's left of it
+                if chunk == self.chunk_count-1 {
+                    data.truncate((file_size - chunk*self.chunk_size as u64) as usize);
+                }
+                rebuilt.push((chunk, data));
+            }
+        }
+        entry.done = true;
+        entry.shards = Vec::new();
+        rebuilt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHUNK_SIZE: usize = 100;
+
+    ///Bytes that differ from chunk to chunk and within them
+    fn file(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i*31 % 251) as u8).collect()
+    }
+
+    ///Send file through an encoder, drop the chunks lost says and feed the rest and the repair packets to a decoder
+    ///Returns the file put back together, with lost chunks that weren't rebuilt left as zeros, and how many were rebuilt
+    fn transfer(data: &[u8], config: &FecConfig, lost: impl Fn(u64) -> bool) -> (Vec<u8>, usize) {
+        let chunk_count = data.len().div_ceil(CHUNK_SIZE) as u64;
+        let params = FecParams {
+            block_chunks: config.block_chunks,
+            repair_chunks: config.repair_chunks,
+            file_size: data.len() as u64,
+        };
+        let mut encoder = BlockEncoder::new(config, CHUNK_SIZE, chunk_count);
+        let mut decoder = BlockDecoder::new(params, CHUNK_SIZE, chunk_count).unwrap();
+        let mut out = vec![0; data.len()];
+        let mut rebuilt_count = 0;
+        for (chunk, bytes) in data.chunks(CHUNK_SIZE).enumerate() {
+            let chunk = chunk as u64;
+            let mut rebuilt = Vec::new();
+            if !lost(chunk) {
+                out[chunk as usize*CHUNK_SIZE..][..bytes.len()].copy_from_slice(bytes);
+                rebuilt.extend(decoder.chunk(chunk, bytes));
+            }
+            if let Some((block, repairs)) = encoder.sent(chunk, bytes).unwrap() {
+                for (r, repair) in repairs.iter().enumerate() {
+                    rebuilt.extend(decoder.repair(block*config.repair_chunks + r as u64, repair));
+                }
+            }
+            for (chunk, bytes) in rebuilt {
+                assert!(lost(chunk), "chunk {} rebuilt but it arrived", chunk);
+                out[chunk as usize*CHUNK_SIZE..][..bytes.len()].copy_from_slice(&bytes);
+                rebuilt_count += 1;
+            }
+        }
+        (out, rebuilt_count)
+    }
+
+    #[test]
+    fn rebuilds_up_to_repair_chunks_per_block() {
+        let config = FecConfig { block_chunks: 8, repair_chunks: 3 };
+        //Three full blocks and a last one of 6 chunks ending in a short chunk
+        let data = file(29*CHUNK_SIZE + 37);
+        for offset in 0..8 {
+            //Block b loses b%4 chunks starting at offset, the last block loses the short chunk and up to 2 more
+            let lost = |chunk: u64| {
+                let (block, slot) = (chunk/8, (chunk%8 + 8 - offset)%8);
+                if block == 3 {
+                    chunk == 29 || slot < 2
+                } else {
+                    slot < block%4
+                }
+            };
+            let expected = (0..30).filter(|c| lost(*c)).count();
+            let (out, rebuilt) = transfer(&data, &config, lost);
+            assert_eq!(rebuilt, expected, "offset {}", offset);
+            assert!(out == data, "offset {}", offset);
+        }
+    }
+
+    #[test]
+    fn rebuilds_a_short_last_block_of_one_chunk() {
+        let config = FecConfig { block_chunks: 4, repair_chunks: 2 };
+        let data = file(4*CHUNK_SIZE + 1);
+        let (out, rebuilt) = transfer(&data, &config, |chunk| chunk == 4 || chunk == 1);
+        assert_eq!(rebuilt, 2);
+        assert!(out == data);
+    }
+
+    #[test]
+    fn too_many_lost_rebuilds_nothing() {
+        let config = FecConfig { block_chunks: 8, repair_chunks: 2 };
+        let data = file(16*CHUNK_SIZE);
+        let (_, rebuilt) = transfer(&data, &config, |chunk| chunk < 3);
+        assert_eq!(rebuilt, 0);
+    }
+
+    #[test]
+    fn params_round_trip() {
+        let config = FecConfig { block_chunks: 32, repair_chunks: 4 };
+        let mut buffer = [0; 24];
+        assert_eq!(pack_params(&config, 12345, &mut buffer), 24);
+        let params = unpack_params(&buffer).unwrap();
+        assert_eq!((params.block_chunks, params.repair_chunks, params.file_size), (32, 4, 12345));
+        assert!(unpack_params(&buffer[0..23]).is_none());
+        let config = FecConfig { block_chunks: 250, repair_chunks: 7 };
+        pack_params(&config, 1, &mut buffer);
+        assert!(unpack_params(&buffer).is_none());
+    }
+}
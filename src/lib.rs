@@ -1,35 +1,210 @@
+mod batch;
+mod cache;
+mod compress;
+mod delta;
+mod duplicates;
+mod fec;
+mod merkle;
+#[cfg(target_os = "linux")]
+mod mmsg;
 mod range_tree;
+mod rate_limit;
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+mod uring;
+mod variant;
+pub mod access_log;
+pub mod client;
+pub mod config;
+pub mod metrics;
+pub mod multicast;
+pub mod session;
+pub mod upload;
+pub mod whitelist;
 
 use std::io;
-use std::io::prelude::*;
-use std::io::SeekFrom;
-use std::io::Seek;
 use std::fs;
 use std::fs::File;
 use std::mem;
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 use std::net::UdpSocket;
 use std::collections::VecDeque;
-use std::collections::HashSet;
-use std::time::{Duration, Instant};
-use range_tree::RangeTree;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use log::{debug, error, info, warn};
+use std::time::Instant;
+use std::time::Duration;
+use sha2::{Digest, Sha256};
+use access_log::AccessLog;
+use duplicates::DuplicateFilter;
+use compress::Compressor;
+use fec::BlockEncoder;
+use batch::{Pipeline, Receiver};
+use cache::FileCache;
+use metrics::DropReason;
+use session::OpenResult;
+use rate_limit::{ClientLimiter, TokenBucket};
+pub use client::{is_file_changed, Client, ClientBuilder, FileChanged, Progress, ProgressObserver, TransferStats};
+pub use config::ServerConfig;
+pub use metrics::Metrics;
+pub use session::SessionManager;
+pub use upload::UploadManager;
+pub use whitelist::Whitelist;
 
 //Constants defining internal behavior
-///Starting out with 512 byte packets
-///All packets are made using these two variables
+///Chunk index of a control packet in a stream of data packets, no file ever has this many chunks
+///A control packet is CONTROL_CHUNK followed by a u64 code
+pub const CONTROL_CHUNK: u64 = u64::MAX;
+///Control code telling the client the file changed since it asked for the metadata
+pub const FILE_CHANGED: u64 = 1;
+///Control code of the packet multicast receivers learn the chunk count and epoch of the file being sent from, see multicast::info_packet
+pub const MULTICAST_INFO: u64 = 2;
+///Control code of the packets answering a HASH_CHUNKS request, CONTROL_CHUNK, CHUNK_HASHES, u64 first chunk and then a 16 byte hash per chunk
+pub const CHUNK_HASHES: u64 = 3;
+///Control code of the packets answering a MERKLE_NODES request, CONTROL_CHUNK, TREE_NODES and then u64 node ID and 16 byte hash pairs
+pub const TREE_NODES: u64 = 4;
+///Packet ID of a chunk request with its ranges packed by pack_ranges
+pub const COMPACT_CHUNKS: u64 = 9;
+///Packet ID of a report of lost chunks, packed like COMPACT_CHUNKS
+pub const NACK_CHUNKS: u64 = 11;
+///Packet ID of a chunk request packed like COMPACT_CHUNKS that also wants repair packets for every whole block it covers
+pub const FEC_CHUNKS: u64 = 13;
+///Packet ID of a request for the hashes of ranges of chunks, packed like COMPACT_CHUNKS
+pub const HASH_CHUNKS: u64 = 15;
+///Packet ID of a request for nodes of the Merkle tree over the chunks of a file, packed like COMPACT_CHUNKS with node IDs in place of chunks
+///A node ID is its level, leaves being level 0, shifted up 56 bits plus its index within the level
+pub const MERKLE_NODES: u64 = 17;
+///Chunk indexes with this bit set are repair packets, the rest of the index is block*repair_chunks plus which repair packet of the block it is
+pub const REPAIR_CHUNK: u64 = 1 << 63;
+///Chunk indexes of data packets with this bit set carry the chunk compressed with the algorithm the server advertised
+pub const COMPRESSED_CHUNK: u64 = 1 << 62;
+///Packet ID bit asking for compressed chunks, set on the ID of any request packed like COMPACT_CHUNKS
+///Only for servers with FEATURE_ZSTD or FEATURE_LZ4, chunks that don't get smaller still come uncompressed
+///In the flags after the name of a metadata or session open request it takes the file's pre-compressed zstd variant if the server has one
+pub const COMPRESS_REQUEST: u64 = 1 << 32;
+///Packet ID bit asking for chunks of the pre-compressed variant rather than the file, set on the ID of a request packed like COMPACT_CHUNKS
+pub const VARIANT_REQUEST: u64 = 1 << 33;
+///Feature bit for understanding COMPACT_CHUNKS and session::SESSION_COMPACT_CHUNKS requests
+pub const FEATURE_COMPACT_RANGES: u64 = 1;
+///Feature bit for understanding NACK_CHUNKS and session::SESSION_NACK_CHUNKS reports
+pub const FEATURE_NACK: u64 = 2;
+///Feature bit for understanding FEC_CHUNKS and session::SESSION_FEC_CHUNKS requests
+///Servers sending repair packets put the block size, repair packets per block and file size after the features
+pub const FEATURE_FEC: u64 = 4;
+///Feature bit for compressing chunks with zstd when asked with COMPRESS_REQUEST
+pub const FEATURE_ZSTD: u64 = 8;
+///Feature bit for compressing chunks with lz4 when asked with COMPRESS_REQUEST
+pub const FEATURE_LZ4: u64 = 16;
+///Set in the features of a metadata or session open response describing the file's pre-compressed zstd variant instead of the file
+///What the variant decodes to follows the FEC params, see variant::pack_info
+pub const VARIANT_ZSTD: u64 = 32;
+///Feature bit for understanding HASH_CHUNKS and session::SESSION_HASH_CHUNKS requests
+pub const FEATURE_HASHES: u64 = 64;
+///Set in the features of a metadata or session open response carrying the root of the Merkle tree over the chunks it describes
+///The 16 byte root comes last, after the variant description, and the server understands MERKLE_NODES and session::SESSION_MERKLE_NODES requests
+pub const FEATURE_MERKLE: u64 = 128;
+///Features every server supports, sent in metadata and session open responses so clients know what they can ask for
+///FEC and the compression algorithm are added by features if they are configured
+pub const FEATURES: u64 = FEATURE_COMPACT_RANGES | FEATURE_NACK | FEATURE_HASHES;
+///Starting out with 512 byte packets, servers can pick a different packet size in their config
+///Every data packet is a u64 chunk index followed by up to PACKET_SIZE - 8 bytes of file data
 const PACKET_SIZE: usize = 512;
-const BUFFER_SIZE: usize = PACKET_SIZE - mem::size_of::<u64>();
+
+///What a transaction is asking the server for
+pub enum TransactionKind {
+    ///Chunk count of a file
+    Metadata,
+    ///Ranges of chunks of a file
+    Chunks,
+    ///One page of the whitelisted files below a directory
+    List(u64),
+    ///Open a file in a session, 0 starts a new session
+    SessionOpen(u64),
+    ///Ranges of chunks of a file opened in a session, by session ID and handle
+    SessionChunks(u64, u64),
+    ///Close a file opened in a session, or the whole session if the handle is 0
+    SessionClose(u64, u64),
+}
+
+///What a request packed like COMPACT_CHUNKS asks of the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeRequest {
+    ///Send the chunks
+    Chunks,
+    ///The chunks were lost, send them even if they were just sent, only for servers with FEATURE_NACK
+    Lost,
+    ///Send the chunks and repair packets for every whole block among them, only for servers with FEATURE_FEC
+    WithRepair,
+    ///Send the hashes of the chunks, only for servers with FEATURE_HASHES
+    Hashes,
+    ///Send the Merkle tree nodes with these IDs, only for servers that sent FEATURE_MERKLE
+    Nodes,
+}
 
 ///Struct representing a request for data chunks
 ///
-///filename: String, String representing which file to pull from
+///kind: TransactionKind, What is being requested
+///filename: String, String representing which file to pull from, or which directory to list
 ///starts: Vec<u64>, Vector of interval beginnings for chunks to pull
 ///starts: Vec<u64>, Vector of offset endings for chunks to pull
+///allowed: bool, Set once the request passed the whitelist
+///packets_sent: u64, Packets sent back while servicing the request
+///bytes_sent: u64, Bytes sent back while servicing the request
+///epoch: u64, Version of the file the client started on, 0 if the client doesn't care
+///lost: bool, The client reported these chunks lost, send them even if they were just sent
+///repair: bool, The client wants repair packets for the blocks it asked for in full
+///compress: bool, The client takes compressed chunks, or the pre-compressed variant if this is a metadata or session open request
+///variant: bool, The chunks are those of the pre-compressed variant
+///hashes: bool, The client wants the hashes of the chunks instead of the chunks
+///nodes: bool, The client wants Merkle tree nodes, the ranges are of node IDs instead of chunks
 pub struct ChunkTransaction {
+    kind: TransactionKind,
     target: std::net::SocketAddr,
     filename: String,
     starts: VecDeque<u64>,
     ends: VecDeque<u64>,
+    allowed: bool,
+    packets_sent: u64,
+    bytes_sent: u64,
+    epoch: u64,
+    lost: bool,
+    repair: bool,
+    compress: bool,
+    variant: bool,
+    hashes: bool,
+    nodes: bool,
+}
+
+impl ChunkTransaction {
+    fn new(kind: TransactionKind, target: std::net::SocketAddr, filename: String) -> Self {
+        Self {
+            kind,
+            target,
+            filename,
+            starts: VecDeque::new(),
+            ends: VecDeque::new(),
+            allowed: false,
+            packets_sent: 0,
+            bytes_sent: 0,
+            epoch: 0,
+            lost: false,
+            repair: false,
+            compress: false,
+            variant: false,
+            hashes: false,
+            nodes: false,
+        }
+    }
+}
+
+///Logs the requested chunk ranges of a transaction as [start-end, ...]
+struct RangeList<'a>(&'a ChunkTransaction);
+
+impl std::fmt::Debug for RangeList<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ranges: Vec<String> = self.0.starts.iter().zip(self.0.ends.iter()).map(|(s,e)| format!("{}-{}", s, e)).collect();
+        write!(f, "[{}]", ranges.join(", "))
+    }
 }
 
 ///Take a u64 and pack it into an owned array of u8
@@ -47,56 +222,209 @@ pub fn unpack_u8arr_into_u64(val: &[u8]) -> u64 {
     u64::from_be_bytes(bytes.try_into().unwrap())
 }
 
-///Turn an inbound request into a metadata transaction and add it to the server's transacton queue
-pub fn add_metadata_transaction(data: &[u8], source: std::net::SocketAddr, transactions: &mut VecDeque<ChunkTransaction>) {
-    //Parse a filename and chunk requests
-    //First grab the u8 representing filename length
-    let namelen: usize = data[0] as usize;
-    let filename = String::from_utf8_lossy(&data[1..1+namelen]);
-    
-    println!("Metadata request received for {}", filename);
-    //Now populate the chunk starts and ends (Both are empty for a metadata request)
-    let new_transaction = ChunkTransaction {
-        filename: String::from(filename),
-        target: source,
-        starts: VecDeque::new(),
-        ends: VecDeque::new(),
-    };
+///Pack a u64 as a LEB128 varint, 7 bits per byte starting with the lowest and the high bit set on all but the last byte
+///Returns how many bytes were written, at most 10
+pub fn pack_varint(mut val: u64, buffer: &mut [u8]) -> usize {
+    let mut byte_counter = 0;
+    while val >= 0x80 {
+        buffer[byte_counter] = (val as u8 & 0x7f) | 0x80;
+        val >>= 7;
+        byte_counter+=1;
+    }
+    buffer[byte_counter] = val as u8;
+    byte_counter+1
+}
 
-    transactions.push_back(new_transaction);
+///How many bytes pack_varint takes for val
+pub fn varint_len(val: u64) -> usize {
+    let bits = 64 - (val | 1).leading_zeros() as usize;
+    bits.div_ceil(7)
 }
 
-///Turn an inbound request into a chunk transaction and add it to the server's transacton queue
-pub fn add_chunk_transaction(data: &[u8], source: std::net::SocketAddr, transactions: &mut VecDeque<ChunkTransaction>) {
-    //Parse a filename and chunk requests
-    //First grab the u8 representing filename length
+///Unpack a varint from the start of data, returns it and how many bytes it took
+///None if data ends in the middle of it or it doesn't fit in a u64
+pub fn unpack_varint(data: &[u8]) -> Option<(u64, usize)> {
+    let mut val: u64 = 0;
+    for (i, byte) in data.iter().enumerate().take(10) {
+        let bits = (*byte & 0x7f) as u64;
+        if i == 9 && bits > 1 {
+            return None;
+        }
+        val |= bits << (7*i);
+        if byte & 0x80 == 0 {
+            return Some((val, i+1));
+        }
+    }
+    None
+}
+
+///Pack a name into buffer at offset as a varint length followed by its bytes, returns how many bytes were written
+///Fails if the name doesn't fit in the buffer with reserved bytes left over for the rest of the packet
+pub fn pack_name(name: &str, buffer: &mut [u8], offset: usize, reserved: usize) -> io::Result<usize> {
+    let packed_len = varint_len(name.len() as u64) + name.len();
+    if offset + packed_len + reserved > buffer.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            format!("Name is {} bytes long, too long for a packet size of {}", name.len(), buffer.len())));
+    }
+    let mut byte_counter = offset + pack_varint(name.len() as u64, &mut buffer[offset..]);
+    buffer[byte_counter..byte_counter+name.len()].copy_from_slice(name.as_bytes());
+    byte_counter+=name.len();
+    Ok(byte_counter - offset)
+}
+
+///Unpack a name packed by pack_name from the start of data, returns it and how many bytes it took
+///None if data ends before the name does
+pub fn unpack_name(data: &[u8]) -> Option<(String, usize)> {
+    let (namelen, varint_bytes) = unpack_varint(data)?;
+    let end = varint_bytes.checked_add(usize::try_from(namelen).ok()?)?;
+    if end > data.len() {
+        return None;
+    }
+    Some((String::from_utf8_lossy(&data[varint_bytes..end]).into_owned(), end))
+}
+
+///Pack as many inclusive chunk ranges as fit into buffer from offset on, returns how many bytes were written
+///Ranges are sorted and each one is a varint gap since the end of the previous range followed by a varint length - 1,
+///a few bytes each instead of 16, so they run until the end of the packet without a count
+pub fn pack_ranges(starts: &[u64], ends: &[u64], buffer: &mut [u8], offset: usize) -> usize {
+    let mut ranges: Vec<(u64, u64)> = starts.iter().cloned().zip(ends.iter().cloned()).filter(|(s, e)| s <= e).collect();
+    ranges.sort_unstable();
+    let mut byte_counter = offset;
+    let mut next: u64 = 0;
+    let mut packed = [0u8; 20];
+    for (start, end) in ranges.iter() {
+        //Overlaps are trimmed so gaps are never negative
+        let start = (*start).max(next);
+        if start > *end {
+            continue;
+        }
+        let mut packed_len = pack_varint(start-next, &mut packed);
+        packed_len += pack_varint(end-start, &mut packed[packed_len..]);
+        if byte_counter+packed_len > buffer.len() {
+            break;
+        }
+        buffer[byte_counter..byte_counter+packed_len].copy_from_slice(&packed[0..packed_len]);
+        byte_counter+=packed_len;
+        next = match end.checked_add(1) {
+            Some(n) => n,
+            None => break,
+        };
+    }
+    byte_counter - offset
+}
+
+///Unpack every range packed by pack_ranges in data, None if a range is cut off or runs past the largest chunk index
+pub fn unpack_ranges(data: &[u8]) -> Option<Vec<(u64, u64)>> {
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
     let mut byte_counter = 0;
-    let namelen: usize = data[byte_counter] as usize;
-    byte_counter+=1;
-    let filename = String::from_utf8_lossy(&data[byte_counter..byte_counter+namelen]);
-    byte_counter+=namelen;
+    let mut next: u64 = 0;
+    while byte_counter < data.len() {
+        let (gap, gap_len) = unpack_varint(&data[byte_counter..])?;
+        byte_counter+=gap_len;
+        let (len, len_len) = unpack_varint(&data[byte_counter..])?;
+        byte_counter+=len_len;
+        let start = next.checked_add(gap)?;
+        let end = start.checked_add(len)?;
+        ranges.push((start, end));
+        next = end.saturating_add(1);
+    }
+    Some(ranges)
+}
 
-    let interval_count: u64 = unpack_u8arr_into_u64(&data[byte_counter..byte_counter+8]);
-    byte_counter+=8;
+///Ranges of the plain chunk request, a u64 range count and then as many u64 start/end pairs, those past the end of data are ignored
+///Returns the ranges and how many bytes they took
+fn unpack_range_pairs(data: &[u8]) -> Option<(Vec<(u64, u64)>, usize)> {
+    let count = unpack_u8arr_into_u64(data.get(0..8)?).min(((data.len()-8)/16) as u64) as usize;
+    let ranges = (0..count).map(|i| {
+        let offset = 8+i*16;
+        (unpack_u8arr_into_u64(&data[offset..offset+8]), unpack_u8arr_into_u64(&data[offset+8..offset+16]))
+    }).collect();
+    Some((ranges, 8+count*16))
+}
+
+///What the requests packed like COMPACT_CHUNKS, with or without a session, ask of the server
+fn range_request(id: u64) -> Option<RangeRequest> {
+    match id {
+        COMPACT_CHUNKS | session::SESSION_COMPACT_CHUNKS => Some(RangeRequest::Chunks),
+        NACK_CHUNKS | session::SESSION_NACK_CHUNKS => Some(RangeRequest::Lost),
+        FEC_CHUNKS | session::SESSION_FEC_CHUNKS => Some(RangeRequest::WithRepair),
+        HASH_CHUNKS | session::SESSION_HASH_CHUNKS => Some(RangeRequest::Hashes),
+        MERKLE_NODES | session::SESSION_MERKLE_NODES => Some(RangeRequest::Nodes),
+        _ => None,
+    }
+}
 
-    //Now populate the chunk starts and ends
-    let mut new_transaction = ChunkTransaction {
-        filename: String::from(filename),
-        target: source,
-        starts: VecDeque::new(),
-        ends: VecDeque::new(),
+///Turn the request following packet ID id into a transaction
+///None if the request is malformed or of a type that isn't implemented
+fn parse_request(id: u64, data: &[u8], source: std::net::SocketAddr) -> Option<ChunkTransaction> {
+    let session_handle = || Some((unpack_u8arr_into_u64(data.get(0..8)?), unpack_u8arr_into_u64(data.get(8..16)?)));
+    let (kind, filename, ranges, epoch, flags) = match id {
+        //A varint filename length and the filename, optionally followed by u64 flags
+        0 => {
+            let (filename, byte_counter) = unpack_name(data)?;
+            let flags = data.get(byte_counter..byte_counter+8).map_or(0, unpack_u8arr_into_u64);
+            debug!(peer:% = source, filename:% = filename, flags = flags; "Metadata request");
+            (TransactionKind::Metadata, filename, Vec::new(), 0, flags)
+        },
+        //The filename and its ranges as u64 pairs, newer clients echo the epoch from the metadata response after them
+        1 => {
+            let (filename, byte_counter) = unpack_name(data)?;
+            let (ranges, range_bytes) = unpack_range_pairs(&data[byte_counter..])?;
+            let epoch = data.get(byte_counter+range_bytes..byte_counter+range_bytes+8).map_or(0, unpack_u8arr_into_u64);
+            (TransactionKind::Chunks, filename, ranges, epoch, 0)
+        },
+        //The directory and then the u64 page being asked for
+        2 => {
+            let (directory, byte_counter) = unpack_name(data)?;
+            let page = unpack_u8arr_into_u64(data.get(byte_counter..byte_counter+8)?);
+            (TransactionKind::List(page), directory, Vec::new(), 0, 0)
+        },
+        //The filename, the u64 epoch and then packed ranges until the end of the packet
+        COMPACT_CHUNKS | NACK_CHUNKS | FEC_CHUNKS | HASH_CHUNKS | MERKLE_NODES => {
+            let (filename, byte_counter) = unpack_name(data)?;
+            let epoch = unpack_u8arr_into_u64(data.get(byte_counter..byte_counter+8)?);
+            (TransactionKind::Chunks, filename, unpack_ranges(&data[byte_counter+8..])?, epoch, 0)
+        },
+        //A u64 session ID and the filename, optionally followed by u64 flags
+        session::SESSION_OPEN => {
+            let (filename, byte_counter) = data.get(8..).and_then(unpack_name)?;
+            let session_id = unpack_u8arr_into_u64(&data[0..8]);
+            let flags = data.get(8+byte_counter..16+byte_counter).map_or(0, unpack_u8arr_into_u64);
+            debug!(peer:% = source, filename:% = filename, session = session_id, flags = flags; "Session open request");
+            (TransactionKind::SessionOpen(session_id), filename, Vec::new(), 0, flags)
+        },
+        //Session files are named by a u64 session ID and u64 handle, the filename is filled in from the session when they are serviced
+        session::SESSION_CHUNKS => {
+            let (session_id, handle) = session_handle()?;
+            let (ranges, _) = unpack_range_pairs(&data[16..])?;
+            (TransactionKind::SessionChunks(session_id, handle), String::new(), ranges, 0, 0)
+        },
+        session::SESSION_COMPACT_CHUNKS | session::SESSION_NACK_CHUNKS | session::SESSION_FEC_CHUNKS | session::SESSION_HASH_CHUNKS | session::SESSION_MERKLE_NODES => {
+            let (session_id, handle) = session_handle()?;
+            (TransactionKind::SessionChunks(session_id, handle), String::new(), unpack_ranges(&data[16..])?, 0, 0)
+        },
+        session::SESSION_CLOSE => {
+            let (session_id, handle) = session_handle()?;
+            (TransactionKind::SessionClose(session_id, handle), String::new(), Vec::new(), 0, 0)
+        },
+        _ => return None,
     };
 
-    for _ in 0..interval_count {
-        let start = unpack_u8arr_into_u64(&data[byte_counter..byte_counter+8]);
-        byte_counter+=8;
-        let end = unpack_u8arr_into_u64(&data[byte_counter..byte_counter+8]);
-        byte_counter+=8;
+    let mut new_transaction = ChunkTransaction::new(kind, source, filename);
+    for (start, end) in ranges {
         new_transaction.starts.push_back(start);
         new_transaction.ends.push_back(end);
     }
-    //Push the generated transaction into the main queue
-    transactions.push_back(new_transaction);
+    new_transaction.epoch = epoch;
+    new_transaction.compress = flags & COMPRESS_REQUEST != 0;
+    match range_request(id) {
+        Some(RangeRequest::Lost) => new_transaction.lost = true,
+        Some(RangeRequest::WithRepair) => new_transaction.repair = true,
+        Some(RangeRequest::Hashes) => new_transaction.hashes = true,
+        Some(RangeRequest::Nodes) => new_transaction.nodes = true,
+        Some(RangeRequest::Chunks) | None => {},
+    }
+    Some(new_transaction)
 }
 
 ///Handle inbound requests
@@ -106,344 +434,722 @@ pub fn server_handle_inbound(
     transactions: &mut VecDeque<ChunkTransaction>,
     buffer: &[u8],
 ) {
-    //Variable used in byte packing
-    let mut byte_counter: usize = 0;
     //Get packet ID, this determines what type of request the packet is
-    let id: u64 = unpack_u8arr_into_u64(&buffer[byte_counter..byte_counter + 8]);
-    byte_counter+=8;
+    let id: u64 = unpack_u8arr_into_u64(&buffer[0..8]);
+    //Range requests ask for compressed chunks or the pre-compressed variant in their ID
+    let compress = id & COMPRESS_REQUEST != 0;
+    let variant = id & VARIANT_REQUEST != 0;
+    let id = id & !(COMPRESS_REQUEST | VARIANT_REQUEST);
 
-    //0 This is going to be a plaintext metadata request
-    if id == 0 {
-        add_metadata_transaction(&buffer[byte_counter..bytes], source, transactions);
-    } 
-    //1 This is going to be a plaintext chunk request
-    else if id == 1 {
-        add_chunk_transaction(&buffer[byte_counter..bytes], source, transactions);
-    }
-    //This is some other type of request that isn't implemeneted, output an error
-    else {
-        println!("Got a request type that isn't implemented yet!");
+    match parse_request(id, &buffer[8..bytes], source) {
+        Some(mut t) => {
+            t.compress |= compress;
+            t.variant = variant;
+            transactions.push_back(t);
+        },
+        None => debug!(peer:% = source, id = id; "Malformed request or a request type that isn't implemented"),
     }
 }
 
-
 //This function is to service transactions
 //THIS IS THE ONLY FUNCTION THAT WILL PASS DATA BACK TO THE CLIENT UNDER ANY CIRCUMSTANCES, THIS IS SECURITY CRITICAL!
-///Service the transaction represented by t on the socket provided, using the appropriate whitelist, limited by limiter
-///If limiter is 0, no limits!
-pub fn server_service_transaction(t: &mut ChunkTransaction, socket: &mut UdpSocket, whitelist: &mut HashSet<String>, limiter: u64) -> std::io::Result<()> {
-    let mut sent_counter = 0;
-    let mut send_buffer: [u8;PACKET_SIZE] = [0; PACKET_SIZE];
-    //Any request for a file that is not on the whitelist gets an all zeros response
-    if !whitelist.contains(&t.filename) {
-        let nil_reply: [u8;PACKET_SIZE] = [0; PACKET_SIZE];
-        //Send the packet
-        match socket.send_to(&nil_reply[0..PACKET_SIZE],t.target)
-        {
-            Ok(_) => {},
-            Err(e) => {
-                println!("Unable to send data to {:?}.  Error:{:?}",t.target,e);
-                return Err(e)
+///Service the transaction represented by t on the socket provided, using the appropriate whitelist and config limits
+///Outbound bytes are paced by bandwidth and counted in metrics
+pub fn server_service_transaction(t: &mut ChunkTransaction, socket: &UdpSocket, state: &ServerState, pipeline: &mut Pipeline, bandwidth: &mut TokenBucket) -> std::io::Result<()> {
+    let ServerState { config, whitelist, metrics, sessions, .. } = state;
+    let chunk_size = config.packet_size - mem::size_of::<u64>();
+    let mut send_buffer: Vec<u8> = vec![0; config.packet_size];
+    metrics.request(&t.kind);
+    match t.kind {
+        //Listings only ever contain whitelisted files, so they don't need a whitelisted name
+        TransactionKind::List(page) => {
+            t.allowed = true;
+            let files = whitelist.list(&t.filename);
+            let bytes_to_send = list_response_packet(&files, page, &mut send_buffer);
+            return send_response(t, socket, &send_buffer[0..bytes_to_send], metrics);
+        },
+        //Session files passed the whitelist when they were opened
+        TransactionKind::SessionChunks(session_id, handle) => {
+            let session = match sessions.get(session_id, t.target) {
+                Some(s) => s,
+                None => {
+                    debug!(peer:% = t.target, session = session_id; "Chunk request for an unknown session");
+                    return Ok(());
+                }
+            };
+            let mut session = match session.lock() {
+                Ok(s) => s,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            let session = &mut *session;
+            let open = match session.files.get_mut(&handle) {
+                Some(o) => o,
+                None => {
+                    debug!(peer:% = t.target, session = session_id, handle = handle; "Chunk request for an unknown handle");
+                    return Ok(());
+                }
+            };
+            t.filename = open.filename.clone();
+            t.allowed = true;
+            //A file swapped out by a rename stays pinned, but one rewritten in place can't be served any more
+            if file_epoch(&open.file.metadata()?) != open.epoch {
+                info!(peer:% = t.target, filename:% = t.filename, session = session_id; "File changed during a session transfer");
+                let bytes_to_send = file_changed_packet(&mut send_buffer);
+                return send_response(t, socket, &send_buffer[0..bytes_to_send], metrics);
             }
+            let result = if t.nodes {
+                send_tree_nodes(t, &open.path, &mut open.file, socket, state, bandwidth, Some(&mut session.bandwidth))
+            } else if t.hashes {
+                send_chunk_hashes(t, &open.path, &mut open.file, socket, state, bandwidth, Some(&mut session.bandwidth))
+            } else {
+                send_chunk_ranges(t, &open.file, socket, state, pipeline, bandwidth, Some(&mut session.bandwidth))
+            };
+            session.record(t.packets_sent, t.bytes_sent);
+            return result;
+        },
+        TransactionKind::SessionClose(session_id, handle) => {
+            sessions.close(session_id, handle, t.target);
+            return Ok(());
+        },
+        _ => {},
+    }
+    //Any request for a file that is not on the whitelist gets an all zeros response
+    //The whitelist hands back the canonical path, only ever open that one
+    let allowed = if t.filename.len() <= config.security.max_filename_len {
+        whitelist.check(&t.filename)
+    } else {
+        None
+    };
+    let path = match allowed {
+        Some(p) => {
+            t.allowed = true;
+            p
+        },
+        None => {
+            info!(peer:% = t.target, filename:% = t.filename; "Denied request");
+            metrics.whitelist_denied();
+            //Session clients are told in their own response format
+            let bytes_to_send = match t.kind {
+                TransactionKind::SessionOpen(_) => session::open_response_packet(0, 0, 0, chunk_size as u64, OpenResult::NotAllowed, features(config), &mut send_buffer),
+                _ => send_buffer.len(),
+            };
+            return send_response(t, socket, &send_buffer[0..bytes_to_send], metrics);
         }
+    };
+    //This is either a metadata request, a session open or a chunk request
+    match t.kind {
+        TransactionKind::Metadata => {
+            let (variant, path, features) = select_variant(t, path, state);
+            //Size, epoch and root all come from the one open file, so they describe the same version of it
+            let opened = File::open(&path).and_then(|file| {
+                let metadata = file.metadata()?;
+                Ok((file, metadata))
+            });
+            let (mut file, metadata) = match opened {
+                Ok(o) => o,
+                Err(e) => {
+                    debug!(path:? = path, error:% = e; "Unable to read metadata");
+                    let bytes_to_send = metadata_response_packet(None, chunk_size as u64, features, &mut send_buffer);
+                    return send_response(t, socket, &send_buffer[0..bytes_to_send], metrics);
+                }
+            };
+            let tree = merkle_tree(&path, &mut file, file_epoch(&metadata), state)?;
+            let features = if tree.is_some() { features | FEATURE_MERKLE } else { features };
+            let mut bytes_to_send = metadata_response_packet(Some(&metadata), chunk_size as u64, features, &mut send_buffer);
+            //Clients rebuilding chunks from repair packets need to know how the file is protected
+            bytes_to_send += fec::pack_params(&config.fec, metadata.len(), &mut send_buffer[bytes_to_send..]);
+            if let Some(info) = &variant {
+                bytes_to_send += variant::pack_info(info, &mut send_buffer[bytes_to_send..]);
+            }
+            if let Some(tree) = &tree {
+                send_buffer[bytes_to_send..bytes_to_send+merkle::ROOT_LEN].copy_from_slice(&tree.root());
+                bytes_to_send += merkle::ROOT_LEN;
+            }
+            send_response(t, socket, &send_buffer[0..bytes_to_send], metrics)
+        },
+        TransactionKind::SessionOpen(session_id) => {
+            let (variant, path, features) = select_variant(t, path, state);
+            let file = File::open(&path)?;
+            let metadata = file.metadata()?;
+            let epoch = file_epoch(&metadata);
+            let chunk_count = metadata.len().div_ceil(chunk_size as u64);
+            //The tree is built from the very file the session pins, and only once the session took it
+            let mut pinned = file.try_clone()?;
+            let (session_id, handle, result) = sessions.open(session_id, t.target, &t.filename, &path, file, epoch);
+            let tree = match result {
+                OpenResult::Opened => match merkle_tree(&path, &mut pinned, epoch, state) {
+                    Ok(tree) => tree,
+                    Err(e) => {
+                        sessions.close(session_id, handle, t.target);
+                        return Err(e);
+                    }
+                },
+                _ => None,
+            };
+            let chunk_count = if result == OpenResult::Opened { chunk_count } else { 0 };
+            let features = if tree.is_some() { features | FEATURE_MERKLE } else { features };
+            let mut bytes_to_send = session::open_response_packet(session_id, handle, chunk_count, chunk_size as u64, result, features, &mut send_buffer);
+            if result == OpenResult::Opened {
+                bytes_to_send += fec::pack_params(&config.fec, metadata.len(), &mut send_buffer[bytes_to_send..]);
+                if let Some(info) = &variant {
+                    bytes_to_send += variant::pack_info(info, &mut send_buffer[bytes_to_send..]);
+                }
+                if let Some(tree) = &tree {
+                    send_buffer[bytes_to_send..bytes_to_send+merkle::ROOT_LEN].copy_from_slice(&tree.root());
+                    bytes_to_send += merkle::ROOT_LEN;
+                }
+            }
+            send_response(t, socket, &send_buffer[0..bytes_to_send], metrics)
+        },
+        _ => {
+            //The epoch the client echoes is that of the variant it was told about, a variant that's gone changed too
+            let path = match t.variant {
+                true if config.compression.variants => variant::find(&path),
+                true => None,
+                false => Some(path),
+            };
+            let (path, mut file) = match path {
+                Some(p) => {
+                    let file = File::open(&p)?;
+                    (p, file)
+                },
+                None => {
+                    info!(peer:% = t.target, filename:% = t.filename; "Pre-compressed variant is gone");
+                    let bytes_to_send = file_changed_packet(&mut send_buffer);
+                    return send_response(t, socket, &send_buffer[0..bytes_to_send], metrics);
+                }
+            };
+            let epoch = file_epoch(&file.metadata()?);
+            if t.epoch != 0 && epoch != t.epoch {
+                info!(peer:% = t.target, filename:% = t.filename; "File changed since the client asked for its metadata");
+                let bytes_to_send = file_changed_packet(&mut send_buffer);
+                return send_response(t, socket, &send_buffer[0..bytes_to_send], metrics);
+            }
+            if t.nodes {
+                return send_tree_nodes(t, &path, &mut file, socket, state, bandwidth, None);
+            }
+            if t.hashes {
+                return send_chunk_hashes(t, &path, &mut file, socket, state, bandwidth, None);
+            }
+            send_chunk_ranges(t, &file, socket, state, pipeline, bandwidth, None)
+        },
     }
-    //This is either a metadata request, or a chunk request
-    if t.starts.len() == 0{
+}
 
-        let bytes_to_send = metadata_response_packet(&t.filename, &mut send_buffer);
-        //Send the packet
-        match socket.send_to(&send_buffer[0..bytes_to_send],t.target)
-        {
-            Ok(_) => {},
-            Err(e) => {
-                println!("Unable to send data to {:?}.  Error:{:?}",t.target,e);
-                return Err(e)
-            }
+///What to describe in a metadata or session open response for t, the pre-compressed variant if t takes one and there is one
+///Returns its description, the path to send and the features of the response
+fn select_variant(t: &ChunkTransaction, path: PathBuf, state: &ServerState) -> (Option<variant::VariantInfo>, PathBuf, u64) {
+    let config = &state.config;
+    let selected = if t.compress { variant::select(&path, config, &state.files) } else { None };
+    match selected {
+        Some((variant_path, info)) => {
+            debug!(peer:% = t.target, filename:% = t.filename, size = info.stored_size, decoded_size = info.decoded_size; "Sending the pre-compressed variant");
+            (Some(info), variant_path, features(config) | VARIANT_ZSTD)
+        },
+        None => (None, path, features(config)),
+    }
+}
+
+///Send a single response packet for t
+fn send_response(t: &mut ChunkTransaction, socket: &UdpSocket, packet: &[u8], metrics: &Metrics) -> std::io::Result<()> {
+    match socket.send_to(packet, t.target)
+    {
+        Ok(sent) => {
+            metrics.sent(sent);
+            t.packets_sent += 1;
+            t.bytes_sent += sent as u64;
+            Ok(())
+        },
+        Err(e) => {
+            warn!(peer:% = t.target, error:% = e; "Unable to send data");
+            Err(e)
         }
+    }
+}
+
+///Send every chunk of file t asks for, paced by the server wide bandwidth and the session's own if there is one
+///Blocks sent whole are followed by their repair packets if t asked for them and FEC is enabled
+///Whatever pipeline is still sending is waited for, so errors sending t's packets are t's
+fn send_chunk_ranges(t: &mut ChunkTransaction, file: &File, socket: &UdpSocket, state: &ServerState, pipeline: &mut Pipeline, bandwidth: &mut TokenBucket, session_bandwidth: Option<&mut TokenBucket>) -> std::io::Result<()> {
+    let result = queue_chunk_ranges(t, file, socket, state, pipeline, bandwidth, session_bandwidth);
+    let finished = pipeline.finish(&state.metrics);
+    result.and(finished)
+}
+
+///Read the chunks of t into batches of packets and send them through pipeline
+///Chunks are picked until the batch is full, read together straight into the packets they go out in, then the
+///repair packets of blocks they completed go after them
+fn queue_chunk_ranges(t: &mut ChunkTransaction, file: &File, socket: &UdpSocket, state: &ServerState, pipeline: &mut Pipeline, bandwidth: &mut TokenBucket, mut session_bandwidth: Option<&mut TokenBucket>) -> std::io::Result<()> {
+    let ServerState { config, metrics, duplicates, .. } = state;
+    let limiter = config.limits.packets_per_request;
+    let mut suppressed: u64 = 0;
+    let mut repairs_sent: u64 = 0;
+    let chunk_size = config.packet_size - mem::size_of::<u64>();
+    let chunk_count = file.metadata()?.len().div_ceil(chunk_size as u64);
+    let mut encoder = if t.repair && config.fec.repair_chunks != 0 {
+        Some(BlockEncoder::new(&config.fec, chunk_size, chunk_count))
     } else {
-        
-        let mut file = File::open(&t.filename)?;
-        let mut buffer: [u8;BUFFER_SIZE] = [0; BUFFER_SIZE];
-        //Look through all requested chunks and grab em
-        for it in t.starts.iter().zip(t.ends.iter()) {
-            let (s,e) = it;
-            file.seek(SeekFrom::Start(*s*(BUFFER_SIZE as u64)))?;
-            //iterate from 0 to *e, make a packet and send it
-            for i in 0..(*e-*s)+1{
-                file.seek(SeekFrom::Start((*s+i)*(BUFFER_SIZE as u64)))?;
-                let bytes_read = file.read(&mut buffer)?;
-                if bytes_read == 0 {
-                    println!("A WE FOUND IT");
+        None
+    };
+    let mut compressor = if t.compress { Compressor::new(&config.compression)? } else { None };
+    let mut scratch: Vec<u8> = if compressor.is_some() { vec![0; chunk_size] } else { Vec::new() };
+    let mut compressed: u64 = 0;
+    let ranges: Vec<(u64, u64)> = t.starts.iter().cloned().zip(t.ends.iter().cloned()).collect();
+    //Every requested chunk in order, backwards ranges ask for nothing and a range stops at the end of the file
+    let mut wanted = ranges.iter().flat_map(|(s, e)| (*s..=*e).take_while(move |c| *c < chunk_count));
+    let mut chunks: Vec<u64> = Vec::new();
+    let mut lens: Vec<usize> = Vec::new();
+    let mut repairs: Vec<(u64, Vec<Vec<u8>>)> = Vec::new();
+    let mut hit_limit = false;
+    let mut last_batch = false;
+    while !last_batch {
+        chunks.clear();
+        let room = pipeline.batch().room();
+        while chunks.len() < room {
+            let chunk = match wanted.next() {
+                Some(c) => c,
+                None => {
+                    last_batch = true;
                     break;
                 }
-                //Data is ready, put it in a buffer
-                let mut packet_buffer: [u8;PACKET_SIZE] = [0; PACKET_SIZE];
-                //Starting simple, just unencrypted chunks
-                let chunknum = pack_u64_into_u8arr(*s+i);
-
-                //Pack the chunknum into the packet buffer
-                let mut byte_counter: usize = 0;
-                for byte in chunknum.iter(){
-                    packet_buffer[byte_counter] = *byte;
-                    byte_counter+=1;
-                }
-                //Now pack the actual bytes of the chunk
-                for byte in buffer[0..bytes_read].iter(){
-                    packet_buffer[byte_counter] = *byte;
-                    byte_counter+=1;
+            };
+            //Asked for again before the last copy could have arrived
+            if duplicates.recent(t.target, &t.filename, chunk, t.lost) {
+                suppressed += 1;
+                continue;
+            }
+            chunks.push(chunk);
+            //Packets that made it into the batch count against the limit as if they were sent
+            if limiter != 0 && t.packets_sent + (pipeline.batch().len() + chunks.len()) as u64 >= limiter {
+                hit_limit = true;
+                last_batch = true;
+                break;
+            }
+        }
+        pipeline.read(file, &chunks, &mut lens)?;
+        let mut read: usize = 0;
+        for (i, (chunk, bytes_read)) in chunks.iter().zip(lens.iter()).enumerate() {
+            let bytes_read = *bytes_read;
+            //The file shrank since it was looked at, what is left isn't there to send
+            if bytes_read == 0 {
+                last_batch = true;
+                break;
+            }
+            read += 1;
+            let packet = pipeline.batch().next();
+            //The last chunk of a block sent whole, its repair packets follow the chunks read with it
+            let block_repairs = match encoder.as_mut() {
+                Some(encoder) if !(hit_limit && i+1 == chunks.len()) => encoder.sent(*chunk, &packet[8..8+bytes_read])?,
+                _ => None,
+            };
+            repairs.extend(block_repairs);
+            let (byte_counter, packed) = compress::pack_chunk(compressor.as_mut(), *chunk, packet, bytes_read, &mut scratch);
+            if packed {
+                compressed += 1;
+            }
+            pipeline.batch().push(byte_counter);
+        }
+        duplicates.sent(t.target, &t.filename, &chunks[0..read]);
+        if pipeline.batch().is_full() {
+            send_batch(t, pipeline, socket, metrics, bandwidth, session_bandwidth.as_deref_mut())?;
+        }
+        'repairs: for (block, block_repairs) in repairs.drain(..) {
+            let repair_count = block_repairs.len() as u64;
+            for (r, repair) in block_repairs.iter().enumerate() {
+                if limiter != 0 && t.packets_sent + pipeline.batch().len() as u64 >= limiter {
+                    last_batch = true;
+                    break 'repairs;
                 }
-                //Send the packet, this will loop and another will be sent
-                match socket.send_to(&packet_buffer[0..byte_counter],t.target)
-                {
-                    Ok(_) => {
-                        sent_counter += 1;
-                        if limiter != 0 && sent_counter >= limiter {
-                            //println!("Sent {:?} packets",sent_counter);
-                            return Ok(())
-                        }
-                    },
-                    Err(e) => {
-                        println!("Unable to send data to {:?}.  Error:{:?}",t.target,e);
-                        return Err(e)
-                    }
+                let bytes_to_send = fec::repair_packet(block, r as u64, repair_count, repair, pipeline.batch().next());
+                pipeline.batch().push(bytes_to_send);
+                if pipeline.batch().is_full() {
+                    send_batch(t, pipeline, socket, metrics, bandwidth, session_bandwidth.as_deref_mut())?;
                 }
+                metrics.repair_sent();
+                repairs_sent += 1;
             }
         }
     }
-
-    //println!("Sent {:?} packets",sent_counter);
+    send_batch(t, pipeline, socket, metrics, bandwidth, session_bandwidth)?;
+    metrics.suppressed(suppressed);
+    if limiter != 0 && t.packets_sent >= limiter {
+        debug!(peer:% = t.target, filename:% = t.filename, ranges:? = RangeList(t), packets = t.packets_sent, bytes = t.bytes_sent, suppressed = suppressed, repairs = repairs_sent, compressed = compressed; "Hit the packets per request limit");
+        return Ok(())
+    }
+    debug!(peer:% = t.target, filename:% = t.filename, ranges:? = RangeList(t), packets = t.packets_sent, bytes = t.bytes_sent, suppressed = suppressed, lost = t.lost, repairs = repairs_sent, compressed = compressed; "Sent chunks");
     Ok(())
 }
 
-/// This function sets up a nonblocking UDP server on a provided address serving files on the provided whitelist
-/// this is also thread friendly!  Since we're talking UDP, multiple threads can work with the same socket no biggie
-pub fn serve(bind_address: &String, whitelist_filename: &String) -> std::io::Result<()> {
-    let mut whitelist: HashSet<String> = HashSet::new();
-    match File::open(whitelist_filename) {
-        Ok(f) => {
-            let reader = io::BufReader::new(f);
-            for line in reader.lines() {
-                if let Ok(item) = line {
-                    whitelist.insert(item);
-                }
-            }
-        },
-        Err(_) => {
+///Send the batch pipeline is filling for t, if there is anything in it
+fn send_batch(t: &mut ChunkTransaction, pipeline: &mut Pipeline, socket: &UdpSocket, metrics: &Metrics, bandwidth: &mut TokenBucket, session_bandwidth: Option<&mut TokenBucket>) -> std::io::Result<()> {
+    if pipeline.batch().is_empty() {
+        return Ok(());
+    }
+    let packets = pipeline.batch().len() as u64;
+    let sent = pipeline.send(socket, t.target, metrics, bandwidth, session_bandwidth)?;
+    t.packets_sent += packets;
+    t.bytes_sent += sent as u64;
+    Ok(())
+}
 
+///Read chunk of file into buffer, which holds a whole chunk, without moving the file's position
+///Returns how many bytes were read, fewer than a chunk only for the last one and 0 past the end
+pub(crate) fn read_chunk(file: &File, chunk: u64, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let offset = chunk*(buffer.len() as u64);
+    let mut filled = 0;
+    while filled < buffer.len() {
+        #[cfg(unix)]
+        let bytes_read = std::os::unix::fs::FileExt::read_at(file, &mut buffer[filled..], offset + filled as u64)?;
+        #[cfg(windows)]
+        let bytes_read = std::os::windows::fs::FileExt::seek_read(file, &mut buffer[filled..], offset + filled as u64)?;
+        #[cfg(not(any(unix, windows)))]
+        let bytes_read = {
+            use std::io::{Read, Seek, SeekFrom};
+            let mut reader = file;
+            reader.seek(SeekFrom::Start(offset + filled as u64))?;
+            reader.read(&mut buffer[filled..])?
+        };
+        if bytes_read == 0 {
+            break;
         }
+        filled += bytes_read;
     }
+    Ok(filled)
+}
 
-    let mut transactions: VecDeque<ChunkTransaction> = VecDeque::new();
-    let mut server_socket: UdpSocket;
-    match UdpSocket::bind(bind_address)
-    {
-        Ok(s) => server_socket = s,
-        Err(e) => {
-            println!("Unable to bind a UDP socket {:?}. Error:{:?}",bind_address,e);
-            return Err(e);
+///Send the hashes of every chunk of file t asks for, as many to a packet as fit, paced like chunks
+///They are the leaves of the file's chunk hash tree, so a file is only read whole the first time they are asked for
+///A packet only holds consecutive chunks, so every range starts a new one
+fn send_chunk_hashes(t: &mut ChunkTransaction, path: &Path, file: &mut File, socket: &UdpSocket, state: &ServerState, bandwidth: &mut TokenBucket, mut session_bandwidth: Option<&mut TokenBucket>) -> std::io::Result<()> {
+    let ServerState { config, metrics, files, .. } = state;
+    let tree = match files.tree(path, file)? {
+        Some(tree) => tree,
+        None => {
+            debug!(peer:% = t.target, filename:% = t.filename; "No chunk hashes to send");
+            return Ok(());
         }
-    }
-    match server_socket.set_nonblocking(true)
-    {
-        Ok(_) => {},
-        Err(_) => {
-            println!("Unable to set nonblocking. Performance will be terrible");
+    };
+    let leaves = tree.leaves();
+    let limiter = config.limits.packets_per_request;
+    let per_packet = delta::hashes_per_packet(config.packet_size);
+    let mut send_buffer: Vec<u8> = vec![0; config.packet_size];
+    let mut hashed: u64 = 0;
+    for (s, e) in t.starts.iter().zip(t.ends.iter()) {
+        let mut chunk = *s;
+        while chunk <= *e {
+            let first = chunk;
+            let mut count: usize = 0;
+            while count < per_packet && chunk <= *e {
+                //Past the end of the file, nothing more to hash for this range
+                let leaf = match usize::try_from(chunk).ok().and_then(|c| leaves.get(c)) {
+                    Some(l) => l,
+                    None => break,
+                };
+                delta::hash_slot(count, &mut send_buffer).copy_from_slice(leaf);
+                count += 1;
+                chunk += 1;
+            }
+            if count == 0 {
+                break;
+            }
+            let bytes_to_send = delta::hashes_packet(first, count, &mut send_buffer);
+            let sent = send_paced(socket, t.target, &send_buffer[0..bytes_to_send], metrics, bandwidth, session_bandwidth.as_deref_mut())?;
+            t.packets_sent += 1;
+            t.bytes_sent += sent as u64;
+            hashed += count as u64;
+            if limiter != 0 && t.packets_sent >= limiter {
+                debug!(peer:% = t.target, filename:% = t.filename, ranges:? = RangeList(t), packets = t.packets_sent, hashes = hashed; "Hit the packets per request limit");
+                return Ok(())
+            }
+            //A short packet means the range or the file ended
+            if count < per_packet {
+                break;
+            }
         }
     }
+    debug!(peer:% = t.target, filename:% = t.filename, ranges:? = RangeList(t), packets = t.packets_sent, bytes = t.bytes_sent, hashes = hashed; "Sent chunk hashes");
+    Ok(())
+}
 
-    let mut buffer = [0; PACKET_SIZE]; //Need a buffer that can hold our maximum packet size
+///Send the Merkle tree nodes t asks for by ID, as many to a packet as fit, paced like chunks
+///Nodes of a level are numbered without gaps, so the first ID the tree doesn't have ends a range
+fn send_tree_nodes(t: &mut ChunkTransaction, path: &Path, file: &mut File, socket: &UdpSocket, state: &ServerState, bandwidth: &mut TokenBucket, mut session_bandwidth: Option<&mut TokenBucket>) -> std::io::Result<()> {
+    let ServerState { config, metrics, files, .. } = state;
+    //The caller checked the file is still the version t asked about
+    let tree = if config.merkle.enabled { files.tree(path, file)? } else { None };
+    let tree = match tree {
+        Some(tree) => tree,
+        None => {
+            debug!(peer:% = t.target, filename:% = t.filename; "No Merkle tree to send nodes of");
+            return Ok(());
+        }
+    };
+    let limiter = config.limits.packets_per_request;
+    let per_packet = merkle::nodes_per_packet(config.packet_size);
+    let mut send_buffer: Vec<u8> = vec![0; config.packet_size];
+    let ranges: Vec<(u64, u64)> = t.starts.iter().cloned().zip(t.ends.iter().cloned()).collect();
+    let mut ids = ranges.iter().flat_map(|(s, e)| (*s..=*e).map_while(|id| tree.node(id).map(|hash| (id, *hash))));
+    let mut nodes: Vec<(u64, [u8; delta::HASH_LEN])> = Vec::with_capacity(per_packet);
+    let mut sent_nodes: u64 = 0;
     loop {
-        //Handle received packets
-        match server_socket.recv_from(&mut buffer) {
-            Ok((bytes_received, address)) => {
-                server_handle_inbound(
-                    bytes_received,
-                    address,
-                    &mut transactions,
-                    &buffer[0..bytes_received],
-                );
-            }
-            Err(e) => match e.kind() {
-                io::ErrorKind::WouldBlock => continue,
-                _ => return Err(e),
-            },
+        nodes.clear();
+        nodes.extend(ids.by_ref().take(per_packet));
+        if nodes.is_empty() {
+            break;
         }
-
-        //And service the transaction queue
-        for mut t in transactions.iter_mut() {
-            match server_service_transaction(&mut t, &mut server_socket, &mut whitelist, 0) {
-                Ok(_) => {},
-                Err(_) => println!("Error sending chunks for {:?}", t.filename),
-            }
+        let bytes_to_send = merkle::nodes_packet(&nodes, &mut send_buffer);
+        let sent = send_paced(socket, t.target, &send_buffer[0..bytes_to_send], metrics, bandwidth, session_bandwidth.as_deref_mut())?;
+        t.packets_sent += 1;
+        t.bytes_sent += sent as u64;
+        sent_nodes += nodes.len() as u64;
+        if limiter != 0 && t.packets_sent >= limiter {
+            debug!(peer:% = t.target, filename:% = t.filename, ranges:? = RangeList(t), packets = t.packets_sent, nodes = sent_nodes; "Hit the packets per request limit");
+            return Ok(())
         }
-        transactions.clear();
     }
+    debug!(peer:% = t.target, filename:% = t.filename, ranges:? = RangeList(t), packets = t.packets_sent, bytes = t.bytes_sent, nodes = sent_nodes; "Sent Merkle tree nodes");
+    Ok(())
 }
 
-/// Request a file by requesting all of its chunks sequentially, limiting the amount of the file stored in RAM at any moment
-pub fn client_request_sequential_limited(target: &String, filename: &String, outfilename: &String, chunk_mem_limit: usize) -> std::io::Result<()> {
-    let server_socket: UdpSocket; //Create the socket using provided params
-    let mut send_buffer: [u8; PACKET_SIZE] = [0; PACKET_SIZE];
-    let mut recv_buffer: [u8; PACKET_SIZE] = [0; PACKET_SIZE];
-    let mut outfile = File::create(outfilename)?;
+///Merkle tree of file, opened from path and version epoch, from the cache or built now
+///None if Merkle trees are off, the file is empty or it isn't version epoch any more
+fn merkle_tree(path: &Path, file: &mut File, epoch: u64, state: &ServerState) -> std::io::Result<Option<Arc<merkle::Tree>>> {
+    if !state.config.merkle.enabled || epoch == 0 || file_epoch(&file.metadata()?) != epoch {
+        return Ok(None);
+    }
+    state.files.tree(path, file)
+}
 
-    //Bind our socket locally to any available port, this is an outbound request
-    match UdpSocket::bind("0.0.0.0:0")
-    {
-        Ok(s) => server_socket = s,
-        Err(e) => {
-            println!("Unable to bind a UDP socket. Error:{:?}",e);
-            return Err(e);
-        }
+///Send one packet of a chunk stream to target once the bandwidth limits allow it, returns how many bytes were sent
+pub(crate) fn send_paced(socket: &UdpSocket, target: std::net::SocketAddr, packet: &[u8], metrics: &Metrics, bandwidth: &mut TokenBucket, session_bandwidth: Option<&mut TokenBucket>) -> std::io::Result<usize> {
+    if let Some(session_bandwidth) = session_bandwidth {
+        metrics.throttled(session_bandwidth.take(packet.len() as u64));
     }
-    //Set to nonblocking
-    match server_socket.set_nonblocking(true)
+    metrics.throttled(bandwidth.take(packet.len() as u64));
+    match socket.send_to(packet, target)
     {
-        Ok(_) => {},
+        Ok(sent) => {
+            metrics.sent(sent);
+            Ok(sent)
+        },
         Err(e) => {
-            println!("Unable to set nonblocking, error: {:?}",e);
-            return Err(e)
+            warn!(peer:% = target, error:% = e; "Unable to send data");
+            Err(e)
         }
     }
+}
 
+///Everything the workers of a server share
+pub struct ServerState {
+    config: ServerConfig,
+    whitelist: Whitelist,
+    uploads: UploadManager,
+    metrics: Arc<Metrics>,
+    access_log: AccessLog,
+    sessions: SessionManager,
+    duplicates: DuplicateFilter,
+    files: FileCache,
+    clients: ClientLimiter,
+    last_sweep: Mutex<Instant>,
+}
 
-    //GOOD, this method handles repeating requests in a reasonable timeframe
-    match client_request_metadata(&server_socket, &mut send_buffer, &mut recv_buffer, &target, &filename) {
-        Ok(_) => {
-            //We're good to go
-        },
-        Err(e) => {
-            println!("Unable to request metadata");
+impl ServerState {
+    ///Load the whitelist and set up everything else config asks for
+    pub fn new(config: &ServerConfig) -> std::io::Result<Self> {
+        let mut whitelist = match Whitelist::from_file(&config.whitelist, &config.root) {
+            Ok(w) => w,
+            Err(e) => {
+                error!(path:? = config.whitelist, error:% = e; "Unable to load whitelist");
+                return Err(e);
+            }
+        };
+        whitelist.set_deny_hidden(config.security.deny_hidden);
+        Ok(Self {
+            config: config.clone(),
+            whitelist,
+            uploads: UploadManager::new(&config.upload, config.packet_size, config.security.max_filename_len),
+            metrics: Arc::new(Metrics::new(config.limits.bytes_per_second)),
+            access_log: AccessLog::new(Duration::from_secs(config.logging.access_coalesce_seconds)),
+            sessions: SessionManager::new(&config.sessions),
+            duplicates: DuplicateFilter::new(Duration::from_millis(config.limits.duplicate_window_ms)),
+            files: FileCache::new(&config.cache, config.packet_size - mem::size_of::<u64>()),
+            clients: ClientLimiter::new(config.limits.requests_per_second),
+            last_sweep: Mutex::new(Instant::now()),
+        })
+    }
+
+    ///Let go of finished transfers, idle sessions and chunks past the duplicate window
+    ///At most once a second, cheap enough to call on every pass of a server loop
+    fn sweep(&self) {
+        //Only one worker needs to sweep, the others carry on
+        let mut last_sweep = match self.last_sweep.try_lock() {
+            Ok(l) => l,
+            Err(_) => return,
+        };
+        if last_sweep.elapsed() < Duration::from_secs(1) {
+            return;
+        }
+        *last_sweep = Instant::now();
+        self.access_log.sweep();
+        self.sessions.sweep();
+        self.duplicates.sweep();
+    }
+}
+
+/// This function sets up nonblocking UDP sockets on the configured addresses serving files on the configured whitelist
+/// this is also thread friendly!  Since we're talking UDP, multiple threads can work with the same socket no biggie
+pub fn serve(config: &ServerConfig) -> std::io::Result<()> {
+    let state = Arc::new(ServerState::new(config)?);
+    //Multicast files are repaired with ordinary requests, so they have to be requestable
+    for multicast in config.multicast.iter() {
+        let path = match state.whitelist.check(&multicast.file) {
+            Some(p) => p,
+            None => {
+                error!(file:% = multicast.file; "Multicast file is not on the whitelist");
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("multicast file {} is not on the whitelist", multicast.file)));
+            }
+        };
+        multicast::spawn_sender(multicast.clone(), path, Arc::clone(&state))?;
+    }
+    #[cfg(feature = "metrics")]
+    if let Some(listen) = &config.metrics.listen {
+        if let Err(e) = metrics::spawn_endpoint(listen, Arc::clone(&state.metrics)) {
+            error!(address:% = listen, error:% = e; "Unable to serve metrics");
             return Err(e);
         }
     }
 
-    
-    //We don't care about the ID field of the returned metadata packet yet TODO
-    let chunk_count = unpack_u8arr_into_u64(&recv_buffer[8..16]); //Metadata requests pass back the chunk count as a u64 in bytes 8-16
-    println!("Chunks count {:?}",chunk_count);
-    if chunk_count == 0 {
-        println!("Either the requested file was empty or not on the whitelist of requestable files");
-        return Ok(())
+    let mut sockets: Vec<UdpSocket> = Vec::new();
+    for bind_address in config.bind.iter() {
+        match UdpSocket::bind(bind_address)
+        {
+            Ok(s) => sockets.push(s),
+            Err(e) => {
+                error!(address:% = bind_address, error:% = e; "Unable to bind a UDP socket");
+                return Err(e);
+            }
+        }
+    }
+    for server_socket in sockets.iter() {
+        match server_socket.set_nonblocking(true)
+        {
+            Ok(_) => {},
+            Err(e) => {
+                warn!(error:% = e; "Unable to set nonblocking. Performance will be terrible");
+            }
+        }
     }
 
-    let mut chunk_vector: Vec<Vec<u8>> = Vec::with_capacity(chunk_mem_limit as usize); //Vector used to buffer chunks to be written into the output file
-    for _ in 0..chunk_mem_limit {
-        chunk_vector.push(Vec::new());
+    info!(bind:? = config.bind, root:? = config.root, workers = config.workers; "Serving");
+
+    //Every extra worker gets its own handles to the same sockets
+    let mut workers = Vec::new();
+    for _ in 1..config.workers {
+        let mut worker_sockets: Vec<UdpSocket> = Vec::new();
+        for server_socket in sockets.iter() {
+            worker_sockets.push(server_socket.try_clone()?);
+        }
+        let state = Arc::clone(&state);
+        workers.push(thread::spawn(move || serve_worker(&worker_sockets, &state)));
     }
-    let mut progress = 0; //Track how many chunks of chunks we have pulled
-    let mut part_start: u64 = progress*chunk_mem_limit as u64;
-    let mut part_end: u64;
-    if (part_start+chunk_mem_limit as u64) < chunk_count {
-        part_end = part_start+(chunk_mem_limit-1) as u64;
-    } else {
-        part_end = chunk_count-1;
+
+    let result = serve_worker(&sockets, &state);
+    for worker in workers {
+        match worker.join() {
+            Ok(Err(e)) => error!(error:% = e; "Worker stopped with an error"),
+            Ok(Ok(_)) => {},
+            Err(_) => error!("Worker panicked"),
+        }
     }
+    state.access_log.flush();
+    result
+}
 
-    let mut rt: RangeTree = RangeTree::new(part_start as usize,part_end as usize);
-    
-    let mut counter: Instant = Instant::now(); //Counter used to track how long it has been since we heard anything from the server
-    let mut next: bool = true; //Boolean used to indicate that regardless of the counter, it's time to request a new packet
-    //So begins the loop, request from progress*memlimit through the lesser of progress+memlimit or chunkcount
+///Receive and service requests on a set of sockets until an error happens
+fn serve_worker(sockets: &[UdpSocket], state: &ServerState) -> std::io::Result<()> {
+    let ServerState { config, uploads, metrics, access_log, clients, .. } = state;
+    let mut transactions: VecDeque<ChunkTransaction> = VecDeque::new();
+    //The bandwidth cap is for the whole server, split it between the workers
+    let mut bandwidth = TokenBucket::new(config.limits.bytes_per_second/(config.workers as u64));
+
+    let mut buffer = vec![0; config.packet_size]; //Need a buffer that can hold our maximum packet size
+    let mut reply_buffer = vec![0; config.packet_size];
+    //Requests that came in together are taken off a socket together
+    let mut receivers: Vec<Receiver> = sockets.iter().map(|_| Receiver::new(config.packet_size)).collect();
+    let mut pipeline = Pipeline::new(config.packet_size);
     loop {
-        //Check a timer and flag to decide if we need to send a request
-        match Instant::now().checked_duration_since(counter) {
-            Some(diff) => {
-                //If we have gone 100 milliseconds without receiving anything, request something
-                if next || diff > Duration::from_millis(200) {
-                    let mut s: Vec<u64> = Vec::new();
-                    let mut e: Vec<u64> = Vec::new();
-                    
-                    for xint in rt.intervals.iter() {
-                        s.push(rt.tree_vec[*xint].start as u64);
-                        e.push(rt.tree_vec[*xint].end as u64);
+        state.sweep();
+        for (server_socket, receiver) in sockets.iter().zip(receivers.iter_mut()) {
+            //Handle received packets
+            match receiver.recv_from(server_socket, &mut buffer) {
+                Ok((bytes_received, address)) => {
+                    if bytes_received < 8 {
+                        metrics.dropped(DropReason::Malformed);
+                        continue;
+                    }
+                    if !config.security.client_allowed(address.ip()) {
+                        metrics.dropped(DropReason::ClientDenied);
+                        continue;
+                    }
+                    //Upload chunks come in bursts, everything else counts against the request limit
+                    let id = unpack_u8arr_into_u64(&buffer[0..8]);
+                    if id != upload::UPLOAD_CHUNK && !clients.allow(address.ip()) {
+                        metrics.dropped(DropReason::RateLimited);
+                        continue;
                     }
-                    let bytes_to_send = range_chunk_request_packet(filename,s,e,&mut send_buffer);
-                    match server_socket.send_to(&send_buffer[0..bytes_to_send], target)
-                    {
-                        Ok(_) => {
-                            if next {
-                                //println!("Sent a packet due to nexting");
-                            } else {
-                                //println!("Sent a packet due to timeout");
+                    //Upload packets carry their own state and are answered right away
+                    if id == upload::UPLOAD_ANNOUNCE || id == upload::UPLOAD_CHUNK || id == upload::UPLOAD_STATUS {
+                        metrics.upload_request();
+                        let reply_bytes = uploads.handle_packet(id, &buffer[8..bytes_received], address, &mut reply_buffer);
+                        if reply_bytes > 0 {
+                            match server_socket.send_to(&reply_buffer[0..reply_bytes], address) {
+                                Ok(sent) => metrics.sent(sent),
+                                Err(e) => {
+                                    metrics.error();
+                                    warn!(peer:% = address, error:% = e; "Unable to send data");
+                                }
                             }
-                            
-                            counter = Instant::now();
-                        },
-                        Err(e) => {
-                            println!("Unable to send data to {:?}.  Error: {:?}",target, e);
-                            return Err(e)
                         }
+                        continue;
                     }
-                    next = false;
-                }
-            },
-            None => {
-                counter = Instant::now();
-            }
-        }
-
-        match server_socket.recv(&mut recv_buffer)
-        {
-            //We either get the next packet, miss a packet, or a latecomer arrives
-            Ok(br) => {
-                let mut chunkdex = unpack_u8arr_into_u64(&recv_buffer[0..8]);
-                rt.add_packet(chunkdex as usize);
-                //println!("Got a chunk: {:?}", chunkdex);
-                if chunkdex >= part_start {
-                    chunkdex -= part_start;
-                    //println!("And now chunkdex is this {:?}",chunkdex);
-                    //Nailed it, got a chunk
-                    counter = Instant::now();
-                    for byte in &recv_buffer[8..br] {
-                        chunk_vector[chunkdex as usize].push(*byte);
+                    let queued = transactions.len();
+                    server_handle_inbound(
+                        bytes_received,
+                        address,
+                        &mut transactions,
+                        &buffer[0..bytes_received],
+                    );
+                    if transactions.len() == queued {
+                        metrics.dropped(DropReason::Malformed);
                     }
-                    
                 }
-                else { }
-            },
-            Err(err) => match err.kind() {
-                io::ErrorKind::WouldBlock => {},
-                _ => return Err(err),
+                Err(e) => match e.kind() {
+                    io::ErrorKind::WouldBlock => continue,
+                    _ => return Err(e),
+                },
             }
-        }
 
-        if rt.intervals.len() == 0{
-            //We're done with this bit, increment progress and move on
-            //Increment progress and proceed
-            for chunk in chunk_vector.iter() {
-                outfile.write_all(&chunk[..])?;
-            }
-            progress+=1;
-
-            if part_end == chunk_count-1 {
-                //We're done!
-                break;
-            } else {
-                //Reinitialize all of our data structures
-                //Set the new start and end
-                part_start = progress*chunk_mem_limit as u64;
-                if (part_start+chunk_mem_limit as u64) < chunk_count {
-                    part_end = part_start+ (chunk_mem_limit-1) as u64;
-                } else {
-                    part_end = chunk_count-1;
-                }
-                //Reinitialize the chunk vector
-                for i in 0..chunk_mem_limit {
-                    chunk_vector[i].clear();
+            //And service the transaction queue
+            for t in transactions.iter_mut() {
+                let started = Instant::now();
+                metrics.start_transaction();
+                let result = server_service_transaction(t, server_socket, state, &mut pipeline, &mut bandwidth);
+                metrics.finish_transaction(started.elapsed());
+                access_log.record(t, result.is_err());
+                if let Err(e) = result {
+                    metrics.error();
+                    warn!(peer:% = t.target, filename:% = t.filename, error:% = e; "Error servicing request");
                 }
-                next = true;
-                
-                rt.reinit(part_start as usize, part_end as usize);
             }
+            transactions.clear();
         }
     }
-
-    Ok(())
 }
 
 ///Populates a given send buffer with the necessary fields to request metadata for file with name fname, returns how many bytes are in the packet
-pub fn metadata_request_packet(fname: &String, buffer: &mut[u8; PACKET_SIZE]) -> usize {
+///flags is COMPRESS_REQUEST to take the pre-compressed variant of the file if there is one
+///Flags go after the name, where servers that don't know them ignore them, and are left out when there are none
+pub fn metadata_request_packet(fname: &str, flags: u64, buffer: &mut [u8]) -> io::Result<usize> {
     let mut byte_counter: usize = 0;
     //Request metadata, ID field of 0
     for byte in pack_u64_into_u8arr(0).iter(){
@@ -451,32 +1157,32 @@ pub fn metadata_request_packet(fname: &String, buffer: &mut[u8; PACKET_SIZE]) ->
         byte_counter+=1;
     }
 
-    buffer[byte_counter] = fname.len() as u8;
-    byte_counter+=1;
-
-    for byte in fname.bytes(){
-        buffer[byte_counter] = byte;
-        byte_counter+=1;
+    byte_counter += pack_name(fname, buffer, byte_counter, if flags != 0 { 8 } else { 0 })?;
+    if flags != 0 {
+        buffer[byte_counter..byte_counter+8].copy_from_slice(&pack_u64_into_u8arr(flags));
+        byte_counter += 8;
     }
-    
-    byte_counter
+
+    Ok(byte_counter)
 }
 
-pub fn metadata_response_packet(filename: &String, buffer: &mut[u8;PACKET_SIZE]) -> usize {
+///Populates a buffer with the metadata response for a file with metadata, the chunk count, chunk size, epoch and features, returns how many bytes are in the packet
+///A file without metadata is described as empty
+pub fn metadata_response_packet(metadata: Option<&fs::Metadata>, chunk_size: u64, features: u64, buffer: &mut [u8]) -> usize {
     let filesize: u64;
-    match fs::metadata(filename) {
-        Ok(m) => {
-            if m.len() % (BUFFER_SIZE as u64) == 0{
-                filesize = m.len()/(BUFFER_SIZE as u64);
+    let epoch: u64;
+    match metadata {
+        Some(m) => {
+            if m.len() % chunk_size == 0{
+                filesize = m.len()/chunk_size;
             } else {
-                filesize = 1+m.len()/(BUFFER_SIZE as u64);
+                filesize = 1+m.len()/chunk_size;
             }
-            
-            println!("File {:?} found!",filename)
+            epoch = file_epoch(m);
         }
-        Err(_) => {
+        None => {
             filesize = 0;
-            println!("File {:?} not found",filename)
+            epoch = 0;
         }
     }
 
@@ -494,57 +1200,122 @@ pub fn metadata_response_packet(filename: &String, buffer: &mut[u8;PACKET_SIZE])
         byte_counter+=1;
     }
 
+    //Clients check the chunk size so a packet size mismatch fails loudly instead of truncating chunks
+    for byte in pack_u64_into_u8arr(chunk_size).iter(){
+        buffer[byte_counter] = *byte;
+        byte_counter+=1;
+    }
+
+    //Clients echo the epoch in their chunk requests so a changed file is noticed
+    for byte in pack_u64_into_u8arr(epoch).iter(){
+        buffer[byte_counter] = *byte;
+        byte_counter+=1;
+    }
+
+    //What else the client may use when asking for the file
+    for byte in pack_u64_into_u8arr(features).iter(){
+        buffer[byte_counter] = *byte;
+        byte_counter+=1;
+    }
+
     byte_counter
 }
 
-pub fn client_request_metadata(server_socket: &UdpSocket ,send_buffer: &mut[u8;PACKET_SIZE], recv_buffer: &mut[u8;PACKET_SIZE],target: &String, filename: &String) -> std::io::Result<()> {
-    //Send a metadata request until we have a confirmed response or an error
-    //Request metadata
-    let bytes_to_send = metadata_request_packet(filename, send_buffer);
-    match server_socket.send_to(&send_buffer[0..bytes_to_send], target)
-    {
-        Ok(_) => {},
-        Err(e) => {
-            println!("Unable to send data to {:?}.  Error: {:?}",target, e);
-            return Err(e)
+///Features of a server with config, FEATURES, FEC if repair packets are sent and the compression algorithm if there is one
+///Responses carry FEC params exactly when FEATURE_FEC is set, so what comes after them can be found
+pub fn features(config: &ServerConfig) -> u64 {
+    let fec = if config.fec.repair_chunks != 0 { FEATURE_FEC } else { 0 };
+    FEATURES | fec | compress::feature(&config.compression)
+}
+
+///Version of a file, changes whenever the file is modified or replaced, never 0
+///Made from the size, modification time and on unix the device and inode
+pub fn file_epoch(metadata: &fs::Metadata) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update(pack_u64_into_u8arr(metadata.len()));
+    if let Ok(modified) = metadata.modified() {
+        if let Ok(since_epoch) = modified.duration_since(std::time::UNIX_EPOCH) {
+            hasher.update(since_epoch.as_nanos().to_be_bytes());
         }
     }
-
-    let mut counter: Instant = Instant::now();
-    //Receive metadata
-    loop
+    #[cfg(unix)]
     {
-        match server_socket.recv(&mut recv_buffer[..])
-        {
-            Ok(_) => { return Ok(()) },
-            Err(e) => match e.kind() {
-                io::ErrorKind::WouldBlock => { },
-                _ => return Err(e),
-            }
+        use std::os::unix::fs::MetadataExt;
+        hasher.update(pack_u64_into_u8arr(metadata.dev()));
+        hasher.update(pack_u64_into_u8arr(metadata.ino()));
+    }
+    unpack_u8arr_into_u64(&hasher.finalize()[0..8]).max(1)
+}
+
+///Populates a buffer with the control packet telling a client its file changed, returns how many bytes are in the packet
+pub fn file_changed_packet(buffer: &mut [u8]) -> usize {
+    buffer[0..8].copy_from_slice(&pack_u64_into_u8arr(CONTROL_CHUNK));
+    buffer[8..16].copy_from_slice(&pack_u64_into_u8arr(FILE_CHANGED));
+    16
+}
+
+///Populates a given send buffer with a request for page of the listing of directory, returns how many bytes are in the packet
+pub fn list_request_packet(directory: &str, page: u64, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut byte_counter: usize = 0;
+    //Request a listing, ID field of 2
+    for byte in pack_u64_into_u8arr(2).iter(){
+        buffer[byte_counter] = *byte;
+        byte_counter+=1;
+    }
+
+    byte_counter += pack_name(directory, buffer, byte_counter, mem::size_of::<u64>())?;
+
+    for byte in pack_u64_into_u8arr(page).iter(){
+        buffer[byte_counter] = *byte;
+        byte_counter+=1;
+    }
+
+    Ok(byte_counter)
+}
+
+///Populates a buffer with one page of a file listing, returns how many bytes are in the packet
+///The packet is the u64 page, the u64 page count and then as many varint length prefixed names as fit
+pub fn list_response_packet(files: &[String], page: u64, buffer: &mut [u8]) -> usize {
+    //Split the names up into pages that fit in a packet, names that could never fit are skipped
+    let capacity = buffer.len() - 2*mem::size_of::<u64>();
+    let mut pages: Vec<&[String]> = Vec::new();
+    let mut page_start = 0;
+    let mut page_bytes = 0;
+    let packed_len = |name: &String| varint_len(name.len() as u64)+name.len();
+    let listable: Vec<String> = files.iter().filter(|f| packed_len(f) <= capacity).cloned().collect();
+    for (i, name) in listable.iter().enumerate() {
+        if page_bytes+packed_len(name) > capacity {
+            pages.push(&listable[page_start..i]);
+            page_start = i;
+            page_bytes = 0;
         }
-        match Instant::now().checked_duration_since(counter) {
-            Some(diff) => {
-                if diff > Duration::from_millis(100) {
-                    match server_socket.send_to(&send_buffer[0..bytes_to_send], target)
-                    {
-                        Ok(_) => {},
-                        Err(e) => {
-                            println!("Unable to send data to {:?}.  Error: {:?}",target, e);
-                            return Err(e)
-                        }
-                    }
-                }
-            },
-            None => {
-                counter = Instant::now();
+        page_bytes += packed_len(name);
+    }
+    pages.push(&listable[page_start..]);
+
+    let mut byte_counter: usize = 0;
+    for byte in pack_u64_into_u8arr(page).iter().chain(pack_u64_into_u8arr(pages.len() as u64).iter()){
+        buffer[byte_counter] = *byte;
+        byte_counter+=1;
+    }
+    if let Some(names) = pages.get(page as usize) {
+        for name in names.iter() {
+            byte_counter += pack_varint(name.len() as u64, &mut buffer[byte_counter..]);
+            for byte in name.bytes(){
+                buffer[byte_counter] = byte;
+                byte_counter+=1;
             }
         }
     }
+
+    byte_counter
 }
 
 
 /// Populates a given send buffer with the necessary field to request chunks of a file with name fname, returns how many bytes are in the packet
-pub fn range_chunk_request_packet(fname: &String, starts: Vec<u64>, ends: Vec<u64>, send_buffer: &mut[u8; PACKET_SIZE]) -> usize {
+///epoch comes from the metadata response, the server refuses to serve a different version of the file, 0 takes whatever version is there
+///Fails if the name is too long to leave room for at least one range
+pub fn range_chunk_request_packet(fname: &str, epoch: u64, starts: Vec<u64>, ends: Vec<u64>, send_buffer: &mut [u8]) -> io::Result<usize> {
     //Variable used in byte packing and overall size determination
     let mut byte_counter = 0;
     //Request a set of chunks (ID of 1)
@@ -553,25 +1324,18 @@ pub fn range_chunk_request_packet(fname: &String, starts: Vec<u64>, ends: Vec<u6
         byte_counter+=1;
     }
 
-    //Length of file name
-    send_buffer[byte_counter] = fname.len() as u8;
-    byte_counter+=1;
-
-    //The actual filename
-    for byte in fname.bytes(){
-        send_buffer[byte_counter] = byte;
-        byte_counter+=1;
-    }
+    //Length of file name and the actual filename, leaving room for the range count, one range and the epoch
+    byte_counter += pack_name(fname, send_buffer, byte_counter, 4*mem::size_of::<u64>())?;
 
     //How many chunk ranges?  The min of how many fit and how many are requested
+    //Leaving room for the range count and the epoch
     let desired_chunks = starts.len() as u64;
-    let fittable_chunks = ((PACKET_SIZE - byte_counter)/(2*mem::size_of::<u64>())) as u64;
-    let actual_chunks: u64;
-    if desired_chunks < fittable_chunks {
-        actual_chunks = desired_chunks;
+    let fittable_chunks = ((send_buffer.len() - byte_counter - 2*mem::size_of::<u64>())/(2*mem::size_of::<u64>())) as u64;
+    let actual_chunks: u64 = if desired_chunks < fittable_chunks {
+        desired_chunks
     } else {
-        actual_chunks = fittable_chunks;
-    }
+        fittable_chunks
+    };
     
     for byte in &pack_u64_into_u8arr(actual_chunks){
         send_buffer[byte_counter] = *byte;
@@ -593,5 +1357,198 @@ pub fn range_chunk_request_packet(fname: &String, starts: Vec<u64>, ends: Vec<u6
         }
     }
 
-    byte_counter
-}
\ No newline at end of file
+    for byte in &pack_u64_into_u8arr(epoch){
+        send_buffer[byte_counter] = *byte;
+        byte_counter+=1;
+    }
+
+    Ok(byte_counter)
+}
+
+///Populates a buffer with a chunk request for as many of the ranges as fit, packed by pack_ranges, returns how many bytes are in the packet
+///Only for servers with FEATURE_COMPACT_RANGES, fails if the name is too long to leave room for a range
+///request picks whether the chunks are asked for, reported lost or asked for along with repair packets
+///flags are COMPRESS_REQUEST and VARIANT_REQUEST bits set on the ID
+pub fn compact_chunk_request_packet(fname: &str, epoch: u64, starts: &[u64], ends: &[u64], request: RangeRequest, flags: u64, buffer: &mut [u8]) -> io::Result<usize> {
+    let id = match request {
+        RangeRequest::Chunks => COMPACT_CHUNKS,
+        RangeRequest::Lost => NACK_CHUNKS,
+        RangeRequest::WithRepair => FEC_CHUNKS,
+        RangeRequest::Hashes => HASH_CHUNKS,
+        RangeRequest::Nodes => MERKLE_NODES,
+    };
+    buffer[0..8].copy_from_slice(&pack_u64_into_u8arr(id | flags));
+    let mut byte_counter = 8;
+    //Room for the epoch and a range with short gap and length
+    byte_counter += pack_name(fname, buffer, byte_counter, mem::size_of::<u64>()+4)?;
+    buffer[byte_counter..byte_counter+8].copy_from_slice(&pack_u64_into_u8arr(epoch));
+    byte_counter+=8;
+    byte_counter += pack_ranges(starts, ends, buffer, byte_counter);
+    Ok(byte_counter)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varint_round_trip() {
+        let mut buffer = [0u8; 10];
+        for val in [0, 1, 0x7f, 0x80, 300, 1 << 35, u64::MAX - 1, u64::MAX].iter() {
+            let len = pack_varint(*val, &mut buffer);
+            assert_eq!(len, varint_len(*val));
+            assert_eq!(unpack_varint(&buffer[0..len]), Some((*val, len)));
+        }
+        assert_eq!(pack_varint(u64::MAX, &mut buffer), 10);
+        assert_eq!(buffer[9], 0x01);
+    }
+
+    #[test]
+    fn varint_truncated() {
+        let mut buffer = [0u8; 10];
+        let len = pack_varint(300, &mut buffer);
+        assert_eq!(unpack_varint(&buffer[0..len-1]), None);
+        assert_eq!(unpack_varint(&[]), None);
+    }
+
+    #[test]
+    fn varint_overflow() {
+        //The 10th byte only has room for the top bit of a u64
+        let mut buffer = [0xffu8; 10];
+        buffer[9] = 0x01;
+        assert_eq!(unpack_varint(&buffer), Some((u64::MAX, 10)));
+        buffer[9] = 0x02;
+        assert_eq!(unpack_varint(&buffer), None);
+        //and no varint goes on past it
+        assert_eq!(unpack_varint(&[0x80; 11]), None);
+    }
+
+    #[test]
+    fn ranges_round_trip() {
+        let mut buffer = [0u8; 64];
+        let len = pack_ranges(&[50, 10, 0, 1000], &[50, 20, 5, 1 << 40], &mut buffer, 4);
+        assert_eq!(unpack_ranges(&buffer[4..4+len]), Some(vec![(0, 5), (10, 20), (50, 50), (1000, 1 << 40)]));
+    }
+
+    #[test]
+    fn ranges_trim_overlaps() {
+        let mut buffer = [0u8; 64];
+        //Backwards ranges are dropped, overlaps trimmed and ranges inside another one left out
+        let len = pack_ranges(&[0, 5, 3, 9, 15], &[10, 15, 4, 2, 15], &mut buffer, 0);
+        assert_eq!(unpack_ranges(&buffer[0..len]), Some(vec![(0, 10), (11, 15)]));
+    }
+
+    #[test]
+    fn ranges_fill_the_buffer() {
+        let mut buffer = [0u8; 5];
+        //Every range takes 2 bytes, only whole ones are packed
+        let len = pack_ranges(&[0, 10, 20], &[1, 11, 21], &mut buffer, 0);
+        assert_eq!(len, 4);
+        assert_eq!(unpack_ranges(&buffer[0..len]), Some(vec![(0, 1), (10, 11)]));
+    }
+
+    #[test]
+    fn ranges_at_the_largest_index() {
+        let mut buffer = [0u8; 64];
+        let len = pack_ranges(&[u64::MAX - 1, 5], &[u64::MAX, 6], &mut buffer, 0);
+        assert_eq!(unpack_ranges(&buffer[0..len]), Some(vec![(5, 6), (u64::MAX - 1, u64::MAX)]));
+    }
+
+    #[test]
+    fn ranges_truncated() {
+        let mut buffer = [0u8; 64];
+        let len = pack_ranges(&[0, 1000], &[5, 100000], &mut buffer, 0);
+        for cut in 1..len {
+            let unpacked = unpack_ranges(&buffer[0..cut]);
+            //Cutting between two ranges leaves the first, anywhere else is malformed
+            assert!(unpacked.is_none() || unpacked == Some(vec![(0, 5)]), "cut at {}", cut);
+        }
+        assert_eq!(unpack_ranges(&buffer[0..0]), Some(Vec::new()));
+    }
+
+    #[test]
+    fn ranges_past_the_largest_index() {
+        let mut buffer = [0u8; 32];
+        //A start past u64::MAX
+        let mut len = pack_varint(u64::MAX, &mut buffer);
+        len += pack_varint(0, &mut buffer[len..]);
+        assert_eq!(unpack_ranges(&buffer[0..len]), Some(vec![(u64::MAX, u64::MAX)]));
+        len += pack_varint(1, &mut buffer[len..]);
+        len += pack_varint(0, &mut buffer[len..]);
+        assert_eq!(unpack_ranges(&buffer[0..len]), None);
+        //and an end past it
+        let mut len = pack_varint(u64::MAX - 1, &mut buffer);
+        len += pack_varint(2, &mut buffer[len..]);
+        assert_eq!(unpack_ranges(&buffer[0..len]), None);
+    }
+
+    ///The transaction the server makes of a request packet
+    fn parse(packet: &[u8]) -> Option<ChunkTransaction> {
+        let mut transactions = VecDeque::new();
+        server_handle_inbound(packet.len(), "127.0.0.1:5000".parse().unwrap(), &mut transactions, packet);
+        transactions.pop_front()
+    }
+
+    fn ranges(t: &ChunkTransaction) -> Vec<(u64, u64)> {
+        t.starts.iter().cloned().zip(t.ends.iter().cloned()).collect()
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        let mut buffer = [0; 512];
+        let length = metadata_request_packet("dir/file.bin", COMPRESS_REQUEST, &mut buffer).unwrap();
+        let t = parse(&buffer[0..length]).unwrap();
+        assert!(matches!(t.kind, TransactionKind::Metadata));
+        assert_eq!(t.filename, "dir/file.bin");
+        assert!(t.compress);
+        let length = metadata_request_packet("file.bin", 0, &mut buffer).unwrap();
+        assert!(!parse(&buffer[0..length]).unwrap().compress);
+
+        let length = list_request_packet("dir", 3, &mut buffer).unwrap();
+        let t = parse(&buffer[0..length]).unwrap();
+        assert!(matches!(t.kind, TransactionKind::List(3)));
+        assert_eq!(t.filename, "dir");
+
+        let length = range_chunk_request_packet("file.bin", 42, vec![0, 10], vec![4, 20], &mut buffer).unwrap();
+        let t = parse(&buffer[0..length]).unwrap();
+        assert!(matches!(t.kind, TransactionKind::Chunks));
+        assert_eq!(ranges(&t), vec![(0, 4), (10, 20)]);
+        assert_eq!(t.epoch, 42);
+        //Older clients don't send the epoch, and range counts past the end of the packet are cut short
+        buffer[8+9..8+9+8].copy_from_slice(&pack_u64_into_u8arr(1000));
+        let t = parse(&buffer[0..length-8]).unwrap();
+        assert_eq!(ranges(&t), vec![(0, 4), (10, 20)]);
+        assert_eq!(t.epoch, 0);
+
+        for request in [RangeRequest::Chunks, RangeRequest::Lost, RangeRequest::WithRepair, RangeRequest::Hashes, RangeRequest::Nodes].iter() {
+            let length = compact_chunk_request_packet("file.bin", 42, &[10, 0], &[20, 4], *request, COMPRESS_REQUEST | VARIANT_REQUEST, &mut buffer).unwrap();
+            let t = parse(&buffer[0..length]).unwrap();
+            assert!(matches!(t.kind, TransactionKind::Chunks));
+            assert_eq!(ranges(&t), vec![(0, 4), (10, 20)]);
+            assert_eq!(t.epoch, 42);
+            assert!(t.compress && t.variant);
+            assert_eq!(range_request(unpack_u8arr_into_u64(&buffer[0..8]) & !(COMPRESS_REQUEST | VARIANT_REQUEST)), Some(*request));
+            assert_eq!((t.lost, t.repair, t.hashes, t.nodes), (
+                *request == RangeRequest::Lost, *request == RangeRequest::WithRepair, *request == RangeRequest::Hashes, *request == RangeRequest::Nodes));
+        }
+    }
+
+    #[test]
+    fn malformed_requests_are_dropped() {
+        let mut buffer = [0; 512];
+        let length = compact_chunk_request_packet("file.bin", 42, &[0], &[4], RangeRequest::Chunks, 0, &mut buffer).unwrap();
+        //Cut off in the name, the epoch and the ranges
+        for cut in [9, 12, 20].iter() {
+            assert!(parse(&buffer[0..*cut]).is_none(), "{}", cut);
+        }
+        buffer[length] = 0x80;
+        assert!(parse(&buffer[0..length+1]).is_none());
+        let length = list_request_packet("dir", 3, &mut buffer).unwrap();
+        assert!(parse(&buffer[0..length-1]).is_none());
+        buffer[0..8].copy_from_slice(&pack_u64_into_u8arr(19));
+        assert!(parse(&buffer[0..length]).is_none());
+        for id in [session::SESSION_CHUNKS, session::SESSION_COMPACT_CHUNKS, session::SESSION_CLOSE].iter() {
+            buffer[0..8].copy_from_slice(&pack_u64_into_u8arr(*id));
+            assert!(parse(&buffer[0..8+15]).is_none());
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use basic_udp::access_log::ACCESS_TARGET;
+use basic_udp::config::LogFormat;
+use log::kv::{Key, Value, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record};
+
+///Logger for the binary, writes one line per record to stderr
+///
+///Text lines look like
+///  2024-05-01T12:00:00.000Z INFO  Denied request peer=127.0.0.1:5000 filename=secret
+///JSON lines are a single object with time, level, target, message and every structured field
+///Access log entries go to their own file when one is given, whatever the level
+struct Logger {
+    level: LevelFilter,
+    format: LogFormat,
+    access: Option<Mutex<File>>,
+}
+
+///Install the logger, can only be done once per process
+pub fn init(level: LevelFilter, format: LogFormat, access_log: Option<&Path>) -> io::Result<()> {
+    let access = match access_log {
+        Some(path) => Some(Mutex::new(OpenOptions::new().create(true).append(true).open(path)?)),
+        None => None,
+    };
+    //Access entries are logged at info, let them through even when the rest is quieter
+    let max_level = if access.is_some() { level.max(LevelFilter::Info) } else { level };
+    match log::set_boxed_logger(Box::new(Logger { level, format, access })) {
+        Ok(_) => {
+            log::set_max_level(max_level);
+            Ok(())
+        },
+        Err(e) => Err(io::Error::other(e.to_string())),
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        (self.access.is_some() && metadata.target() == ACCESS_TARGET) || metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = match self.format {
+            LogFormat::Text => text_line(record),
+            LogFormat::Json => json_line(record),
+        };
+        //A single write per line so lines from different threads don't interleave
+        if let (Some(access), ACCESS_TARGET) = (&self.access, record.target()) {
+            let mut file = match access.lock() {
+                Ok(f) => f,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            let _ = file.write_all(line.as_bytes());
+        } else {
+            let _ = io::stderr().lock().write_all(line.as_bytes());
+        }
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+        if let Some(access) = &self.access {
+            if let Ok(mut file) = access.lock() {
+                let _ = file.flush();
+            }
+        }
+    }
+}
+
+fn text_line(record: &Record) -> String {
+    let mut line = format!("{} {:<5} {}", timestamp(), record.level(), record.args());
+    let mut fields = Fields(Vec::new());
+    let _ = record.key_values().visit(&mut fields);
+    for (key, value) in fields.0.iter() {
+        let value = value.to_string();
+        //Quote values that would otherwise be ambiguous, and escape them so a filename can't forge a line of its own
+        if value.is_empty() || value.contains(|c: char| c.is_whitespace() || c.is_control()) {
+            let _ = write!(line, " {}={}", key, json_string(&value));
+        } else {
+            let _ = write!(line, " {}={}", key, value);
+        }
+    }
+    line.push('\n');
+    line
+}
+
+fn json_line(record: &Record) -> String {
+    let mut line = String::from("{");
+    let _ = write!(line, "\"time\":{},\"level\":{},\"target\":{},\"message\":{}",
+        json_string(&timestamp()), json_string(record.level().as_str()), json_string(record.target()), json_string(&record.args().to_string()));
+    let mut fields = Fields(Vec::new());
+    let _ = record.key_values().visit(&mut fields);
+    for (key, value) in fields.0.iter() {
+        let _ = write!(line, ",{}:", json_string(key));
+        //Numbers and booleans keep their type, everything else becomes a string
+        if let Some(n) = value.to_u64() {
+            let _ = write!(line, "{}", n);
+        } else if let Some(n) = value.to_i64() {
+            let _ = write!(line, "{}", n);
+        } else if let Some(b) = value.to_bool() {
+            let _ = write!(line, "{}", b);
+        } else {
+            line.push_str(&json_string(&value.to_string()));
+        }
+    }
+    line.push_str("}\n");
+    line
+}
+
+///Structured fields of a record in the order they were written
+struct Fields<'kvs>(Vec<(String, Value<'kvs>)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'kvs> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        self.0.push((key.as_str().to_string(), value));
+        Ok(())
+    }
+}
+
+///Quote and escape a string for JSON
+fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len()+2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => { let _ = write!(quoted, "\\u{:04x}", c as u32); },
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+///Current UTC time as RFC 3339 with milliseconds
+fn timestamp() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = now.as_secs();
+    let (year, month, day) = civil_from_days((seconds/86400) as i64);
+    let of_day = seconds%86400;
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, of_day/3600, of_day%3600/60, of_day%60, now.subsec_millis())
+}
+
+///Turn days since 1970-01-01 into a year, month and day, Howard Hinnant's algorithm
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe/1460 + doe/36524 - doe/146096)/365;
+    let doy = doe - (365*yoe + yoe/4 - yoe/100);
+    let mp = (5*doy + 2)/153;
+    let day = (doy - (153*mp + 2)/5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era*400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
@@ -1,66 +1,443 @@
-use basic_udp;
-
 use std::env;
-use std::collections::HashMap;
-use std::fs::File;
+use std::fs;
+use std::fs::{File, OpenOptions};
 use std::io;
-use std::io::prelude::*;
-
-
-//Basic UDP file transfer server
-fn main() -> std::io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() == 2 {
-        //Disregard whatever was passed, start a server
-        //Serve files indefinitely until an error happens
-        let mut server_arg_map: HashMap<String,String> = HashMap::new();
-
-        match File::open(&args[1]) {
-            Ok(config_file) => {
-                let reader = io::BufReader::new(config_file);
-                for line in reader.lines() {
-                    match line {
-                        Ok(l) => {
-                            let split: Vec<&str> = l.split_ascii_whitespace().collect();
-                            if split.len() >= 2{
-                                server_arg_map.entry(split[0].to_string()).or_insert(split[1].to_string());
-                            }
+use std::io::{BufWriter, Cursor, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use clap::{Args, Parser, Subcommand};
+use basic_udp::{is_file_changed, Client, ClientBuilder, Progress, ProgressObserver, ServerConfig, TransferStats};
+use basic_udp::config::LogFormat;
+use log::{info, warn, LevelFilter};
+
+mod logger;
+
+///Times get starts a download over because the file changed on the server before giving up
+const MAX_RESTARTS: u32 = 3;
+
+///Simple reliable file transfer over UDP
+#[derive(Parser)]
+#[command(name = "basic_udp", version)]
+struct Cli {
+    ///Print more details, can be repeated
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+    ///Only print errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    ///Log line format, text or json, overrides the server config
+    #[arg(long, global = true, value_parser = ["text", "json"])]
+    log_format: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    ///Serve whitelisted files until an error happens
+    Serve {
+        ///TOML config file
+        config: PathBuf,
+        ///Only validate the config and print the resulting settings
+        #[arg(long)]
+        check_config: bool,
+    },
+    ///Download a file
+    Get {
+        ///Server address, e.g. 127.0.0.1:9001
+        server: String,
+        ///Name of the file on the server
+        file: String,
+        ///Where to write the file, defaults to the last component of the remote name, - writes to stdout
+        output: Option<PathBuf>,
+        ///Write the file to stdout, same as passing - as the output
+        #[arg(short = 'O', long, conflicts_with_all = ["output", "resume"])]
+        stdout: bool,
+        ///Keep the complete chunks of an existing output file and fetch only the rest
+        #[arg(long)]
+        resume: bool,
+        ///Receive the file from the multicast group the server sends it to, e.g. 239.255.0.1:9200, missing chunks are fetched from the server
+        #[arg(long, conflicts_with_all = ["stdout", "resume"])]
+        multicast: Option<String>,
+        ///Bring an existing output file up to date in place, fetching only the chunks that differ from it
+        #[arg(long, conflicts_with_all = ["stdout", "resume", "multicast"])]
+        update: bool,
+        #[command(flatten)]
+        client: ClientArgs,
+    },
+    ///Upload a file, the server has to have uploads enabled
+    Put {
+        server: String,
+        ///Local file to upload, - reads from stdin
+        input: PathBuf,
+        ///Name to store the file under in the server's upload directory
+        remote: String,
+        ///File holding the server's upload key, otherwise the BASIC_UDP_KEY environment variable is used
+        #[arg(long)]
+        key_file: Option<PathBuf>,
+        #[command(flatten)]
+        client: ClientArgs,
+    },
+    ///Show how big a file on the server is
+    Stat {
+        server: String,
+        file: String,
+        #[command(flatten)]
+        client: ClientArgs,
+    },
+    ///List the files a server is willing to hand out
+    Ls {
+        server: String,
+        ///Only list files below this directory
+        #[arg(default_value = "")]
+        directory: String,
+        #[command(flatten)]
+        client: ClientArgs,
+    },
+    ///Download a file repeatedly and report throughput, nothing is written to disk
+    Bench {
+        server: String,
+        file: String,
+        ///How many times to download the file
+        #[arg(short = 'n', long, default_value_t = 5)]
+        count: u32,
+        #[command(flatten)]
+        client: ClientArgs,
+    },
+}
+
+///Client tunables shared by every client subcommand
+#[derive(Args)]
+struct ClientArgs {
+    ///Chunks held in memory and requested at once
+    #[arg(long, default_value_t = 1000)]
+    window: usize,
+    ///Packet size, has to match the server's packet_size
+    #[arg(long, default_value_t = 512)]
+    packet_size: usize,
+    ///Milliseconds without data before missing chunks are requested again
+    #[arg(long, default_value_t = 200)]
+    retry_ms: u64,
+    ///Seconds without data before giving up
+    #[arg(long, default_value_t = 10)]
+    timeout: u64,
+    ///Fetch through a server side session, the server keeps the file open and chunks are requested by handle
+    #[arg(long)]
+    session: bool,
+    ///Don't ask for FEC repair packets even if the server sends them
+    #[arg(long)]
+    no_fec: bool,
+    ///Don't ask for compressed chunks even if the server compresses them
+    #[arg(long)]
+    no_compression: bool,
+    ///Don't check chunks against the Merkle root even if the server sends one
+    #[arg(long)]
+    no_verify: bool,
+    ///Address of the interface to join multicast groups on
+    #[arg(long)]
+    interface: Option<IpAddr>,
+}
+
+impl ClientArgs {
+    fn builder(&self) -> ClientBuilder {
+        let builder = Client::builder()
+            .window(self.window)
+            .packet_size(self.packet_size)
+            .retry_interval(Duration::from_millis(self.retry_ms))
+            .timeout(Duration::from_secs(self.timeout))
+            .sessions(self.session)
+            .fec(!self.no_fec)
+            .compression(!self.no_compression)
+            .verify(!self.no_verify);
+        match self.interface {
+            Some(interface) => builder.multicast_interface(interface),
+            None => builder,
+        }
+    }
+
+    fn client(&self) -> io::Result<Client> {
+        self.builder().build()
+    }
+
+    ///Client that shows a progress bar on stderr unless quiet
+    fn client_with_progress(&self, quiet: bool) -> io::Result<Client> {
+        if quiet {
+            self.client()
+        } else {
+            self.builder().progress(ProgressBar::new()).build()
+        }
+    }
+}
+
+///Progress output on stderr, a live bar on terminals and a line every few seconds otherwise
+struct ProgressBar {
+    terminal: bool,
+    last_line: Mutex<Option<Instant>>,
+}
+
+impl ProgressBar {
+    fn new() -> Self {
+        Self {
+            terminal: io::stderr().is_terminal(),
+            last_line: Mutex::new(None),
+        }
+    }
+}
+
+impl ProgressObserver for ProgressBar {
+    fn on_progress(&self, progress: &Progress) {
+        let fraction = if progress.total_bytes == 0 {
+            1.0
+        } else {
+            progress.bytes_done as f64/progress.total_bytes as f64
+        };
+        let eta = match progress.eta {
+            Some(e) => format!("{}s", e.as_secs()),
+            None => String::from("?"),
+        };
+        let details = format!("{:>3.0}% {}/{} bytes {:.2} MB/s ETA {} retransmits {}",
+            fraction*100.0, progress.bytes_done, progress.total_bytes, progress.rate/1_000_000.0, eta, progress.retransmits);
+
+        if self.terminal {
+            let width = 30;
+            let filled = ((fraction*width as f64) as usize).min(width);
+            let mut stderr = io::stderr();
+            let _ = write!(stderr, "\r[{}{}] {}\x1b[K", "#".repeat(filled), "-".repeat(width-filled), details);
+            if progress.finished {
+                let _ = writeln!(stderr);
+            }
+            let _ = stderr.flush();
+        } else {
+            let mut last_line = match self.last_line.lock() {
+                Ok(l) => l,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            let due = match *last_line {
+                Some(l) => l.elapsed() >= Duration::from_secs(5),
+                None => true,
+            };
+            if due || progress.finished {
+                eprintln!("{}", details);
+                *last_line = Some(Instant::now());
+            }
+        }
+    }
+}
+
+//Basic UDP file transfer server and client
+fn main() -> io::Result<()> {
+    let cli = Cli::parse();
+    let log_format = match cli.log_format.as_deref() {
+        Some("json") => Some(LogFormat::Json),
+        Some(_) => Some(LogFormat::Text),
+        None => None,
+    };
+    //The server picks its logging from the config file once it is loaded
+    if !matches!(cli.command, Command::Serve { .. }) {
+        logger::init(log_level(cli.verbose, cli.quiet, LevelFilter::Warn), log_format.unwrap_or(LogFormat::Text), None)?;
+    }
+    match cli.command {
+        Command::Serve { config, check_config } => {
+            let loaded = match ServerConfig::from_file(&config) {
+                Ok(c) => c,
+                Err(e) => {
+                    eprintln!("{} is invalid: {}", config.display(), e);
+                    return Err(e);
+                }
+            };
+            if check_config {
+                if !cli.quiet {
+                    println!("{} is valid\n{:#?}", config.display(), loaded);
+                }
+                return Ok(());
+            }
+            let level = log_level(cli.verbose, cli.quiet, loaded.logging.level.filter());
+            logger::init(level, log_format.unwrap_or(loaded.logging.format), loaded.logging.access_log.as_deref())?;
+            basic_udp::serve(&loaded)
+        },
+        Command::Get { server, file, output, stdout, resume, multicast, update, client } => {
+            let client = client.client_with_progress(cli.quiet)?;
+            let stdout = stdout || output.as_ref().map(|o| o.as_os_str() == "-").unwrap_or(false);
+            let stats = if stdout {
+                if resume || update {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "--resume and --update need an output file, not stdout"));
+                }
+                //Chunks arrive in order so they can go straight down the pipe
+                let out = io::stdout();
+                let mut out = BufWriter::with_capacity(1 << 16, out.lock());
+                match client.fetch_to(&server, &file, &mut out) {
+                    Ok(s) => s,
+                    //Whoever reads the pipe has seen enough, e.g. head, nothing to complain about
+                    Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+                    Err(e) => return Err(e),
+                }
+            } else if let Some(group) = multicast {
+                let output = match output {
+                    Some(o) => o,
+                    None => default_output(&file)?,
+                };
+                //Chunks land wherever they belong as the group sends them, the file is written out of order
+                let mut outfile = OpenOptions::new().write(true).create(true).truncate(true).open(&output)?;
+                let mut restarts = 0;
+                loop {
+                    match client.fetch_multicast_to(&server, &group, &file, &mut outfile) {
+                        Ok(s) => break s,
+                        Err(e) if is_file_changed(&e) && restarts < MAX_RESTARTS => {
+                            restarts += 1;
+                            warn!(filename:% = file, restart = restarts; "File changed on the server, starting over");
+                            outfile.set_len(0)?;
+                        },
+                        Err(e) => return Err(e),
+                    }
+                }
+            } else if update {
+                let output = match output {
+                    Some(o) => o,
+                    None => default_output(&file)?,
+                };
+                let mut outfile = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&output)?;
+                let mut restarts = 0;
+                loop {
+                    match client.fetch_update(&server, &file, &mut outfile) {
+                        Ok(s) => break s,
+                        //Comparing again sorts out whatever chunks came from the old version
+                        Err(e) if is_file_changed(&e) && restarts < MAX_RESTARTS => {
+                            restarts += 1;
+                            warn!(filename:% = file, restart = restarts; "File changed on the server, starting over");
+                        },
+                        Err(e) => return Err(e),
+                    }
+                }
+            } else {
+                let output = match output {
+                    Some(o) => o,
+                    None => default_output(&file)?,
+                };
+                let mut outfile = OpenOptions::new().write(true).create(true).truncate(!resume).open(&output)?;
+                //Drop any partial chunk at the end and continue after the last complete one
+                let chunk_size = client.chunk_size();
+                let mut first_chunk = outfile.metadata()?.len()/chunk_size;
+                outfile.set_len(first_chunk*chunk_size)?;
+                outfile.seek(SeekFrom::End(0))?;
+                if resume {
+                    info!(output:% = output.display(), chunk = first_chunk; "Resuming the download");
+                }
+                let mut restarts = 0;
+                loop {
+                    match client.fetch_from(&server, &file, &mut outfile, first_chunk) {
+                        Ok(s) => break s,
+                        //What we have is a mix of versions, start over to get a consistent copy
+                        Err(e) if is_file_changed(&e) && restarts < MAX_RESTARTS => {
+                            restarts += 1;
+                            warn!(filename:% = file, restart = restarts; "File changed on the server, starting over");
+                            outfile.set_len(0)?;
+                            outfile.seek(SeekFrom::Start(0))?;
+                            first_chunk = 0;
                         },
-                        Err(_) => {
-                            //No big deal, go with default settings/behavior
-                        }
+                        Err(e) => return Err(e),
                     }
                 }
-            },
-            Err(e) => {
-                println!("Unable to open config file");
-                return Err(e);
+            };
+            if cli.verbose > 0 {
+                eprintln!("{}", summary(&stats));
             }
-        }
-        
+            Ok(())
+        },
+        Command::Put { server, input, remote, key_file, client } => {
+            let client = client.client_with_progress(cli.quiet)?;
+            let key = match key_file {
+                Some(path) => fs::read_to_string(path)?.trim().to_string(),
+                None => match env::var("BASIC_UDP_KEY") {
+                    Ok(k) => k,
+                    Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Pass --key-file or set BASIC_UDP_KEY")),
+                },
+            };
+            let stats = if input.as_os_str() == "-" {
+                //The announcement needs the size and hash up front, so stdin is collected first
+                let mut data: Vec<u8> = Vec::new();
+                io::stdin().read_to_end(&mut data)?;
+                client.push(&server, &remote, &mut Cursor::new(data), key.as_bytes())?
+            } else {
+                client.push(&server, &remote, &mut File::open(&input)?, key.as_bytes())?
+            };
+            if cli.verbose > 0 {
+                eprintln!("{}", summary(&stats));
+            }
+            Ok(())
+        },
+        Command::Stat { server, file, client } => {
+            let client = client.client()?;
+            let chunks = client.stat(&server, &file)?;
+            if chunks == 0 {
+                println!("{}: empty or not on the whitelist", file);
+            } else {
+                let chunk_size = client.chunk_size();
+                println!("{}: {} chunks of {} bytes, at most {} bytes", file, chunks, chunk_size, chunks*chunk_size);
+            }
+            Ok(())
+        },
+        Command::Ls { server, directory, client } => {
+            let files = client.client()?.list(&server, &directory)?;
+            for f in files.iter() {
+                println!("{}", f);
+            }
+            if cli.verbose > 0 {
+                eprintln!("{} files", files.len());
+            }
+            Ok(())
+        },
+        Command::Bench { server, file, count, client } => {
+            let client = client.client()?;
+            let mut total = TransferStats::default();
+            for run in 0..count {
+                let stats = client.fetch_to(&server, &file, &mut io::sink())?;
+                if !cli.quiet {
+                    println!("Run {}: {}", run+1, summary(&stats));
+                }
+                total.bytes += stats.bytes;
+                total.chunks += stats.chunks;
+                total.duration += stats.duration;
+                total.retransmits += stats.retransmits;
+                total.duplicate_packets += stats.duplicate_packets;
+                total.nacks += stats.nacks;
+                total.repaired += stats.repaired;
+                total.unchanged += stats.unchanged;
+                total.rejected += stats.rejected;
+                total.rtt = stats.rtt;
+            }
+            println!("Total: {}", summary(&total));
+            Ok(())
+        },
+    }
+}
 
-        //println!("Here's the config map {:?}",server_arg_map);
-        //We've parsed the config file, use it or fill out defaults
-        if !server_arg_map.contains_key("ip") {
-            println!("ip not found in config file, using default IP/port 127.0.0.1:9001");
-            server_arg_map.insert(String::from("ip"),String::from("127.0.0.1:9001"));
-        }
-        if !server_arg_map.contains_key("whitelist") {
-            println!("whitelist not found in config file, using default called: whitelist");
-            server_arg_map.insert(String::from("whitelist"),String::from("whitelist"));
-        }
+///-q only logs errors, every -v logs one level more than the default
+fn log_level(verbose: u8, quiet: bool, default: LevelFilter) -> LevelFilter {
+    if quiet {
+        return LevelFilter::Error;
+    }
+    let levels = [LevelFilter::Off, LevelFilter::Error, LevelFilter::Warn, LevelFilter::Info, LevelFilter::Debug, LevelFilter::Trace];
+    let start = levels.iter().position(|l| *l == default).unwrap_or(2);
+    levels[(start + verbose as usize).min(levels.len()-1)]
+}
+
+///One line description of a transfer
+fn summary(stats: &TransferStats) -> String {
+    let seconds = stats.duration.as_secs_f64();
+    let rtt = match stats.rtt {
+        Some(r) => format!("{:.2}ms", r.as_secs_f64()*1000.0),
+        None => String::from("unknown"),
+    };
+    format!("{} bytes in {:.3}s ({:.2} MB/s), {} retransmits, {} nacks, {} repaired, {} unchanged, {} rejected, {} duplicate packets, rtt {}",
+        stats.bytes, seconds, stats.bytes as f64/seconds/1_000_000.0, stats.retransmits, stats.nacks, stats.repaired, stats.unchanged, stats.rejected, stats.duplicate_packets, rtt)
+}
 
-        let result = basic_udp::serve(&server_arg_map["ip"],&server_arg_map["whitelist"]);
-
-        return result;
-    } else if args.len() == 4 {
-        //Run in client mode
-        //Parse a filename, a port:address
-        //Perform the client portion of transfer
-        let result = basic_udp::client_request_sequential_limited(&args[1], &args[2], &args[3],1000);
-        return result;
-    } else {
-        println!("Server mode:\nbasic_udp <config file>\nClient mode:\nbasic_udp <address:port> <filename> <outfilename>");
-        Ok(())
+///Local name for a download when none is given, the last component of the remote name
+fn default_output(file: &str) -> io::Result<PathBuf> {
+    match Path::new(file).file_name() {
+        Some(name) => Ok(PathBuf::from(name)),
+        None => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Can't pick an output name for {:?}, pass one", file))),
     }
 }
@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use sha2::{Digest, Sha256};
+use crate::delta;
+use crate::delta::HASH_LEN;
+use crate::{pack_u64_into_u8arr, unpack_u8arr_into_u64, CONTROL_CHUNK, TREE_NODES};
+
+///Bytes the root adds to metadata and session open responses
+pub(crate) const ROOT_LEN: usize = HASH_LEN;
+///Smallest packet size a session open response with FEC params, a variant's description and the root fits in
+pub(crate) const MIN_PACKET_SIZE: usize = crate::variant::MIN_PACKET_SIZE + ROOT_LEN;
+///Bits of a node ID below the level, the rest is the node's index within its level
+const LEVEL_SHIFT: u32 = 56;
+///CONTROL_CHUNK and TREE_NODES come before the nodes
+const HEADER_LEN: usize = 16;
+///u64 node ID followed by its hash
+const NODE_LEN: usize = 8 + HASH_LEN;
+
+///ID of node index of level, leaves are level 0
+pub(crate) fn node_id(level: u64, index: u64) -> u64 {
+    level << LEVEL_SHIFT | index
+}
+
+///Parent of left and right, the SHA-256 of both truncated like the leaves
+fn parent(left: &[u8; HASH_LEN], right: &[u8; HASH_LEN]) -> [u8; HASH_LEN] {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    let mut hash = [0; HASH_LEN];
+    hash.copy_from_slice(&hasher.finalize()[0..HASH_LEN]);
+    hash
+}
+
+///Width of the level above one of width nodes, a node without a sibling moves up as it is
+fn parent_width(width: u64) -> u64 {
+    width.div_ceil(2)
+}
+
+///Merkle tree over the chunks of a file, the leaves are the chunk hashes sent in CHUNK_HASHES packets
+///Clients know the shape of the tree from the chunk count, so leaves and inner nodes can't be mixed up
+pub(crate) struct Tree {
+    levels: Vec<Vec<[u8; HASH_LEN]>>,
+}
+
+impl Tree {
+    ///Hash every chunk of file, None if it is empty
+    pub(crate) fn build(file: &mut File, chunk_size: usize) -> io::Result<Option<Self>> {
+        let mut buffer: Vec<u8> = vec![0; chunk_size];
+        let mut leaves: Vec<[u8; HASH_LEN]> = Vec::new();
+        file.seek(SeekFrom::Start(0))?;
+        loop {
+            let bytes_read = read_full(file, &mut buffer)?;
+            if bytes_read == 0 {
+                break;
+            }
+            leaves.push(delta::chunk_hash(&buffer[0..bytes_read]));
+        }
+        Ok(Self::from_leaves(leaves))
+    }
+
+    ///Tree over chunk hashes computed before, None if there are none
+    pub(crate) fn from_leaves(leaves: Vec<[u8; HASH_LEN]>) -> Option<Self> {
+        if leaves.is_empty() {
+            return None;
+        }
+        let mut levels = vec![leaves];
+        while let Some(below) = levels.last().filter(|l| l.len() > 1) {
+            let level = below.chunks(2).map(|pair| match pair {
+                [left, right] => parent(left, right),
+                _ => pair[0],
+            }).collect();
+            levels.push(level);
+        }
+        Some(Self { levels })
+    }
+
+    ///Hashes of the chunks, in order
+    pub(crate) fn leaves(&self) -> &[[u8; HASH_LEN]] {
+        &self.levels[0]
+    }
+
+    ///Bytes of hashes the tree holds, about twice its leaves
+    pub(crate) fn bytes(&self) -> usize {
+        self.levels.iter().map(|l| l.len()*HASH_LEN).sum()
+    }
+
+    pub(crate) fn root(&self) -> [u8; HASH_LEN] {
+        self.levels[self.levels.len()-1][0]
+    }
+
+    ///Hash of a node by its ID, None if the tree has no such node
+    pub(crate) fn node(&self, id: u64) -> Option<&[u8; HASH_LEN]> {
+        let level = usize::try_from(id >> LEVEL_SHIFT).ok()?;
+        let index = usize::try_from(id & ((1 << LEVEL_SHIFT) - 1)).ok()?;
+        self.levels.get(level)?.get(index)
+    }
+}
+
+///Nodes besides the leaves from first through last that computing the root of a tree over leaf_count leaves takes, as node IDs
+///At most two per level, the siblings just outside either end
+pub(crate) fn proof_nodes(leaf_count: u64, first: u64, last: u64) -> Vec<u64> {
+    let mut nodes: Vec<u64> = Vec::new();
+    let (mut low, mut high, mut width, mut level) = (first, last, leaf_count, 0);
+    while width > 1 {
+        if low % 2 == 1 {
+            nodes.push(node_id(level, low-1));
+        }
+        if high % 2 == 0 && high+1 < width {
+            nodes.push(node_id(level, high+1));
+        }
+        low /= 2;
+        high /= 2;
+        width = parent_width(width);
+        level += 1;
+    }
+    nodes
+}
+
+///Root of a tree over leaf_count leaves from the leaves starting at first and the nodes proof_nodes asked for
+///None if a node is missing
+pub(crate) fn root_from(leaf_count: u64, first: u64, leaves: &[[u8; HASH_LEN]], nodes: &HashMap<u64, [u8; HASH_LEN]>) -> Option<[u8; HASH_LEN]> {
+    let mut current: Vec<[u8; HASH_LEN]> = leaves.to_vec();
+    let (mut low, mut width, mut level) = (first, leaf_count, 0);
+    while width > 1 {
+        let high = low + current.len() as u64 - 1;
+        let node = |index: u64| -> Option<[u8; HASH_LEN]> {
+            if index >= low && index <= high {
+                Some(current[(index-low) as usize])
+            } else {
+                nodes.get(&node_id(level, index)).copied()
+            }
+        };
+        let mut above: Vec<[u8; HASH_LEN]> = Vec::new();
+        for p in low/2..=high/2 {
+            let left = node(2*p)?;
+            above.push(if 2*p+1 < width { parent(&left, &node(2*p+1)?) } else { left });
+        }
+        current = above;
+        low /= 2;
+        width = parent_width(width);
+        level += 1;
+    }
+    current.first().copied()
+}
+
+///Populates a buffer with a packet of tree nodes, CONTROL_CHUNK, TREE_NODES and then u64 node ID and hash pairs, returns how many bytes are in the packet
+pub(crate) fn nodes_packet(nodes: &[(u64, [u8; HASH_LEN])], buffer: &mut [u8]) -> usize {
+    buffer[0..8].copy_from_slice(&pack_u64_into_u8arr(CONTROL_CHUNK));
+    buffer[8..16].copy_from_slice(&pack_u64_into_u8arr(TREE_NODES));
+    let mut byte_counter = HEADER_LEN;
+    for (id, hash) in nodes.iter() {
+        buffer[byte_counter..byte_counter+8].copy_from_slice(&pack_u64_into_u8arr(*id));
+        buffer[byte_counter+8..byte_counter+NODE_LEN].copy_from_slice(hash);
+        byte_counter += NODE_LEN;
+    }
+    byte_counter
+}
+
+///How many nodes fit in a packet of packet_size
+pub(crate) fn nodes_per_packet(packet_size: usize) -> usize {
+    (packet_size - HEADER_LEN)/NODE_LEN
+}
+
+///Node IDs and hashes of a TREE_NODES packet, None if it is something else
+pub(crate) fn unpack_nodes(packet: &[u8]) -> Option<impl Iterator<Item = (u64, &[u8])>> {
+    if packet.len() < HEADER_LEN || unpack_u8arr_into_u64(&packet[0..8]) != CONTROL_CHUNK || unpack_u8arr_into_u64(&packet[8..16]) != TREE_NODES {
+        return None;
+    }
+    Some(packet[HEADER_LEN..].chunks_exact(NODE_LEN).map(|node| (unpack_u8arr_into_u64(&node[0..8]), &node[8..])))
+}
+
+///Read until buf is full or the file runs dry, returns how many bytes were read
+fn read_full(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let bytes_read = file.read(&mut buf[filled..])?;
+        if bytes_read == 0 {
+            break;
+        }
+        filled += bytes_read;
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(leaf_count: u64) -> Tree {
+        Tree::from_leaves((0..leaf_count).map(|i| delta::chunk_hash(&i.to_le_bytes())).collect()).unwrap()
+    }
+
+    ///The nodes proof_nodes asks for, as a client would have them after TREE_NODES packets
+    fn proof(tree: &Tree, leaf_count: u64, first: u64, last: u64) -> HashMap<u64, [u8; HASH_LEN]> {
+        proof_nodes(leaf_count, first, last).into_iter().map(|id| (id, *tree.node(id).unwrap())).collect()
+    }
+
+    #[test]
+    fn root_from_proof_matches_root_for_every_range() {
+        for leaf_count in [1, 2, 3, 5, 9, 17, 33] {
+            let tree = tree(leaf_count);
+            for first in 0..leaf_count {
+                for last in first..leaf_count {
+                    let nodes = proof(&tree, leaf_count, first, last);
+                    let leaves = &tree.leaves()[first as usize..=last as usize];
+                    assert_eq!(root_from(leaf_count, first, leaves, &nodes), Some(tree.root()), "{} leaves, {}..={}", leaf_count, first, last);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn tampered_leaf_changes_root() {
+        for leaf_count in [2, 3, 5, 9, 17] {
+            let tree = tree(leaf_count);
+            for first in 0..leaf_count {
+                for last in first..leaf_count {
+                    let nodes = proof(&tree, leaf_count, first, last);
+                    let mut leaves = tree.leaves()[first as usize..=last as usize].to_vec();
+                    let tampered = leaves.len()/2;
+                    leaves[tampered][0] ^= 1;
+                    assert_ne!(root_from(leaf_count, first, &leaves, &nodes), Some(tree.root()), "{} leaves, {}..={}", leaf_count, first, last);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn tampered_node_changes_root() {
+        let tree = tree(9);
+        let mut nodes = proof(&tree, 9, 3, 4);
+        assert!(!nodes.is_empty());
+        for hash in nodes.values_mut() {
+            hash[0] ^= 1;
+        }
+        assert_ne!(root_from(9, 3, &tree.leaves()[3..=4], &nodes), Some(tree.root()));
+    }
+
+    #[test]
+    fn missing_node_gives_none() {
+        let tree = tree(5);
+        let mut nodes = proof(&tree, 5, 1, 1);
+        let id = proof_nodes(5, 1, 1)[0];
+        nodes.remove(&id);
+        assert_eq!(root_from(5, 1, &tree.leaves()[1..=1], &nodes), None);
+    }
+
+    #[test]
+    fn single_leaf_needs_no_nodes() {
+        let tree = tree(1);
+        assert!(proof_nodes(1, 0, 0).is_empty());
+        assert_eq!(tree.root(), tree.leaves()[0]);
+    }
+}
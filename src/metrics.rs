@@ -0,0 +1,380 @@
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use crate::TransactionKind;
+
+///Upper bounds in seconds of the request duration histogram buckets
+const DURATION_BUCKETS: [f64; 10] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+///Why a packet was dropped without being answered
+#[derive(Clone, Copy)]
+pub enum DropReason {
+    ///The client is not in security.allowed_clients
+    ClientDenied,
+    ///The client went over limits.requests_per_second
+    RateLimited,
+    ///Unknown packet ID or a request that couldn't be parsed
+    Malformed,
+}
+
+///Counters and histograms describing what a server has been doing since it started
+///
+///Everything is atomic, workers update the same Metrics without locking
+pub struct Metrics {
+    requests_metadata: AtomicU64,
+    requests_chunks: AtomicU64,
+    requests_list: AtomicU64,
+    requests_upload: AtomicU64,
+    requests_session: AtomicU64,
+    dropped_client_denied: AtomicU64,
+    dropped_rate_limited: AtomicU64,
+    dropped_malformed: AtomicU64,
+    whitelist_denials: AtomicU64,
+    duplicates_suppressed: AtomicU64,
+    repair_packets: AtomicU64,
+    packets_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    errors: AtomicU64,
+    active_transactions: AtomicU64,
+    throttled_micros: AtomicU64,
+    bandwidth_limit: u64,
+    request_duration: Histogram,
+}
+
+///Cumulative histogram with fixed bucket bounds
+struct Histogram {
+    bounds: &'static [f64],
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Metrics {
+    ///bandwidth_limit is the configured bytes_per_second, exported so alerts can compare it to the bytes sent
+    pub fn new(bandwidth_limit: u64) -> Self {
+        Self {
+            requests_metadata: AtomicU64::new(0),
+            requests_chunks: AtomicU64::new(0),
+            requests_list: AtomicU64::new(0),
+            requests_upload: AtomicU64::new(0),
+            requests_session: AtomicU64::new(0),
+            dropped_client_denied: AtomicU64::new(0),
+            dropped_rate_limited: AtomicU64::new(0),
+            dropped_malformed: AtomicU64::new(0),
+            whitelist_denials: AtomicU64::new(0),
+            duplicates_suppressed: AtomicU64::new(0),
+            repair_packets: AtomicU64::new(0),
+            packets_sent: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            active_transactions: AtomicU64::new(0),
+            throttled_micros: AtomicU64::new(0),
+            bandwidth_limit,
+            request_duration: Histogram::new(&DURATION_BUCKETS),
+        }
+    }
+
+    ///Count a request the server is about to service
+    pub fn request(&self, kind: &TransactionKind) {
+        let counter = match kind {
+            TransactionKind::Metadata => &self.requests_metadata,
+            TransactionKind::Chunks | TransactionKind::SessionChunks(_, _) => &self.requests_chunks,
+            TransactionKind::List(_) => &self.requests_list,
+            TransactionKind::SessionOpen(_) | TransactionKind::SessionClose(_, _) => &self.requests_session,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    ///Count an upload announce, chunk or status packet
+    pub fn upload_request(&self) {
+        self.requests_upload.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dropped(&self, reason: DropReason) {
+        let counter = match reason {
+            DropReason::ClientDenied => &self.dropped_client_denied,
+            DropReason::RateLimited => &self.dropped_rate_limited,
+            DropReason::Malformed => &self.dropped_malformed,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn whitelist_denied(&self) {
+        self.whitelist_denials.fetch_add(1, Ordering::Relaxed);
+    }
+
+    ///Chunks that were asked for but not sent because they had just been sent
+    pub fn suppressed(&self, chunks: u64) {
+        self.duplicates_suppressed.fetch_add(chunks, Ordering::Relaxed);
+    }
+
+    ///Count one FEC repair packet, it is counted by sent too
+    pub fn repair_sent(&self) {
+        self.repair_packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    ///Count one packet of bytes that made it onto the socket
+    pub fn sent(&self, bytes: usize) {
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    ///Time spent sleeping because the bandwidth limit was reached
+    pub fn throttled(&self, slept: Duration) {
+        self.throttled_micros.fetch_add(slept.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    ///Mark a transaction as being serviced, finish_transaction undoes it
+    pub fn start_transaction(&self) {
+        self.active_transactions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    ///A transaction is done after taking elapsed to service
+    pub fn finish_transaction(&self, elapsed: Duration) {
+        self.active_transactions.fetch_sub(1, Ordering::Relaxed);
+        self.request_duration.observe(elapsed);
+    }
+
+    ///Everything in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let load = |c: &AtomicU64| c.load(Ordering::Relaxed);
+
+        header(&mut out, "basic_udp_requests_total", "counter", "Requests received by kind");
+        for (kind, counter) in [("metadata", &self.requests_metadata), ("chunks", &self.requests_chunks), ("list", &self.requests_list), ("upload", &self.requests_upload), ("session", &self.requests_session)] {
+            let _ = writeln!(out, "basic_udp_requests_total{{kind=\"{}\"}} {}", kind, load(counter));
+        }
+        header(&mut out, "basic_udp_dropped_total", "counter", "Packets dropped without an answer by reason");
+        for (reason, counter) in [("client_denied", &self.dropped_client_denied), ("rate_limited", &self.dropped_rate_limited), ("malformed", &self.dropped_malformed)] {
+            let _ = writeln!(out, "basic_udp_dropped_total{{reason=\"{}\"}} {}", reason, load(counter));
+        }
+        single(&mut out, "basic_udp_whitelist_denials_total", "counter", "Requests for files that are not on the whitelist", load(&self.whitelist_denials) as f64);
+        single(&mut out, "basic_udp_duplicates_suppressed_total", "counter", "Chunks asked for again while still in flight and not sent twice", load(&self.duplicates_suppressed) as f64);
+        single(&mut out, "basic_udp_repair_packets_total", "counter", "FEC repair packets sent, included in packets sent", load(&self.repair_packets) as f64);
+        single(&mut out, "basic_udp_packets_sent_total", "counter", "Packets sent", load(&self.packets_sent) as f64);
+        single(&mut out, "basic_udp_bytes_sent_total", "counter", "Bytes sent", load(&self.bytes_sent) as f64);
+        single(&mut out, "basic_udp_errors_total", "counter", "Requests that failed to be serviced or answered", load(&self.errors) as f64);
+        single(&mut out, "basic_udp_active_transactions", "gauge", "Requests being serviced right now", load(&self.active_transactions) as f64);
+        single(&mut out, "basic_udp_bandwidth_limit_bytes_per_second", "gauge", "Configured outbound bandwidth limit, 0 is unlimited", self.bandwidth_limit as f64);
+        single(&mut out, "basic_udp_throttled_seconds_total", "counter", "Time spent waiting on the bandwidth limit", load(&self.throttled_micros) as f64/1_000_000.0);
+
+        header(&mut out, "basic_udp_request_duration_seconds", "histogram", "Time taken to service a request");
+        self.request_duration.render(&mut out, "basic_udp_request_duration_seconds");
+        out
+    }
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, value: Duration) {
+        let seconds = value.as_secs_f64();
+        //Buckets are stored non cumulative and summed up when rendering
+        if let Some(i) = self.bounds.iter().position(|b| seconds <= *b) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(value.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str) {
+        let mut cumulative = 0;
+        for (bound, bucket) in self.bounds.iter().zip(self.buckets.iter()) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum_micros.load(Ordering::Relaxed) as f64/1_000_000.0);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn single(out: &mut String, name: &str, kind: &str, help: &str, value: f64) {
+    header(out, name, kind, help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+///Serve the metrics over plain HTTP on address, GET /metrics answers in the Prometheus text format
+#[cfg(feature = "metrics")]
+pub fn spawn_endpoint(address: &str, metrics: std::sync::Arc<Metrics>) -> std::io::Result<std::thread::JoinHandle<()>> {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind(address)?;
+    log::info!(address:% = address; "Serving metrics");
+    Ok(std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(s) => s,
+                Err(e) => {
+                    log::warn!(error:% = e; "Unable to accept a metrics connection");
+                    continue;
+                }
+            };
+            //Scrapes are tiny, don't let a silent connection hold up the next one
+            let _ = stream.set_read_timeout(Some(Duration::from_secs(2)));
+            let mut request = [0u8; 1024];
+            let mut filled = 0;
+            while filled < request.len() && !request[..filled].windows(4).any(|w| w == b"\r\n\r\n") {
+                match stream.read(&mut request[filled..]) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => filled += n,
+                }
+            }
+            let line = String::from_utf8_lossy(&request[..filled]);
+            let path = line.split_whitespace().nth(1).unwrap_or("");
+            let response = if line.starts_with("GET ") && (path == "/metrics" || path == "/") {
+                let body = metrics.render();
+                format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+            } else {
+                String::from("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+            };
+            let _ = stream.write_all(response.as_bytes());
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_include_their_bound() {
+        let histogram = Histogram::new(&DURATION_BUCKETS);
+        histogram.observe(Duration::from_micros(100));
+        histogram.observe(Duration::from_micros(101));
+        histogram.observe(Duration::from_secs(5));
+        histogram.observe(Duration::from_secs(6));
+        let buckets: Vec<u64> = histogram.buckets.iter().map(|b| b.load(Ordering::Relaxed)).collect();
+        assert_eq!(buckets, vec![1, 1, 0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(histogram.count.load(Ordering::Relaxed), 4);
+        assert_eq!(histogram.sum_micros.load(Ordering::Relaxed), 11_000_201);
+    }
+
+    #[test]
+    fn renders_a_known_state() {
+        let metrics = Metrics::new(1_000_000);
+        for kind in [TransactionKind::Metadata, TransactionKind::Chunks, TransactionKind::SessionChunks(1, 1), TransactionKind::List(0),
+            TransactionKind::SessionOpen(0), TransactionKind::SessionClose(1, 0)].iter() {
+            metrics.request(kind);
+        }
+        for _ in 0..3 {
+            metrics.upload_request();
+            metrics.sent(512);
+        }
+        metrics.dropped(DropReason::ClientDenied);
+        metrics.dropped(DropReason::RateLimited);
+        metrics.dropped(DropReason::RateLimited);
+        metrics.whitelist_denied();
+        metrics.suppressed(7);
+        metrics.repair_sent();
+        metrics.error();
+        metrics.throttled(Duration::from_millis(250));
+        for _ in 0..4 {
+            metrics.start_transaction();
+        }
+        metrics.finish_transaction(Duration::from_micros(100));
+        metrics.finish_transaction(Duration::from_millis(3));
+        metrics.finish_transaction(Duration::from_secs(10));
+
+        let expected = "\
+# HELP basic_udp_requests_total Requests received by kind
+# TYPE basic_udp_requests_total counter
+basic_udp_requests_total{kind=\"metadata\"} 1
+basic_udp_requests_total{kind=\"chunks\"} 2
+basic_udp_requests_total{kind=\"list\"} 1
+basic_udp_requests_total{kind=\"upload\"} 3
+basic_udp_requests_total{kind=\"session\"} 2
+# HELP basic_udp_dropped_total Packets dropped without an answer by reason
+# TYPE basic_udp_dropped_total counter
+basic_udp_dropped_total{reason=\"client_denied\"} 1
+basic_udp_dropped_total{reason=\"rate_limited\"} 2
+basic_udp_dropped_total{reason=\"malformed\"} 0
+# HELP basic_udp_whitelist_denials_total Requests for files that are not on the whitelist
+# TYPE basic_udp_whitelist_denials_total counter
+basic_udp_whitelist_denials_total 1
+# HELP basic_udp_duplicates_suppressed_total Chunks asked for again while still in flight and not sent twice
+# TYPE basic_udp_duplicates_suppressed_total counter
+basic_udp_duplicates_suppressed_total 7
+# HELP basic_udp_repair_packets_total FEC repair packets sent, included in packets sent
+# TYPE basic_udp_repair_packets_total counter
+basic_udp_repair_packets_total 1
+# HELP basic_udp_packets_sent_total Packets sent
+# TYPE basic_udp_packets_sent_total counter
+basic_udp_packets_sent_total 3
+# HELP basic_udp_bytes_sent_total Bytes sent
+# TYPE basic_udp_bytes_sent_total counter
+basic_udp_bytes_sent_total 1536
+# HELP basic_udp_errors_total Requests that failed to be serviced or answered
+# TYPE basic_udp_errors_total counter
+basic_udp_errors_total 1
+# HELP basic_udp_active_transactions Requests being serviced right now
+# TYPE basic_udp_active_transactions gauge
+basic_udp_active_transactions 1
+# HELP basic_udp_bandwidth_limit_bytes_per_second Configured outbound bandwidth limit, 0 is unlimited
+# TYPE basic_udp_bandwidth_limit_bytes_per_second gauge
+basic_udp_bandwidth_limit_bytes_per_second 1000000
+# HELP basic_udp_throttled_seconds_total Time spent waiting on the bandwidth limit
+# TYPE basic_udp_throttled_seconds_total counter
+basic_udp_throttled_seconds_total 0.25
+# HELP basic_udp_request_duration_seconds Time taken to service a request
+# TYPE basic_udp_request_duration_seconds histogram
+basic_udp_request_duration_seconds_bucket{le=\"0.0001\"} 1
+basic_udp_request_duration_seconds_bucket{le=\"0.0005\"} 1
+basic_udp_request_duration_seconds_bucket{le=\"0.001\"} 1
+basic_udp_request_duration_seconds_bucket{le=\"0.005\"} 2
+basic_udp_request_duration_seconds_bucket{le=\"0.01\"} 2
+basic_udp_request_duration_seconds_bucket{le=\"0.05\"} 2
+basic_udp_request_duration_seconds_bucket{le=\"0.1\"} 2
+basic_udp_request_duration_seconds_bucket{le=\"0.5\"} 2
+basic_udp_request_duration_seconds_bucket{le=\"1\"} 2
+basic_udp_request_duration_seconds_bucket{le=\"5\"} 2
+basic_udp_request_duration_seconds_bucket{le=\"+Inf\"} 3
+basic_udp_request_duration_seconds_sum 10.0031
+basic_udp_request_duration_seconds_count 3
+";
+        assert_eq!(metrics.render(), expected);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn endpoint_serves_the_rendered_metrics() {
+        use std::io::{Read, Write};
+        use std::net::{TcpListener, TcpStream};
+        use std::sync::Arc;
+
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let metrics = Arc::new(Metrics::new(0));
+        metrics.error();
+        spawn_endpoint(&address, Arc::clone(&metrics)).unwrap();
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(&address).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(&format!("\r\n\r\n{}", metrics.render())));
+        assert!(response.contains("\nbasic_udp_errors_total 1\n"));
+        assert!(get("/other").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -0,0 +1,181 @@
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+
+///Control message setting the size UDP segmentation offload cuts a send into, libc only has it for some targets
+const UDP_SEGMENT: libc::c_int = 103;
+///Most segments the kernel cuts one send into
+const MAX_SEGMENTS: usize = 64;
+///Most UDP payload one send can carry, however it is cut up
+const MAX_PAYLOAD: usize = 65507;
+
+///sendmmsg messages carrying packets of a buffer to a target, packet i starts i*packet_size into it and is lens[i] long
+///With gso a run of full packets, and a shorter one after them, goes out as one message the kernel cuts into packets
+///They point into the buffer, it has to stay put as long as they are around
+pub(crate) struct Messages {
+    //First packet and packet count of every message
+    runs: Vec<(usize, usize)>,
+    //Everything the headers point at is on the heap, moving Messages doesn't move it
+    _address: Box<libc::sockaddr_storage>,
+    _iovecs: Vec<libc::iovec>,
+    _controls: Vec<[u64; 4]>,
+    headers: Vec<libc::mmsghdr>,
+}
+
+impl Messages {
+    pub(crate) fn new(target: SocketAddr, buffer: &[u8], packet_size: usize, lens: &[usize], gso: bool) -> Self {
+        let (address, address_len) = sockaddr(target);
+        let mut address = Box::new(address);
+        let max_segments = if gso { MAX_SEGMENTS.min(MAX_PAYLOAD/packet_size).max(1) } else { 1 };
+        let mut runs: Vec<(usize, usize)> = Vec::new();
+        let mut first = 0;
+        while first < lens.len() {
+            let mut count = 1;
+            //Only a full packet can have another one after it in the same message
+            while count < max_segments && first+count < lens.len() && lens[first+count-1] == packet_size {
+                count += 1;
+            }
+            runs.push((first, count));
+            first += count;
+        }
+        let mut iovecs: Vec<libc::iovec> = runs.iter().map(|(first, count)| libc::iovec {
+            iov_base: buffer[first*packet_size..].as_ptr() as *mut libc::c_void,
+            iov_len: (count-1)*packet_size + lens[first+count-1],
+        }).collect();
+        //u64s keep the control messages aligned
+        let mut controls: Vec<[u64; 4]> = vec![[0; 4]; runs.len()];
+        //Zeroed mmsghdrs are empty messages
+        let mut headers: Vec<libc::mmsghdr> = vec![unsafe { mem::zeroed() }; runs.len()];
+        for (i, message) in headers.iter_mut().enumerate() {
+            let header = &mut message.msg_hdr;
+            header.msg_name = &mut *address as *mut libc::sockaddr_storage as *mut libc::c_void;
+            header.msg_namelen = address_len;
+            header.msg_iov = &mut iovecs[i];
+            header.msg_iovlen = 1;
+            if runs[i].1 > 1 {
+                header.msg_control = controls[i].as_mut_ptr() as *mut libc::c_void;
+                //The control buffer has room for one cmsghdr and a u16, CMSG_FIRSTHDR can't come back null
+                unsafe {
+                    header.msg_controllen = libc::CMSG_SPACE(mem::size_of::<u16>() as u32) as _;
+                    let control = libc::CMSG_FIRSTHDR(header);
+                    (*control).cmsg_level = libc::SOL_UDP;
+                    (*control).cmsg_type = UDP_SEGMENT;
+                    (*control).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as _;
+                    ptr::write_unaligned(libc::CMSG_DATA(control) as *mut u16, packet_size as u16);
+                }
+            }
+        }
+        Self {
+            runs,
+            _address: address,
+            _iovecs: iovecs,
+            _controls: controls,
+            headers,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.headers.len()
+    }
+
+    ///First packet and packet count of message
+    #[cfg(feature = "io_uring")]
+    pub(crate) fn run(&self, message: usize) -> (usize, usize) {
+        self.runs[message]
+    }
+
+    #[cfg(feature = "io_uring")]
+    pub(crate) fn header(&self, message: usize) -> *const libc::msghdr {
+        &self.headers[message].msg_hdr
+    }
+
+    ///Whether the kernel cuts message into several packets
+    #[cfg(feature = "io_uring")]
+    pub(crate) fn segmented(&self, message: usize) -> bool {
+        self.runs[message].1 > 1
+    }
+}
+
+///Send packets from buffer to target with a single sendmmsg, laid out as Messages has them
+///Returns how many packets were sent, the kernel may stop before the end
+pub(crate) fn send(socket: &UdpSocket, target: SocketAddr, buffer: &[u8], packet_size: usize, lens: &[usize], gso: bool) -> io::Result<usize> {
+    let mut messages = Messages::new(target, buffer, packet_size, lens, gso);
+    //messages holds len() initialized mmsghdrs, everything they point at outlives the call
+    let sent = unsafe { libc::sendmmsg(socket.as_raw_fd(), messages.headers.as_mut_ptr(), messages.len() as _, 0) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(messages.runs.iter().take(sent as usize).map(|(_, count)| count).sum())
+}
+
+///Receive whatever datagrams are waiting on socket with a single recvmmsg without blocking, one to every packet_size of buffer
+///Their lengths go in lens and where they came from in sources, WouldBlock if there are none
+pub(crate) fn recv(socket: &UdpSocket, buffer: &mut [u8], packet_size: usize, lens: &mut Vec<usize>, sources: &mut Vec<SocketAddr>) -> io::Result<()> {
+    let slots = buffer.len()/packet_size;
+    //Zeroed sockaddr_storages and mmsghdrs are valid, the kernel fills them in
+    let mut addresses: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; slots];
+    let mut iovecs: Vec<libc::iovec> = buffer.chunks_exact_mut(packet_size).map(|slot| libc::iovec {
+        iov_base: slot.as_mut_ptr() as *mut libc::c_void,
+        iov_len: packet_size,
+    }).collect();
+    let mut messages: Vec<libc::mmsghdr> = vec![unsafe { mem::zeroed() }; slots];
+    for (i, message) in messages.iter_mut().enumerate() {
+        message.msg_hdr.msg_name = &mut addresses[i] as *mut libc::sockaddr_storage as *mut libc::c_void;
+        message.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        message.msg_hdr.msg_iov = &mut iovecs[i];
+        message.msg_hdr.msg_iovlen = 1;
+    }
+    //messages holds slots initialized mmsghdrs pointing into buffer and addresses
+    let received = unsafe { libc::recvmmsg(socket.as_raw_fd(), messages.as_mut_ptr(), slots as _, libc::MSG_DONTWAIT as _, ptr::null_mut()) };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    for (message, address) in messages.iter().zip(addresses.iter()).take(received as usize) {
+        if let Some(source) = socket_addr(address) {
+            lens.push((message.msg_len as usize).min(packet_size));
+            sources.push(source);
+        }
+    }
+    Ok(())
+}
+
+fn sockaddr(address: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    //A zeroed sockaddr_storage is valid, and big and aligned enough for either family
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match address {
+        SocketAddr::V4(a) => {
+            let sin = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = a.port().to_be();
+            sin.sin_addr.s_addr = u32::from_ne_bytes(a.ip().octets());
+            mem::size_of::<libc::sockaddr_in>()
+        },
+        SocketAddr::V6(a) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = a.port().to_be();
+            sin6.sin6_flowinfo = a.flowinfo();
+            sin6.sin6_addr.s6_addr = a.ip().octets();
+            sin6.sin6_scope_id = a.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        },
+    };
+    (storage, len as libc::socklen_t)
+}
+
+fn socket_addr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    //The family says which sockaddr the kernel wrote
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let sin = unsafe { &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in) };
+            Some(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes()), u16::from_be(sin.sin_port))))
+        },
+        libc::AF_INET6 => {
+            let sin6 = unsafe { &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in6) };
+            Some(SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(sin6.sin6_addr.s6_addr), u16::from_be(sin6.sin6_port), sin6.sin6_flowinfo, sin6.sin6_scope_id)))
+        },
+        _ => None,
+    }
+}
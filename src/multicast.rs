@@ -0,0 +1,138 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use log::{debug, info, warn};
+use crate::config::MulticastConfig;
+use crate::fec;
+use crate::fec::BlockEncoder;
+use crate::rate_limit::TokenBucket;
+use crate::{file_epoch, pack_u64_into_u8arr, read_chunk, send_paced, ServerState, CONTROL_CHUNK, MULTICAST_INFO};
+
+///Chunks sent between two info packets, receivers that join late or a file that changes are noticed this soon
+const INFO_INTERVAL: u64 = 1024;
+///The sender bursts at most this fraction of a second worth of data
+const BURST_DIVISOR: u64 = 100;
+
+///Populates a buffer with the control packet telling multicast receivers which version of the file is being sent, returns how many bytes are in the packet
+///CONTROL_CHUNK, MULTICAST_INFO, u64 chunk count and u64 epoch
+pub fn info_packet(chunk_count: u64, epoch: u64, buffer: &mut [u8]) -> usize {
+    let mut byte_counter: usize = 0;
+    for value in [CONTROL_CHUNK, MULTICAST_INFO, chunk_count, epoch].iter() {
+        buffer[byte_counter..byte_counter+8].copy_from_slice(&pack_u64_into_u8arr(*value));
+        byte_counter += 8;
+    }
+    byte_counter
+}
+
+///The IPv6 hop limit of multicast packets sent on socket, what ttl is for IPv4
+#[cfg(target_os = "linux")]
+fn set_multicast_hops_v6(socket: &UdpSocket, hops: u32) -> io::Result<()> {
+    use std::convert::TryFrom;
+    use std::os::unix::io::AsRawFd;
+    let hops = libc::c_int::try_from(hops).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let result = unsafe {
+        libc::setsockopt(socket.as_raw_fd(), libc::IPPROTO_IPV6, libc::IPV6_MULTICAST_HOPS,
+            &hops as *const libc::c_int as *const libc::c_void, mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+///Other platforms keep their default hop limit of 1
+#[cfg(not(target_os = "linux"))]
+fn set_multicast_hops_v6(_socket: &UdpSocket, hops: u32) -> io::Result<()> {
+    if hops != 1 {
+        warn!(ttl = hops; "The hop limit of IPv6 multicast groups can't be set on this platform, keeping 1");
+    }
+    Ok(())
+}
+
+///Start sending the file at path to the group of config round after round, in a thread of its own that runs as long as the server
+pub(crate) fn spawn_sender(config: MulticastConfig, path: PathBuf, state: Arc<ServerState>) -> io::Result<thread::JoinHandle<()>> {
+    let group = match config.group_addr() {
+        Some(g) => g,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} is not a multicast group", config.group))),
+    };
+    //Bound to the interface's address, the kernel sends multicast out of the interface owning it
+    let socket = UdpSocket::bind(SocketAddr::new(config.interface, 0))?;
+    if group.is_ipv4() {
+        socket.set_multicast_ttl_v4(config.ttl)?;
+        socket.set_multicast_loop_v4(true)?;
+    } else {
+        set_multicast_hops_v6(&socket, config.ttl)?;
+        socket.set_multicast_loop_v6(true)?;
+    }
+    info!(group:% = group, file:% = config.file, bytes_per_second = config.bytes_per_second; "Sending to multicast group");
+    Ok(thread::spawn(move || {
+        //Nobody on the group can ask for a slower pace, so no bursting after the pause between rounds
+        let burst = (config.bytes_per_second/BURST_DIVISOR).max(state.config.packet_size as u64);
+        let mut bandwidth = TokenBucket::with_burst(config.bytes_per_second, burst);
+        loop {
+            if let Err(e) = send_round(&path, &socket, group, &state, &mut bandwidth) {
+                state.metrics.error();
+                warn!(group:% = group, file:% = config.file, error:% = e; "Unable to send a multicast round");
+            }
+            thread::sleep(Duration::from_secs(config.pause_seconds));
+        }
+    }))
+}
+
+///Send every chunk of the file once, each block followed by its repair packets if FEC is enabled
+///The round ends early if the file changes, so the next one sends the new version
+fn send_round(path: &Path, socket: &UdpSocket, group: SocketAddr, state: &ServerState, bandwidth: &mut TokenBucket) -> io::Result<()> {
+    let ServerState { config, metrics, .. } = state;
+    let chunk_size = config.packet_size - mem::size_of::<u64>();
+    let file = File::open(path)?;
+    let metadata = file.metadata()?;
+    let chunk_count = metadata.len().div_ceil(chunk_size as u64);
+    let epoch = file_epoch(&metadata);
+    let mut send_buffer: Vec<u8> = vec![0; config.packet_size];
+    let mut encoder = if config.fec.repair_chunks != 0 {
+        Some(BlockEncoder::new(&config.fec, chunk_size, chunk_count))
+    } else {
+        None
+    };
+    let mut packets: u64 = 0;
+    //An empty file still gets its info packet, reading its first chunk then ends the round
+    for chunk in 0..chunk_count.max(1) {
+        if chunk % INFO_INTERVAL == 0 {
+            if chunk != 0 && file_epoch(&fs::metadata(path)?) != epoch {
+                info!(group:% = group, path:? = path; "File changed, starting the next multicast round");
+                return Ok(());
+            }
+            let bytes_to_send = info_packet(chunk_count, epoch, &mut send_buffer);
+            send_paced(socket, group, &send_buffer[0..bytes_to_send], metrics, bandwidth, None)?;
+            packets += 1;
+        }
+        let bytes_read = read_chunk(&file, chunk, &mut send_buffer[8..8+chunk_size])?;
+        if bytes_read == 0 {
+            break;
+        }
+        send_buffer[0..8].copy_from_slice(&pack_u64_into_u8arr(chunk));
+        send_paced(socket, group, &send_buffer[0..8+bytes_read], metrics, bandwidth, None)?;
+        packets += 1;
+
+        let repairs = match encoder.as_mut() {
+            Some(encoder) => encoder.sent(chunk, &send_buffer[8..8+bytes_read])?,
+            None => None,
+        };
+        if let Some((block, repairs)) = repairs {
+            for (r, repair) in repairs.iter().enumerate() {
+                let bytes_to_send = fec::repair_packet(block, r as u64, repairs.len() as u64, repair, &mut send_buffer);
+                send_paced(socket, group, &send_buffer[0..bytes_to_send], metrics, bandwidth, None)?;
+                metrics.repair_sent();
+                packets += 1;
+            }
+        }
+    }
+    debug!(group:% = group, path:? = path, chunks = chunk_count, packets = packets; "Sent a multicast round");
+    Ok(())
+}
@@ -1,5 +1,6 @@
 use std::collections::HashSet;
 use std::fmt;
+use log::error;
 
 pub struct Node {
     pub start: usize,
@@ -51,8 +52,8 @@ impl RangeTree
 
         Self{
             root: 0,
-            intervals: intervals,
-            tree_vec: tree_vec,
+            intervals,
+            tree_vec,
         }
     }
 
@@ -69,17 +70,15 @@ impl RangeTree
         let old_root: usize = i;
         let new_root: usize;
         let replaced_child: Option<usize>;
-        let left_depth: usize;
-        let right_depth: usize;
         //Shrug the tree at a given node to balance it
-        match self.tree_vec[i].left {
-            Some(ld) => {left_depth = self.tree_vec[ld].depth},
-            None => {left_depth = 0}
-        }
-        match self.tree_vec[i].right {
-            Some(rd) => {right_depth = self.tree_vec[rd].depth},
-            None => {right_depth = 0}
-        }
+        let left_depth: usize = match self.tree_vec[i].left {
+            Some(ld) => self.tree_vec[ld].depth,
+            None => 0
+        };
+        let right_depth: usize = match self.tree_vec[i].right {
+            Some(rd) => self.tree_vec[rd].depth,
+            None => 0
+        };
         if left_depth > right_depth {
             //Shrug left case
             //Obtain all relevant node indexes
@@ -88,7 +87,7 @@ impl RangeTree
                     new_root = nr;
                 },
                 None => {
-                    println!("Attempting to balance favoring a node that doesn't exist!");
+                    error!(node = i; "Attempting to balance favoring a node that doesn't exist");
                     return i
                 }
             }
@@ -142,7 +141,7 @@ impl RangeTree
                     new_root = nr;
                 },
                 None => {
-                    println!("Attempting to balance favoring a node that doesn't exist!");
+                    error!(node = i; "Attempting to balance favoring a node that doesn't exist");
                     return i
                 }
             }
@@ -193,17 +192,14 @@ impl RangeTree
         }
 
         //Just need to fix the depth numbers on the old root, the new root will still be correct
-        let old_root_left_depth: usize;
-        let old_root_right_depth: usize;
-
-        match self.tree_vec[old_root].left {
-            Some(ld) => {old_root_left_depth = self.tree_vec[ld].depth},
-            None => {old_root_left_depth = 0}
-        }
-        match self.tree_vec[old_root].right {
-            Some(rd) => {old_root_right_depth = self.tree_vec[rd].depth},
-            None => {old_root_right_depth = 0}
-        }
+        let old_root_left_depth: usize = match self.tree_vec[old_root].left {
+            Some(ld) => self.tree_vec[ld].depth,
+            None => 0
+        };
+        let old_root_right_depth: usize = match self.tree_vec[old_root].right {
+            Some(rd) => self.tree_vec[rd].depth,
+            None => 0
+        };
 
         if old_root_left_depth > old_root_right_depth {
             self.tree_vec[old_root].depth = old_root_left_depth+1;
@@ -225,8 +221,7 @@ impl RangeTree
                     //We're good to proceed!
                 },
                 None => {
-                    println!("Somehow attempted to traverse to a non-existent node {:?}",traverser);
-                    println!("Root's got {:?} {:?}",self.tree_vec[self.root].start,self.tree_vec[self.root].end);
+                    error!(node = traverser, root_start = self.tree_vec[self.root].start, root_end = self.tree_vec[self.root].end; "Attempted to traverse to a non-existent node");
                     break;
                 }
             }
@@ -287,18 +282,15 @@ impl RangeTree
                     //Now adjust depths and check for rebalance opportunities all the way up
                     let mut depth_traverser = traverser;
                     loop {
-                        let left_depth: usize;
-                        let right_depth: usize;
-                        let parent: Option<usize>;
-                        parent = self.tree_vec[depth_traverser].parent;
-                        match self.tree_vec[depth_traverser].left {
-                            Some(ld) => {left_depth = self.tree_vec[ld].depth},
-                            None => {left_depth = 0}
-                        }
-                        match self.tree_vec[depth_traverser].right {
-                            Some(rd) => {right_depth = self.tree_vec[rd].depth},
-                            None => {right_depth = 0}
-                        }
+                        let parent: Option<usize> = self.tree_vec[depth_traverser].parent;
+                        let left_depth: usize = match self.tree_vec[depth_traverser].left {
+                            Some(ld) => self.tree_vec[ld].depth,
+                            None => 0
+                        };
+                        let right_depth: usize = match self.tree_vec[depth_traverser].right {
+                            Some(rd) => self.tree_vec[rd].depth,
+                            None => 0
+                        };
 
                         if left_depth > right_depth {
                             self.tree_vec[depth_traverser].depth = left_depth+1;
@@ -307,16 +299,6 @@ impl RangeTree
                         }
 
                         if left_depth > right_depth+1 || right_depth > left_depth+1 {
-                            /*println!("We have a chance to rebalance - Left: {:?} Right: {:?}",left_depth,right_depth);
-                            let troot = self.balance(depth_traverser);
-                            match self.tree_vec[troot].left {
-                                Some(ld) => {println!("Left {:?}",self.tree_vec[ld].depth)},
-                                None => {println!("Left is 0")}
-                            }
-                            match self.tree_vec[troot].right {
-                                Some(rd) => {println!("Right {:?}",self.tree_vec[rd].depth)},
-                                None => {println!("Right is 0")}
-                            }*/
                             self.balance(depth_traverser);
                         }
                         match parent {
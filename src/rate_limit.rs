@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+///Token bucket allowing rate units per second with bursts of up to one second worth unless built with_burst
+///A rate of 0 means unlimited
+pub struct TokenBucket {
+    rate: u64,
+    burst: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        Self::with_burst(rate, rate)
+    }
+
+    ///Bucket holding at most burst units, for senders whose receivers can't ask them to slow down
+    pub fn with_burst(rate: u64, burst: u64) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst as f64,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.burst as f64);
+    }
+
+    ///Take amount tokens if they are available right now
+    pub fn try_take(&mut self, amount: u64) -> bool {
+        if self.rate == 0 {
+            return true;
+        }
+        self.refill();
+        if self.tokens >= amount as f64 {
+            self.tokens -= amount as f64;
+            true
+        } else {
+            false
+        }
+    }
+
+    ///Take amount tokens, sleeping until enough have accumulated, returns how long it slept
+    pub fn take(&mut self, amount: u64) -> Duration {
+        if self.rate == 0 {
+            return Duration::ZERO;
+        }
+        self.refill();
+        self.tokens -= amount as f64;
+        if self.tokens < 0.0 {
+            //Go into debt and sleep it off, this keeps large amounts from starving forever
+            let debt = Duration::from_secs_f64(-self.tokens / self.rate as f64);
+            thread::sleep(debt);
+            return debt;
+        }
+        Duration::ZERO
+    }
+}
+
+///Per client address request limiter, shared by every worker so a client gets rate requests a second however many there are
+pub struct ClientLimiter {
+    rate: u64,
+    clients: Mutex<Clients>,
+}
+
+///Bucket of every client heard from lately
+struct Clients {
+    buckets: HashMap<IpAddr, TokenBucket>,
+    last_cleanup: Instant,
+}
+
+impl ClientLimiter {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            clients: Mutex::new(Clients {
+                buckets: HashMap::new(),
+                last_cleanup: Instant::now(),
+            }),
+        }
+    }
+
+    ///Count a request from ip, returns false if the client is over its limit
+    pub fn allow(&self, ip: IpAddr) -> bool {
+        if self.rate == 0 {
+            return true;
+        }
+        let mut clients = match self.clients.lock() {
+            Ok(c) => c,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        //Forget clients that have been quiet long enough to have a full bucket again
+        if clients.last_cleanup.elapsed() > Duration::from_secs(10) {
+            clients.buckets.retain(|_, b| b.last.elapsed() < Duration::from_secs(1));
+            clients.last_cleanup = Instant::now();
+        }
+        let rate = self.rate;
+        clients.buckets.entry(ip).or_insert_with(|| TokenBucket::new(rate)).try_take(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn client_limit_holds_across_workers() {
+        let limiter = Arc::new(ClientLimiter::new(20));
+        let client: IpAddr = "192.0.2.7".parse().unwrap();
+        //Four workers taking requests of the same client off the same sockets
+        let workers: Vec<_> = (0..4).map(|_| {
+            let limiter = Arc::clone(&limiter);
+            thread::spawn(move || (0..50).filter(|_| limiter.allow(client)).count())
+        }).collect();
+        let allowed: usize = workers.into_iter().map(|w| w.join().unwrap()).sum();
+        //A second's worth up front, plus whatever trickled in while the workers ran
+        assert!((20..=22).contains(&allowed), "{} allowed", allowed);
+        //Other clients have buckets of their own
+        assert!(limiter.allow("192.0.2.8".parse().unwrap()));
+    }
+
+    #[test]
+    fn zero_rate_allows_everything() {
+        let limiter = ClientLimiter::new(0);
+        let client: IpAddr = "::1".parse().unwrap();
+        assert!((0..1000).all(|_| limiter.allow(client)));
+    }
+}
@@ -0,0 +1,429 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use log::{debug, info};
+use sha2::{Digest, Sha256};
+use crate::config::SessionConfig;
+use crate::rate_limit::TokenBucket;
+use crate::{pack_name, pack_ranges, pack_u64_into_u8arr, unpack_u8arr_into_u64, RangeRequest};
+
+///Packet ID of a request to open a file within a session
+pub const SESSION_OPEN: u64 = 6;
+///Packet ID of a chunk request for a file opened within a session
+pub const SESSION_CHUNKS: u64 = 7;
+///Packet ID of a request to close a file or a whole session
+pub const SESSION_CLOSE: u64 = 8;
+///Packet ID of a chunk request for a file opened within a session with its ranges packed by pack_ranges
+pub const SESSION_COMPACT_CHUNKS: u64 = 10;
+///Packet ID of a report of lost chunks of a file opened within a session, packed like SESSION_COMPACT_CHUNKS
+pub const SESSION_NACK_CHUNKS: u64 = 12;
+///Packet ID of a chunk request for a file opened within a session that also wants repair packets, packed like SESSION_COMPACT_CHUNKS
+pub const SESSION_FEC_CHUNKS: u64 = 14;
+///Packet ID of a request for the hashes of chunks of a file opened within a session, packed like SESSION_COMPACT_CHUNKS
+pub const SESSION_HASH_CHUNKS: u64 = 16;
+///Packet ID of a request for Merkle tree nodes of a file opened within a session, packed like SESSION_COMPACT_CHUNKS with node IDs in place of chunks
+pub const SESSION_MERKLE_NODES: u64 = 18;
+
+///Outcome of opening a file in a session, sent back to the client in the open response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenResult {
+    Opened = 0,
+    NotAllowed = 1,
+    Disabled = 2,
+    TooMany = 3,
+    UnknownSession = 4,
+}
+
+///A file held open for a session, it keeps serving the version that was opened even if the file is replaced
+pub(crate) struct OpenFile {
+    pub(crate) filename: String,
+    ///Canonical path the file was opened from, what the server caches about it is keyed by it
+    pub(crate) path: PathBuf,
+    pub(crate) file: File,
+    ///Epoch of the file when it was opened, a different one means it was rewritten in place
+    pub(crate) epoch: u64,
+}
+
+///Server side state of one client session
+pub(crate) struct Session {
+    client: SocketAddr,
+    opened: Instant,
+    last_activity: Instant,
+    next_handle: u64,
+    pub(crate) files: HashMap<u64, OpenFile>,
+    ///Outbound bandwidth of this session alone, on top of the server wide limit
+    pub(crate) bandwidth: TokenBucket,
+    requests: u64,
+    packets: u64,
+    bytes: u64,
+}
+
+type SharedSession = Arc<Mutex<Session>>;
+
+///Server side bookkeeping of every open session, shared between workers
+///The owning client is kept next to each session so finding one never waits on a session busy sending
+pub struct SessionManager {
+    config: SessionConfig,
+    sessions: Mutex<HashMap<u64, (SocketAddr, SharedSession)>>,
+    counter: Mutex<u64>,
+    seed: u64,
+}
+
+impl SessionManager {
+    pub fn new(config: &SessionConfig) -> Self {
+        Self {
+            config: config.clone(),
+            sessions: Mutex::new(HashMap::new()),
+            counter: Mutex::new(0),
+            //Randomly keyed so session IDs can't be predicted from the time and the client address
+            seed: RandomState::new().build_hasher().finish(),
+        }
+    }
+
+    ///Hold file open under a new handle, in a new session when session_id is 0
+    ///Returns the session ID and the handle, both 0 unless the result is Opened
+    pub fn open(&self, session_id: u64, client: SocketAddr, filename: &str, path: &Path, file: File, epoch: u64) -> (u64, u64, OpenResult) {
+        if !self.config.enabled {
+            return (0, 0, OpenResult::Disabled);
+        }
+        let mut sessions = lock(&self.sessions);
+        let (session_id, session) = if session_id == 0 {
+            let open_sessions = sessions.values().filter(|(c, _)| c.ip() == client.ip()).count();
+            if open_sessions >= self.config.max_per_client {
+                return (0, 0, OpenResult::TooMany);
+            }
+            let session_id = self.new_session_id(client);
+            let session = Arc::new(Mutex::new(Session {
+                client,
+                opened: Instant::now(),
+                last_activity: Instant::now(),
+                next_handle: 1,
+                files: HashMap::new(),
+                bandwidth: TokenBucket::new(self.config.bytes_per_second),
+                requests: 0,
+                packets: 0,
+                bytes: 0,
+            }));
+            sessions.insert(session_id, (client, Arc::clone(&session)));
+            debug!(peer:% = client, session = session_id; "Session started");
+            (session_id, session)
+        } else {
+            match sessions.get(&session_id) {
+                Some((c, s)) if c.ip() == client.ip() => (session_id, Arc::clone(s)),
+                _ => return (0, 0, OpenResult::UnknownSession),
+            }
+        };
+        drop(sessions);
+
+        let mut session = lock(&session);
+        if session.files.len() >= self.config.max_files {
+            return (0, 0, OpenResult::TooMany);
+        }
+        let handle = session.next_handle;
+        session.next_handle += 1;
+        session.last_activity = Instant::now();
+        session.requests += 1;
+        session.files.insert(handle, OpenFile {
+            filename: String::from(filename),
+            path: path.to_path_buf(),
+            file,
+            epoch,
+        });
+        (session_id, handle, OpenResult::Opened)
+    }
+
+    ///The session a request refers to, None if it doesn't exist or belongs to another client
+    pub(crate) fn get(&self, session_id: u64, client: SocketAddr) -> Option<SharedSession> {
+        let sessions = lock(&self.sessions);
+        match sessions.get(&session_id) {
+            Some((c, s)) if c.ip() == client.ip() => Some(Arc::clone(s)),
+            _ => None,
+        }
+    }
+
+    ///Close one file of a session, a handle of 0 closes the whole session
+    pub fn close(&self, session_id: u64, handle: u64, client: SocketAddr) {
+        if handle != 0 {
+            if let Some(session) = self.get(session_id, client) {
+                let mut session = lock(&session);
+                session.files.remove(&handle);
+                session.requests += 1;
+                session.last_activity = Instant::now();
+            }
+            return;
+        }
+        //Out of the map first, the map lock is never taken while holding a session
+        let removed = {
+            let mut sessions = lock(&self.sessions);
+            match sessions.get(&session_id) {
+                Some((c, _)) if c.ip() == client.ip() => sessions.remove(&session_id),
+                _ => None,
+            }
+        };
+        if let Some((_, session)) = removed {
+            let mut session = lock(&session);
+            session.requests += 1;
+            log_end(session_id, &session, "closed");
+        }
+    }
+
+    ///Drop sessions that have been idle too long
+    pub fn sweep(&self) {
+        let idle_timeout = Duration::from_secs(self.config.idle_timeout_seconds);
+        lock(&self.sessions).retain(|session_id, (_, session)| {
+            //A session busy servicing a request is clearly not idle
+            let session = match session.try_lock() {
+                Ok(s) => s,
+                Err(_) => return true,
+            };
+            let keep = session.last_activity.elapsed() < idle_timeout;
+            if !keep {
+                log_end(*session_id, &session, "timed out");
+            }
+            keep
+        });
+    }
+
+    ///Hard to guess ID for a new session, it is what authorizes the requests that follow
+    fn new_session_id(&self, client: SocketAddr) -> u64 {
+        let mut counter = lock(&self.counter);
+        *counter += 1;
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+        let mut hasher = Sha256::new();
+        hasher.update(pack_u64_into_u8arr(self.seed));
+        hasher.update(nanos.to_be_bytes());
+        hasher.update(pack_u64_into_u8arr(*counter));
+        hasher.update(client.to_string().as_bytes());
+        let digest = hasher.finalize();
+        //0 means no session, never hand it out
+        unpack_u8arr_into_u64(&digest[0..8]).max(1)
+    }
+}
+
+impl Session {
+    ///Count a serviced chunk request
+    pub(crate) fn record(&mut self, packets: u64, bytes: u64) {
+        self.last_activity = Instant::now();
+        self.requests += 1;
+        self.packets += packets;
+        self.bytes += bytes;
+    }
+}
+
+fn log_end(session_id: u64, session: &Session, reason: &str) {
+    info!(peer:% = session.client, session = session_id, reason = reason, files = session.files.len(), requests = session.requests,
+        packets = session.packets, bytes = session.bytes, duration_ms = session.opened.elapsed().as_millis() as u64; "Session ended");
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(m) => m,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+///Populates a buffer with a request to open fname, in the existing session_id or a new one if it is 0, returns how many bytes are in the packet
+///Fails if fname doesn't fit in the packet, flags is COMPRESS_REQUEST to open the pre-compressed variant of the file if there is one
+///Flags go after the name like in metadata requests, and are left out when there are none
+pub fn open_request_packet(session_id: u64, fname: &str, flags: u64, buffer: &mut [u8]) -> io::Result<usize> {
+    buffer[0..8].copy_from_slice(&pack_u64_into_u8arr(SESSION_OPEN));
+    buffer[8..16].copy_from_slice(&pack_u64_into_u8arr(session_id));
+    let byte_counter = 16+pack_name(fname, buffer, 16, if flags != 0 { 8 } else { 0 })?;
+    if flags == 0 {
+        return Ok(byte_counter);
+    }
+    buffer[byte_counter..byte_counter+8].copy_from_slice(&pack_u64_into_u8arr(flags));
+    Ok(byte_counter+8)
+}
+
+///u64 ID, u64 session ID, u64 handle, u64 chunk count, u64 chunk size, u64 OpenResult and u64 features
+///Servers sending repair packets add how the file is protected after it
+pub fn open_response_packet(session_id: u64, handle: u64, chunk_count: u64, chunk_size: u64, result: OpenResult, features: u64, buffer: &mut [u8]) -> usize {
+    let mut byte_counter: usize = 0;
+    for value in [SESSION_OPEN, session_id, handle, chunk_count, chunk_size, result as u64, features].iter() {
+        buffer[byte_counter..byte_counter+8].copy_from_slice(&pack_u64_into_u8arr(*value));
+        byte_counter += 8;
+    }
+    byte_counter
+}
+
+///Populates a buffer with a request for ranges of chunks of an open file, as many ranges as fit, returns how many bytes are in the packet
+pub fn chunk_request_packet(session_id: u64, handle: u64, starts: &[u64], ends: &[u64], buffer: &mut [u8]) -> usize {
+    let count = starts.len().min((buffer.len() - 32)/16);
+    let mut byte_counter: usize = 0;
+    for value in [SESSION_CHUNKS, session_id, handle, count as u64].iter() {
+        buffer[byte_counter..byte_counter+8].copy_from_slice(&pack_u64_into_u8arr(*value));
+        byte_counter += 8;
+    }
+    for (s, e) in starts[0..count].iter().zip(ends[0..count].iter()) {
+        buffer[byte_counter..byte_counter+8].copy_from_slice(&pack_u64_into_u8arr(*s));
+        buffer[byte_counter+8..byte_counter+16].copy_from_slice(&pack_u64_into_u8arr(*e));
+        byte_counter += 16;
+    }
+    byte_counter
+}
+
+///Populates a buffer with a request for as many ranges of chunks of an open file as fit, packed by pack_ranges, returns how many bytes are in the packet
+///Only for servers with FEATURE_COMPACT_RANGES
+///request picks whether the chunks are asked for, reported lost or asked for along with repair packets
+///flags is COMPRESS_REQUEST to ask for them compressed, the handle already says whether it is a pre-compressed variant
+pub fn compact_chunk_request_packet(session_id: u64, handle: u64, starts: &[u64], ends: &[u64], request: RangeRequest, flags: u64, buffer: &mut [u8]) -> usize {
+    let id = match request {
+        RangeRequest::Chunks => SESSION_COMPACT_CHUNKS,
+        RangeRequest::Lost => SESSION_NACK_CHUNKS,
+        RangeRequest::WithRepair => SESSION_FEC_CHUNKS,
+        RangeRequest::Hashes => SESSION_HASH_CHUNKS,
+        RangeRequest::Nodes => SESSION_MERKLE_NODES,
+    };
+    let mut byte_counter: usize = 0;
+    for value in [id | flags, session_id, handle].iter() {
+        buffer[byte_counter..byte_counter+8].copy_from_slice(&pack_u64_into_u8arr(*value));
+        byte_counter += 8;
+    }
+    byte_counter + pack_ranges(starts, ends, buffer, byte_counter)
+}
+
+///Populates a buffer with a request to close handle, or the whole session if handle is 0, returns how many bytes are in the packet
+pub fn close_request_packet(session_id: u64, handle: u64, buffer: &mut [u8]) -> usize {
+    let mut byte_counter: usize = 0;
+    for value in [SESSION_CLOSE, session_id, handle].iter() {
+        buffer[byte_counter..byte_counter+8].copy_from_slice(&pack_u64_into_u8arr(*value));
+        byte_counter += 8;
+    }
+    byte_counter
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use crate::{server_handle_inbound, ChunkTransaction, TransactionKind, COMPRESS_REQUEST};
+
+    fn manager(max_per_client: usize, max_files: usize) -> SessionManager {
+        SessionManager::new(&SessionConfig { enabled: true, max_per_client, max_files, ..SessionConfig::default() })
+    }
+
+    fn client(address: &str) -> SocketAddr {
+        address.parse().unwrap()
+    }
+
+    ///Opens a file that is sure to be there
+    fn open(manager: &SessionManager, session_id: u64, client: SocketAddr) -> (u64, u64, OpenResult) {
+        let path = std::env::current_exe().unwrap();
+        manager.open(session_id, client, "file", &path, File::open(&path).unwrap(), 1)
+    }
+
+    ///The transaction the server makes of a request packet
+    fn parse(packet: &[u8]) -> ChunkTransaction {
+        let mut transactions = VecDeque::new();
+        server_handle_inbound(packet.len(), client("127.0.0.1:5000"), &mut transactions, packet);
+        assert_eq!(transactions.len(), 1);
+        transactions.pop_front().unwrap()
+    }
+
+    fn ranges(t: &ChunkTransaction) -> Vec<(u64, u64)> {
+        t.starts.iter().cloned().zip(t.ends.iter().cloned()).collect()
+    }
+
+    #[test]
+    fn files_open_and_close_within_sessions() {
+        let disabled = SessionManager::new(&SessionConfig::default());
+        assert_eq!(open(&disabled, 0, client("127.0.0.1:5000")), (0, 0, OpenResult::Disabled));
+
+        let manager = manager(2, 2);
+        let owner = client("127.0.0.1:5000");
+        let (session_id, handle, result) = open(&manager, 0, owner);
+        assert_eq!(result, OpenResult::Opened);
+        assert!(session_id != 0);
+        assert_eq!(handle, 1);
+        //The client's port doesn't matter, its address does
+        assert_eq!(open(&manager, session_id, client("127.0.0.1:6000")), (session_id, 2, OpenResult::Opened));
+        assert_eq!(open(&manager, session_id, owner), (0, 0, OpenResult::TooMany));
+        assert_eq!(open(&manager, session_id, client("192.0.2.1:5000")), (0, 0, OpenResult::UnknownSession));
+        assert_eq!(open(&manager, session_id+1, owner), (0, 0, OpenResult::UnknownSession));
+        assert!(manager.get(session_id, client("192.0.2.1:5000")).is_none());
+
+        let files = |session_id: u64| -> Vec<u64> {
+            let mut handles: Vec<u64> = lock(&manager.get(session_id, owner).unwrap()).files.keys().cloned().collect();
+            handles.sort_unstable();
+            handles
+        };
+        assert_eq!(files(session_id), vec![1, 2]);
+        manager.close(session_id, 1, owner);
+        assert_eq!(files(session_id), vec![2]);
+        assert_eq!(open(&manager, session_id, owner), (session_id, 3, OpenResult::Opened));
+
+        let (second, _, _) = open(&manager, 0, owner);
+        assert!(second != 0 && second != session_id);
+        assert_eq!(open(&manager, 0, owner), (0, 0, OpenResult::TooMany));
+        //Only the owner closes a session
+        manager.close(second, 0, client("192.0.2.1:5000"));
+        assert!(manager.get(second, owner).is_some());
+        manager.close(second, 0, owner);
+        assert!(manager.get(second, owner).is_none());
+        assert_eq!(open(&manager, 0, client("192.0.2.1:5000")).2, OpenResult::Opened);
+        assert_eq!(open(&manager, 0, owner).2, OpenResult::Opened);
+    }
+
+    #[test]
+    fn idle_sessions_expire() {
+        let manager = manager(8, 8);
+        let owner = client("127.0.0.1:5000");
+        let (idle, _, _) = open(&manager, 0, owner);
+        let (busy, _, _) = open(&manager, 0, owner);
+        let long_ago = Instant::now().checked_sub(Duration::from_secs(61)).unwrap();
+        lock(&manager.get(idle, owner).unwrap()).last_activity = long_ago;
+
+        manager.sweep();
+        assert!(manager.get(idle, owner).is_none());
+        assert!(manager.get(busy, owner).is_some());
+        assert_eq!(open(&manager, idle, owner), (0, 0, OpenResult::UnknownSession));
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        let mut buffer = [0; 512];
+        let length = open_request_packet(7, "dir/file.bin", 0, &mut buffer).unwrap();
+        let t = parse(&buffer[0..length]);
+        assert!(matches!(t.kind, TransactionKind::SessionOpen(7)));
+        assert_eq!(t.filename, "dir/file.bin");
+        assert!(!t.compress);
+        let length = open_request_packet(0, "file.bin", COMPRESS_REQUEST, &mut buffer).unwrap();
+        let t = parse(&buffer[0..length]);
+        assert!(matches!(t.kind, TransactionKind::SessionOpen(0)));
+        assert!(t.compress);
+        assert!(open_request_packet(0, &"x".repeat(600), 0, &mut buffer).is_err());
+
+        let length = chunk_request_packet(7, 3, &[0, 10], &[4, 20], &mut buffer);
+        let t = parse(&buffer[0..length]);
+        assert!(matches!(t.kind, TransactionKind::SessionChunks(7, 3)));
+        assert_eq!(ranges(&t), vec![(0, 4), (10, 20)]);
+        //As many ranges as fit
+        let starts: Vec<u64> = (0..100).map(|i| i*10).collect();
+        let length = chunk_request_packet(7, 3, &starts, &starts, &mut buffer[0..64]);
+        assert_eq!(ranges(&parse(&buffer[0..length])).len(), 2);
+
+        for request in [RangeRequest::Chunks, RangeRequest::Lost, RangeRequest::WithRepair, RangeRequest::Hashes, RangeRequest::Nodes].iter() {
+            let length = compact_chunk_request_packet(7, 3, &[10, 0], &[20, 4], *request, COMPRESS_REQUEST, &mut buffer);
+            let t = parse(&buffer[0..length]);
+            assert!(matches!(t.kind, TransactionKind::SessionChunks(7, 3)));
+            assert_eq!(ranges(&t), vec![(0, 4), (10, 20)]);
+            assert!(t.compress);
+            assert_eq!((t.lost, t.repair, t.hashes, t.nodes), (
+                *request == RangeRequest::Lost, *request == RangeRequest::WithRepair, *request == RangeRequest::Hashes, *request == RangeRequest::Nodes));
+        }
+
+        let length = close_request_packet(7, 0, &mut buffer);
+        assert!(matches!(parse(&buffer[0..length]).kind, TransactionKind::SessionClose(7, 0)));
+
+        let length = open_response_packet(7, 3, 100, 504, OpenResult::TooMany, 5, &mut buffer);
+        let fields: Vec<u64> = buffer[0..length].chunks(8).map(unpack_u8arr_into_u64).collect();
+        assert_eq!(fields, vec![SESSION_OPEN, 7, 3, 100, 504, OpenResult::TooMany as u64, 5]);
+    }
+}
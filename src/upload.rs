@@ -0,0 +1,790 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::mem;
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use log::{error, info, warn};
+use crate::config::UploadConfig;
+use crate::range_tree::RangeTree;
+use crate::{pack_name, pack_u64_into_u8arr, unpack_u8arr_into_u64, unpack_varint};
+
+///Packet ID of an upload announcement
+pub const UPLOAD_ANNOUNCE: u64 = 3;
+///Packet ID of a chunk of upload data
+pub const UPLOAD_CHUNK: u64 = 4;
+///Packet ID of an upload status request
+pub const UPLOAD_STATUS: u64 = 5;
+
+///Announcements older or newer than this are refused so a captured one can't be replayed later
+const ANNOUNCE_MAX_SKEW: u64 = 300;
+///Uploads nobody has touched for this long are dropped along with their staging file
+const UPLOAD_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+type HmacSha256 = Hmac<Sha256>;
+
+///Why an announcement was refused, sent back to the client in the announce response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceResult {
+    Accepted = 0,
+    Unauthorized = 1,
+    InvalidName = 2,
+    TooLarge = 3,
+    Exists = 4,
+    Disabled = 5,
+    Failed = 6,
+    ///Not enough free space left in the upload directory
+    NoSpace = 7,
+}
+
+///Where an upload stands, sent back to the client in status responses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadState {
+    Unknown = 0,
+    Receiving = 1,
+    Complete = 2,
+    HashMismatch = 3,
+    ///Every chunk is in, the server is checking the hash and moving the file into place
+    Verifying = 4,
+    ///A file with the same name showed up while the upload was received and overwrite is off
+    Exists = 5,
+}
+
+///An upload announcement, everything the server needs to accept a file
+///
+///name: String, Where the file goes, relative to the upload directory
+///size: u64, Size of the file in bytes
+///hash: [u8; 32], SHA-256 of the whole file, checked before the file is moved into place
+///timestamp: u64, Seconds since the unix epoch when the announcement was made
+pub struct Announcement {
+    pub name: String,
+    pub size: u64,
+    pub hash: [u8; 32],
+    pub timestamp: u64,
+}
+
+///One file being received
+struct Upload {
+    client: SocketAddr,
+    name: String,
+    hash: [u8; 32],
+    chunk_count: u64,
+    staging: Arc<Mutex<Staging>>,
+    staging_path: PathBuf,
+    final_path: PathBuf,
+    state: UploadState,
+    last_activity: Instant,
+}
+
+///The staging file of an upload and the chunks it still lacks
+///Locked on its own so writing a chunk doesn't hold up every other upload
+struct Staging {
+    file: File,
+    size: u64,
+    chunk_count: u64,
+    chunk_size: u64,
+    missing: RangeTree,
+}
+
+///Server side bookkeeping of every upload in flight, shared between workers
+pub struct UploadManager {
+    config: UploadConfig,
+    chunk_size: u64,
+    max_filename_len: usize,
+    //Shared with the threads verifying finished uploads
+    uploads: Arc<Mutex<HashMap<u64, Upload>>>,
+    counter: Mutex<u64>,
+}
+
+///What it takes to check a finished upload and move it into place, away from the workers
+struct Verification {
+    client: SocketAddr,
+    name: String,
+    hash: [u8; 32],
+    chunk_count: u64,
+    staging_path: PathBuf,
+    final_path: PathBuf,
+    overwrite: bool,
+}
+
+impl Announcement {
+    ///Bytes covered by the announcement signature
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.extend_from_slice(self.name.as_bytes());
+        bytes.extend_from_slice(&pack_u64_into_u8arr(self.size));
+        bytes.extend_from_slice(&self.hash);
+        bytes.extend_from_slice(&pack_u64_into_u8arr(self.timestamp));
+        bytes
+    }
+
+    ///HMAC-SHA256 of the announcement under key
+    pub fn sign(&self, key: &[u8]) -> [u8; 32] {
+        let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any size");
+        mac.update(&self.signed_bytes());
+        mac.finalize().into_bytes().into()
+    }
+
+    ///Constant time check of a signature
+    pub fn verify(&self, key: &[u8], signature: &[u8]) -> bool {
+        let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any size");
+        mac.update(&self.signed_bytes());
+        mac.verify_slice(signature).is_ok()
+    }
+}
+
+impl UploadManager {
+    ///packet_size is the server's packet size, upload chunks carry packet_size - 24 bytes of data
+    pub fn new(config: &UploadConfig, packet_size: usize, max_filename_len: usize) -> Self {
+        Self {
+            config: config.clone(),
+            chunk_size: (packet_size - 3*mem::size_of::<u64>()) as u64,
+            max_filename_len,
+            uploads: Arc::new(Mutex::new(HashMap::new())),
+            counter: Mutex::new(0),
+        }
+    }
+
+    ///Handle an upload packet, returns how many bytes of reply were put in reply_buffer, 0 for no reply
+    pub fn handle_packet(&self, id: u64, data: &[u8], source: SocketAddr, reply_buffer: &mut [u8]) -> usize {
+        let mut uploads = match self.uploads.lock() {
+            Ok(u) => u,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        //Forget about uploads that went quiet, finished ones are only kept around to answer late status requests
+        //Ones being verified are kept until their thread is done with them, however long the file takes to hash
+        uploads.retain(|_, u| {
+            let keep = u.last_activity.elapsed() < UPLOAD_IDLE_TIMEOUT || u.state == UploadState::Verifying;
+            if !keep && u.state == UploadState::Receiving {
+                warn!(peer:% = u.client, filename:% = u.name; "Upload timed out");
+                let _ = fs::remove_file(&u.staging_path);
+            }
+            keep
+        });
+
+        if id == UPLOAD_ANNOUNCE {
+            let (upload_id, chunk_count, result) = match parse_announce(data) {
+                Some((announcement, signature)) => self.announce(&mut uploads, announcement, &signature, source),
+                None => (0, 0, AnnounceResult::Failed),
+            };
+            announce_response_packet(upload_id, chunk_count, self.chunk_size, result, reply_buffer)
+        } else if id == UPLOAD_CHUNK {
+            if data.len() < 16 {
+                return 0;
+            }
+            let upload_id = unpack_u8arr_into_u64(&data[0..8]);
+            let chunk = unpack_u8arr_into_u64(&data[8..16]);
+            let staging = match uploads.get_mut(&upload_id) {
+                Some(upload) if upload.client.ip() == source.ip() && upload.state == UploadState::Receiving => {
+                    upload.last_activity = Instant::now();
+                    Arc::clone(&upload.staging)
+                },
+                _ => return 0,
+            };
+            //Other uploads and status requests don't wait for the write
+            drop(uploads);
+            let written = match staging.lock() {
+                Ok(s) => s,
+                Err(poisoned) => poisoned.into_inner(),
+            }.write_chunk(chunk, &data[16..]);
+            match written {
+                Ok(true) => {
+                    let mut uploads = match self.uploads.lock() {
+                        Ok(u) => u,
+                        Err(poisoned) => poisoned.into_inner(),
+                    };
+                    if let Some(upload) = uploads.get_mut(&upload_id) {
+                        self.verify(upload_id, upload);
+                    }
+                },
+                Ok(false) => {},
+                Err(e) => error!(peer:% = source, upload = upload_id, chunk = chunk, error:% = e; "Unable to write upload chunk"),
+            }
+            //Chunks are never answered, the client asks for the status once it is done sending
+            0
+        } else if id == UPLOAD_STATUS {
+            if data.len() < 8 {
+                return 0;
+            }
+            let upload_id = unpack_u8arr_into_u64(&data[0..8]);
+            match uploads.get_mut(&upload_id) {
+                Some(upload) if upload.client.ip() == source.ip() => {
+                    upload.last_activity = Instant::now();
+                    status_response_packet(upload_id, upload.state, &upload.missing_ranges(), reply_buffer)
+                },
+                _ => status_response_packet(upload_id, UploadState::Unknown, &[], reply_buffer),
+            }
+        } else {
+            0
+        }
+    }
+
+    ///Accept or refuse an announcement, announcing the same file again hands back the existing upload
+    fn announce(&self, uploads: &mut HashMap<u64, Upload>, announcement: Announcement, signature: &[u8], source: SocketAddr) -> (u64, u64, AnnounceResult) {
+        let directory = match &self.config.directory {
+            Some(d) => d,
+            None => return (0, 0, AnnounceResult::Disabled),
+        };
+        if !announcement.verify(self.config.key.as_bytes(), signature) {
+            warn!(peer:% = source, filename:% = announcement.name; "Upload has a bad signature");
+            return (0, 0, AnnounceResult::Unauthorized);
+        }
+        let now = unix_time();
+        if now.max(announcement.timestamp) - now.min(announcement.timestamp) > ANNOUNCE_MAX_SKEW {
+            warn!(peer:% = source, filename:% = announcement.name, timestamp = announcement.timestamp; "Upload has a stale timestamp");
+            return (0, 0, AnnounceResult::Unauthorized);
+        }
+
+        let chunk_count = announcement.size.div_ceil(self.chunk_size);
+        //Retransmitted announcements get the upload that already exists
+        for (upload_id, upload) in uploads.iter() {
+            if upload.client == source && upload.name == announcement.name && upload.hash == announcement.hash {
+                return (*upload_id, upload.chunk_count, AnnounceResult::Accepted);
+            }
+        }
+
+        if !valid_upload_name(&announcement.name, self.max_filename_len) {
+            return (0, 0, AnnounceResult::InvalidName);
+        }
+        if announcement.size > self.config.max_size {
+            return (0, 0, AnnounceResult::TooLarge);
+        }
+        let final_path = directory.join(&announcement.name);
+        if final_path.exists() && !self.config.overwrite {
+            return (0, 0, AnnounceResult::Exists);
+        }
+        if uploads.values().any(|u| u.final_path == final_path && (u.state == UploadState::Receiving || u.state == UploadState::Verifying)) {
+            return (0, 0, AnnounceResult::Exists);
+        }
+
+        //Staging files are sparse, what the uploads in flight still have to write isn't taken yet
+        let pending: u64 = uploads.values()
+            .filter(|u| u.state == UploadState::Receiving)
+            .map(|u| u.missing_ranges().iter().map(|(s, e)| (e-s+1)*self.chunk_size).sum::<u64>())
+            .sum();
+        match free_space(directory) {
+            Ok(free) if free.saturating_sub(pending) >= announcement.size => {},
+            Ok(free) => {
+                warn!(peer:% = source, filename:% = announcement.name, bytes = announcement.size, free = free, pending = pending; "Not enough free space for upload");
+                return (0, 0, AnnounceResult::NoSpace);
+            },
+            Err(e) => {
+                error!(path:? = directory, error:% = e; "Unable to check free space for upload");
+                return (0, 0, AnnounceResult::Failed);
+            }
+        }
+
+        let upload_id = self.new_upload_id(&announcement, source);
+        //Staging files live in the upload directory so the final rename never crosses filesystems
+        let staging_path = directory.join(format!(".upload-{:016x}.part", upload_id));
+        let file = match File::create(&staging_path).and_then(|f| f.set_len(announcement.size).map(|_| f)) {
+            Ok(f) => f,
+            Err(e) => {
+                error!(path:? = staging_path, error:% = e; "Unable to create staging file");
+                return (0, 0, AnnounceResult::Failed);
+            }
+        };
+
+        info!(peer:% = source, filename:% = announcement.name, bytes = announcement.size; "Accepted upload");
+        let mut staging = Staging {
+            file,
+            size: announcement.size,
+            chunk_count,
+            chunk_size: self.chunk_size,
+            missing: RangeTree::new(0, chunk_count.saturating_sub(1) as usize),
+        };
+        if chunk_count == 0 {
+            staging.missing.intervals.clear();
+        }
+        let mut upload = Upload {
+            client: source,
+            name: announcement.name,
+            hash: announcement.hash,
+            chunk_count,
+            staging: Arc::new(Mutex::new(staging)),
+            staging_path,
+            final_path,
+            state: UploadState::Receiving,
+            last_activity: Instant::now(),
+        };
+        //The thread verifying it can't look it up before the caller lets go of uploads
+        if chunk_count == 0 {
+            self.verify(upload_id, &mut upload);
+        }
+        uploads.insert(upload_id, upload);
+        (upload_id, chunk_count, AnnounceResult::Accepted)
+    }
+
+    ///Every chunk of upload is in, check its hash and move it into place on a thread of its own
+    ///Hashing a big file takes a while, workers keep answering with Verifying until it is done
+    fn verify(&self, upload_id: u64, upload: &mut Upload) {
+        if upload.state != UploadState::Receiving {
+            return;
+        }
+        upload.state = UploadState::Verifying;
+        let verification = Verification {
+            client: upload.client,
+            name: upload.name.clone(),
+            hash: upload.hash,
+            chunk_count: upload.chunk_count,
+            staging_path: upload.staging_path.clone(),
+            final_path: upload.final_path.clone(),
+            overwrite: self.config.overwrite,
+        };
+        let uploads = Arc::clone(&self.uploads);
+        let spawned = thread::Builder::new().name("upload-verify".to_string()).spawn(move || {
+            let state = verification.run();
+            let mut uploads = match uploads.lock() {
+                Ok(u) => u,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            if let Some(upload) = uploads.get_mut(&upload_id) {
+                upload.state = state;
+                upload.last_activity = Instant::now();
+            }
+        });
+        if let Err(e) = spawned {
+            error!(peer:% = upload.client, filename:% = upload.name, error:% = e; "Unable to start verifying upload");
+            upload.state = UploadState::HashMismatch;
+            let _ = fs::remove_file(&upload.staging_path);
+        }
+    }
+
+    ///Hard to guess ID for a new upload, it is what authorizes the chunks that follow
+    fn new_upload_id(&self, announcement: &Announcement, source: SocketAddr) -> u64 {
+        let mut counter = match self.counter.lock() {
+            Ok(c) => c,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        *counter += 1;
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+        let mut hasher = Sha256::new();
+        hasher.update(self.config.key.as_bytes());
+        hasher.update(nanos.to_be_bytes());
+        hasher.update(pack_u64_into_u8arr(*counter));
+        hasher.update(source.to_string().as_bytes());
+        hasher.update(announcement.hash);
+        let digest = hasher.finalize();
+        //0 means refused, never hand it out
+        unpack_u8arr_into_u64(&digest[0..8]).max(1)
+    }
+}
+
+impl Upload {
+    ///Missing chunk ranges sorted by start
+    fn missing_ranges(&self) -> Vec<(u64, u64)> {
+        match self.staging.lock() {
+            Ok(s) => s,
+            Err(poisoned) => poisoned.into_inner(),
+        }.missing_ranges()
+    }
+}
+
+impl Staging {
+    ///Store a chunk, returns whether that was the last one missing
+    ///Once every chunk is in the file is left alone, it is being verified
+    fn write_chunk(&mut self, chunk: u64, data: &[u8]) -> io::Result<bool> {
+        if self.missing.intervals.is_empty() || chunk >= self.chunk_count {
+            return Ok(false);
+        }
+        //Every chunk but the last one is full
+        let expected = if chunk == self.chunk_count-1 {
+            self.size - chunk*self.chunk_size
+        } else {
+            self.chunk_size
+        };
+        if data.len() as u64 != expected {
+            return Ok(false);
+        }
+        self.file.seek(SeekFrom::Start(chunk*self.chunk_size))?;
+        self.file.write_all(data)?;
+        self.file.flush()?;
+        self.missing.add_packet(chunk as usize);
+        Ok(self.missing.intervals.is_empty())
+    }
+
+    ///Missing chunk ranges sorted by start
+    fn missing_ranges(&self) -> Vec<(u64, u64)> {
+        let mut ranges: Vec<(u64, u64)> = self.missing.intervals.iter()
+            .map(|i| (self.missing.tree_vec[*i].start as u64, self.missing.tree_vec[*i].end as u64))
+            .collect();
+        ranges.sort_unstable();
+        ranges
+    }
+}
+
+impl Verification {
+    ///Check the hash of the staging file and move it into place, returns where the upload ends up
+    fn run(&self) -> UploadState {
+        let hash = match hash_file(&self.staging_path) {
+            Ok((h, _)) => h,
+            Err(e) => {
+                error!(peer:% = self.client, filename:% = self.name, error:% = e; "Unable to hash upload");
+                let _ = fs::remove_file(&self.staging_path);
+                return UploadState::HashMismatch;
+            }
+        };
+        if hash != self.hash {
+            warn!(peer:% = self.client, filename:% = self.name; "Upload does not match its announced hash, dropping it");
+            let _ = fs::remove_file(&self.staging_path);
+            return UploadState::HashMismatch;
+        }
+        if let Some(parent) = self.final_path.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+        match place(&self.staging_path, &self.final_path, self.overwrite) {
+            Ok(_) => {
+                info!(peer:% = self.client, filename:% = self.name, chunks = self.chunk_count; "Upload complete");
+                UploadState::Complete
+            },
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                warn!(peer:% = self.client, filename:% = self.name; "Upload target appeared while it was received, dropping it");
+                let _ = fs::remove_file(&self.staging_path);
+                UploadState::Exists
+            },
+            Err(e) => {
+                error!(peer:% = self.client, filename:% = self.name, error:% = e; "Unable to move upload into place");
+                let _ = fs::remove_file(&self.staging_path);
+                UploadState::HashMismatch
+            }
+        }
+    }
+}
+
+///Move staging to destination, which is only replaced if overwrite is set
+///Without overwrite a hard link is made and the staging name removed, linking fails if destination exists
+///so nothing that shows up between the announcement and now gets replaced
+fn place(staging: &Path, destination: &Path, overwrite: bool) -> io::Result<()> {
+    if overwrite {
+        return fs::rename(staging, destination);
+    }
+    #[cfg(unix)]
+    {
+        fs::hard_link(staging, destination)?;
+        if let Err(e) = fs::remove_file(staging) {
+            warn!(path:? = staging, error:% = e; "Unable to remove staging file");
+        }
+        Ok(())
+    }
+    #[cfg(not(unix))]
+    {
+        if destination.exists() {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        fs::rename(staging, destination)
+    }
+}
+
+///Bytes an unprivileged user can still write to the filesystem holding directory
+#[cfg(target_os = "linux")]
+fn free_space(directory: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let path = CString::new(directory.as_os_str().as_bytes()).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64))
+}
+
+///Other platforms don't get told how much room is left, the write fails once it runs out
+#[cfg(not(target_os = "linux"))]
+fn free_space(_directory: &Path) -> io::Result<u64> {
+    Ok(u64::MAX)
+}
+
+///Upload names have to stay inside the upload directory and can't be hidden
+fn valid_upload_name(name: &str, max_len: usize) -> bool {
+    if name.is_empty() || name.len() > max_len {
+        return false;
+    }
+    Path::new(name).components().all(|c| match c {
+        Component::Normal(s) => !s.to_string_lossy().starts_with('.'),
+        _ => false,
+    })
+}
+
+///Seconds since the unix epoch
+pub fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+///SHA-256 and size of everything a reader hands out
+pub fn hash_reader<R: Read>(reader: &mut R) -> io::Result<([u8; 32], u64)> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1 << 16];
+    let mut size: u64 = 0;
+    loop {
+        let bytes_read = reader.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[0..bytes_read]);
+        size += bytes_read as u64;
+    }
+    Ok((hasher.finalize().into(), size))
+}
+
+///SHA-256 and size of a file
+pub fn hash_file(path: &Path) -> io::Result<([u8; 32], u64)> {
+    hash_reader(&mut File::open(path)?)
+}
+
+///Populates a buffer with a signed upload announcement, returns how many bytes are in the packet
+///u64 ID, varint name length, name, u64 size, 32 byte hash, u64 timestamp, 32 byte signature
+///Fails if the name is too long to fit in the packet
+pub fn announce_packet(announcement: &Announcement, key: &[u8], buffer: &mut [u8]) -> io::Result<usize> {
+    buffer[0..8].copy_from_slice(&pack_u64_into_u8arr(UPLOAD_ANNOUNCE));
+    let mut byte_counter: usize = 8+pack_name(&announcement.name, buffer, 8, 8+32+8+32)?;
+    let mut push = |bytes: &[u8]| {
+        buffer[byte_counter..byte_counter+bytes.len()].copy_from_slice(bytes);
+        byte_counter += bytes.len();
+    };
+    push(&pack_u64_into_u8arr(announcement.size));
+    push(&announcement.hash);
+    push(&pack_u64_into_u8arr(announcement.timestamp));
+    push(&announcement.sign(key));
+    Ok(byte_counter)
+}
+
+///Parse an announcement packet following its ID, hands back the announcement and its signature
+fn parse_announce(data: &[u8]) -> Option<(Announcement, Vec<u8>)> {
+    let (namelen, mut byte_counter) = unpack_varint(data)?;
+    let namelen = usize::try_from(namelen).ok()?;
+    if data.len() != byte_counter.checked_add(namelen)?+8+32+8+32 {
+        return None;
+    }
+    let name = String::from_utf8(data[byte_counter..byte_counter+namelen].to_vec()).ok()?;
+    byte_counter += namelen;
+    let size = unpack_u8arr_into_u64(&data[byte_counter..byte_counter+8]);
+    byte_counter += 8;
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&data[byte_counter..byte_counter+32]);
+    byte_counter += 32;
+    let timestamp = unpack_u8arr_into_u64(&data[byte_counter..byte_counter+8]);
+    byte_counter += 8;
+    let signature = data[byte_counter..byte_counter+32].to_vec();
+    Some((Announcement { name, size, hash, timestamp }, signature))
+}
+
+///u64 ID, u64 upload ID (0 if refused), u64 chunk count, u64 chunk size, u64 AnnounceResult
+pub fn announce_response_packet(upload_id: u64, chunk_count: u64, chunk_size: u64, result: AnnounceResult, buffer: &mut [u8]) -> usize {
+    let mut byte_counter: usize = 0;
+    for value in [UPLOAD_ANNOUNCE, upload_id, chunk_count, chunk_size, result as u64].iter() {
+        buffer[byte_counter..byte_counter+8].copy_from_slice(&pack_u64_into_u8arr(*value));
+        byte_counter += 8;
+    }
+    byte_counter
+}
+
+///Populates a buffer with one chunk of upload data, returns how many bytes are in the packet
+pub fn upload_chunk_packet(upload_id: u64, chunk: u64, data: &[u8], buffer: &mut [u8]) -> usize {
+    buffer[0..8].copy_from_slice(&pack_u64_into_u8arr(UPLOAD_CHUNK));
+    buffer[8..16].copy_from_slice(&pack_u64_into_u8arr(upload_id));
+    buffer[16..24].copy_from_slice(&pack_u64_into_u8arr(chunk));
+    buffer[24..24+data.len()].copy_from_slice(data);
+    24+data.len()
+}
+
+///Populates a buffer with a status request for an upload, returns how many bytes are in the packet
+pub fn status_request_packet(upload_id: u64, buffer: &mut [u8]) -> usize {
+    buffer[0..8].copy_from_slice(&pack_u64_into_u8arr(UPLOAD_STATUS));
+    buffer[8..16].copy_from_slice(&pack_u64_into_u8arr(upload_id));
+    16
+}
+
+///u64 ID, u64 upload ID, u64 UploadState, u64 range count and then as many u64 start/end pairs of missing chunks as fit
+pub fn status_response_packet(upload_id: u64, state: UploadState, missing: &[(u64, u64)], buffer: &mut [u8]) -> usize {
+    let fittable = (buffer.len() - 32)/16;
+    let count = missing.len().min(fittable);
+    let mut byte_counter: usize = 0;
+    for value in [UPLOAD_STATUS, upload_id, state as u64, count as u64].iter() {
+        buffer[byte_counter..byte_counter+8].copy_from_slice(&pack_u64_into_u8arr(*value));
+        byte_counter += 8;
+    }
+    for (s, e) in missing[0..count].iter() {
+        buffer[byte_counter..byte_counter+8].copy_from_slice(&pack_u64_into_u8arr(*s));
+        buffer[byte_counter+8..byte_counter+16].copy_from_slice(&pack_u64_into_u8arr(*e));
+        byte_counter += 16;
+    }
+    byte_counter
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "0123456789abcdef";
+    //Chunks of 40 bytes
+    const PACKET_SIZE: usize = 64;
+
+    ///An empty upload directory
+    fn scratch(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("basic_udp-upload-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn manager(directory: &Path, max_size: u64, overwrite: bool) -> UploadManager {
+        let config = UploadConfig { directory: Some(directory.to_path_buf()), key: KEY.to_string(), max_size, overwrite };
+        UploadManager::new(&config, PACKET_SIZE, 1024)
+    }
+
+    fn client() -> SocketAddr {
+        "127.0.0.1:5000".parse().unwrap()
+    }
+
+    fn announcement(name: &str, contents: &[u8]) -> Announcement {
+        let (hash, size) = hash_reader(&mut &contents[..]).unwrap();
+        Announcement { name: name.to_string(), size, hash, timestamp: unix_time() }
+    }
+
+    ///Upload ID and result of an announcement signed with key
+    fn announce(manager: &UploadManager, announcement: &Announcement, key: &str) -> (u64, u64) {
+        let mut packet = [0; 512];
+        let length = announce_packet(announcement, key.as_bytes(), &mut packet).unwrap();
+        let mut reply = [0; 512];
+        assert_eq!(manager.handle_packet(UPLOAD_ANNOUNCE, &packet[8..length], client(), &mut reply), 40);
+        (unpack_u8arr_into_u64(&reply[8..16]), unpack_u8arr_into_u64(&reply[32..40]))
+    }
+
+    fn send_chunk(manager: &UploadManager, upload_id: u64, chunk: u64, data: &[u8], source: SocketAddr) {
+        let mut packet = [0; PACKET_SIZE];
+        let length = upload_chunk_packet(upload_id, chunk, data, &mut packet);
+        assert_eq!(manager.handle_packet(UPLOAD_CHUNK, &packet[8..length], source, &mut [0; 512]), 0);
+    }
+
+    ///State and missing ranges the server reports for an upload
+    fn status(manager: &UploadManager, upload_id: u64) -> (u64, Vec<(u64, u64)>) {
+        let mut packet = [0; 16];
+        status_request_packet(upload_id, &mut packet);
+        let mut reply = [0; 512];
+        let length = manager.handle_packet(UPLOAD_STATUS, &packet[8..16], client(), &mut reply);
+        let count = unpack_u8arr_into_u64(&reply[24..32]) as usize;
+        assert_eq!(length, 32+16*count);
+        let missing = (0..count).map(|i| (unpack_u8arr_into_u64(&reply[32+16*i..40+16*i]), unpack_u8arr_into_u64(&reply[40+16*i..48+16*i]))).collect();
+        (unpack_u8arr_into_u64(&reply[16..24]), missing)
+    }
+
+    ///Send every chunk of contents and wait for the verification to settle
+    fn upload(manager: &UploadManager, upload_id: u64, contents: &[u8]) -> u64 {
+        for (chunk, data) in contents.chunks(40).enumerate() {
+            send_chunk(manager, upload_id, chunk as u64, data, client());
+        }
+        let started = Instant::now();
+        loop {
+            let (state, _) = status(manager, upload_id);
+            if state != UploadState::Verifying as u64 || started.elapsed() > Duration::from_secs(5) {
+                return state;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn announcements_need_the_key_and_a_current_clock() {
+        let directory = scratch("announce");
+        let manager = manager(&directory, 1000, false);
+        let accepted = AnnounceResult::Accepted as u64;
+        let unauthorized = AnnounceResult::Unauthorized as u64;
+        assert_eq!(announce(&manager, &announcement("wrong-key", b"x"), "fedcba9876543210"), (0, unauthorized));
+        let mut stale = announcement("stale", b"x");
+        stale.timestamp -= ANNOUNCE_MAX_SKEW+1;
+        assert_eq!(announce(&manager, &stale, KEY), (0, unauthorized));
+        let mut early = announcement("early", b"x");
+        early.timestamp += ANNOUNCE_MAX_SKEW+1;
+        assert_eq!(announce(&manager, &early, KEY), (0, unauthorized));
+        let mut skewed = announcement("skewed", b"x");
+        skewed.timestamp -= ANNOUNCE_MAX_SKEW-10;
+        let (upload_id, result) = announce(&manager, &skewed, KEY);
+        assert!(upload_id != 0);
+        assert_eq!(result, accepted);
+        //A retransmitted announcement gets the same upload
+        assert_eq!(announce(&manager, &skewed, KEY), (upload_id, accepted));
+
+        assert_eq!(announce(&manager, &announcement("../escape", b"x"), KEY).1, AnnounceResult::InvalidName as u64);
+        assert_eq!(announce(&manager, &announcement("dir/.hidden", b"x"), KEY).1, AnnounceResult::InvalidName as u64);
+        assert_eq!(announce(&manager, &announcement("large", &[0; 1001]), KEY).1, AnnounceResult::TooLarge as u64);
+        let mut huge = announcement("huge", b"x");
+        huge.size = 1 << 62;
+        assert_eq!(announce(&self::manager(&directory, u64::MAX, false), &huge, KEY).1, AnnounceResult::NoSpace as u64);
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn chunks_are_staged_and_missing_ones_tracked() {
+        let directory = scratch("staging");
+        let manager = manager(&directory, 1000, false);
+        let contents: Vec<u8> = (0..150).map(|i| i as u8).collect();
+        let (upload_id, _) = announce(&manager, &announcement("sub/file.bin", &contents), KEY);
+        let staging_path = directory.join(format!(".upload-{:016x}.part", upload_id));
+        assert_eq!(fs::metadata(&staging_path).unwrap().len(), 150);
+        assert_eq!(status(&manager, upload_id), (UploadState::Receiving as u64, vec![(0, 3)]));
+
+        send_chunk(&manager, upload_id, 1, &contents[40..80], client());
+        send_chunk(&manager, upload_id, 3, &contents[120..150], client());
+        assert_eq!(status(&manager, upload_id), (UploadState::Receiving as u64, vec![(0, 0), (2, 2)]));
+        //Short chunks, chunks past the end and chunks from someone else are dropped
+        send_chunk(&manager, upload_id, 0, &contents[0..39], client());
+        send_chunk(&manager, upload_id, 4, &contents[0..40], client());
+        send_chunk(&manager, upload_id, 2, &contents[80..120], "192.0.2.1:5000".parse().unwrap());
+        assert_eq!(status(&manager, upload_id), (UploadState::Receiving as u64, vec![(0, 0), (2, 2)]));
+        assert_eq!(status(&manager, upload_id+1), (UploadState::Unknown as u64, vec![]));
+        assert!(!directory.join("sub/file.bin").exists());
+
+        assert_eq!(upload(&manager, upload_id, &contents), UploadState::Complete as u64);
+        assert_eq!(fs::read(directory.join("sub/file.bin")).unwrap(), contents);
+        assert!(!staging_path.exists());
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn uploads_not_matching_their_hash_are_dropped() {
+        let directory = scratch("hash");
+        let manager = manager(&directory, 1000, false);
+        let contents = [7; 100];
+        let mut announced = announcement("file.bin", &contents);
+        announced.hash[0] ^= 1;
+        let (upload_id, _) = announce(&manager, &announced, KEY);
+        assert_eq!(upload(&manager, upload_id, &contents), UploadState::HashMismatch as u64);
+        assert!(!directory.join("file.bin").exists());
+        assert!(!directory.join(format!(".upload-{:016x}.part", upload_id)).exists());
+
+        let (upload_id, _) = announce(&manager, &announcement("empty.bin", b""), KEY);
+        assert_eq!(upload(&manager, upload_id, b""), UploadState::Complete as u64);
+        assert_eq!(fs::read(directory.join("empty.bin")).unwrap(), b"");
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn files_appearing_during_an_upload_are_kept_without_overwrite() {
+        let directory = scratch("overwrite");
+        let contents = [1; 100];
+        let manager = manager(&directory, 1000, false);
+        fs::write(directory.join("there.bin"), b"old").unwrap();
+        assert_eq!(announce(&manager, &announcement("there.bin", &contents), KEY), (0, AnnounceResult::Exists as u64));
+
+        let (upload_id, _) = announce(&manager, &announcement("racing.bin", &contents), KEY);
+        fs::write(directory.join("racing.bin"), b"old").unwrap();
+        assert_eq!(upload(&manager, upload_id, &contents), UploadState::Exists as u64);
+        assert_eq!(fs::read(directory.join("racing.bin")).unwrap(), b"old");
+        assert!(!directory.join(format!(".upload-{:016x}.part", upload_id)).exists());
+
+        let manager = self::manager(&directory, 1000, true);
+        let (upload_id, _) = announce(&manager, &announcement("there.bin", &contents), KEY);
+        assert_eq!(upload(&manager, upload_id, &contents), UploadState::Complete as u64);
+        assert_eq!(fs::read(directory.join("there.bin")).unwrap(), &contents[..]);
+        let _ = fs::remove_dir_all(&directory);
+    }
+}
@@ -0,0 +1,245 @@
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::net::{SocketAddr, UdpSocket};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::thread;
+use std::time::Duration;
+use io_uring::{opcode, squeue, types, IoUring};
+use log::warn;
+use crate::batch::{segmentation, segmentation_failed};
+use crate::metrics::Metrics;
+use crate::mmsg::Messages;
+use crate::read_chunk;
+
+///Submission queue entries, more are submitted whenever it fills up
+const ENTRIES: u32 = 256;
+///Set in the user data of reads, the rest is the packet the chunk is read into
+///Sends carry their batch, group and message instead
+const READ: u64 = 1 << 63;
+
+///Packets of a batch the kernel is sending
+struct Flight {
+    fd: RawFd,
+    target: SocketAddr,
+    buffer: *const u8,
+    packet_size: usize,
+    lens: Vec<usize>,
+    //The batch as it was first sent, then runs of it sent again one packet at a time
+    groups: Vec<Messages>,
+    //Which messages of every group went out
+    sent: Vec<Vec<bool>>,
+    pending: usize,
+    error: Option<io::Error>,
+}
+
+///Reads and sends submitted to io_uring, a batch at a time
+///Every buffer handed to it has to stay put until the reads into it are done or its batch was waited for
+pub(crate) struct Ring {
+    ring: IoUring,
+    //By batch
+    flights: Vec<Option<Flight>>,
+    //Results of the reads of read, by packet
+    reads: Vec<i32>,
+    reads_pending: usize,
+    completed: Vec<(u64, i32)>,
+}
+
+impl Ring {
+    pub(crate) fn new(batches: usize) -> io::Result<Self> {
+        Ok(Self {
+            ring: IoUring::new(ENTRIES)?,
+            flights: (0..batches).map(|_| None).collect(),
+            reads: Vec::new(),
+            reads_pending: 0,
+            completed: Vec::new(),
+        })
+    }
+
+    ///Read chunk i of chunks into packet i of buffer after its first 8 bytes, all of them submitted together
+    ///Sends still going complete while it waits for them
+    pub(crate) fn read(&mut self, file: &File, chunks: &[u64], buffer: &mut [u8], packet_size: usize, lens: &mut Vec<usize>) -> io::Result<()> {
+        let chunk_size = packet_size - mem::size_of::<u64>();
+        self.reads.clear();
+        self.reads.resize(chunks.len(), 0);
+        let mut submitted = Ok(());
+        for (i, (chunk, packet)) in chunks.iter().zip(buffer.chunks_exact_mut(packet_size)).enumerate() {
+            let read = opcode::Read::new(types::Fd(file.as_raw_fd()), packet[8..].as_mut_ptr(), chunk_size as u32)
+                .offset(chunk*(chunk_size as u64))
+                .build()
+                .user_data(READ | i as u64);
+            if let Err(e) = self.push(&read) {
+                submitted = Err(e);
+                break;
+            }
+            self.reads_pending += 1;
+        }
+        //The kernel writes into buffer until they are done, even if not all of them made it in or something failed on the way
+        while self.reads_pending > 0 {
+            if let Err(e) = self.enter(1) {
+                submitted = submitted.and(Err(e));
+            }
+        }
+        submitted?;
+        for (i, (chunk, packet)) in chunks.iter().zip(buffer.chunks_exact_mut(packet_size)).enumerate() {
+            let result = self.reads[i];
+            if result < 0 {
+                return Err(io::Error::from_raw_os_error(-result));
+            }
+            //Short for the last chunk and for reads cut short, read_chunk tells them apart
+            let len = if (result as usize) < chunk_size { read_chunk(file, *chunk, &mut packet[8..])? } else { chunk_size };
+            lens.push(len);
+        }
+        Ok(())
+    }
+
+    ///Start sending the packets of batch index in buffer to target, laid out as mmsg::Messages has them
+    pub(crate) fn send(&mut self, index: usize, socket: &UdpSocket, target: SocketAddr, buffer: &[u8], packet_size: usize, lens: &[usize]) -> io::Result<()> {
+        self.flights[index] = Some(Flight {
+            fd: socket.as_raw_fd(),
+            target,
+            buffer: buffer.as_ptr(),
+            packet_size,
+            lens: lens.to_vec(),
+            groups: Vec::new(),
+            sent: Vec::new(),
+            pending: 0,
+            error: None,
+        });
+        self.submit_group(index, Messages::new(target, buffer, packet_size, lens, segmentation()))?;
+        //Going out while the next batch is read
+        self.enter(0)
+    }
+
+    ///Wait until the kernel is done with batch index, returns the first error sending it
+    pub(crate) fn wait(&mut self, index: usize, metrics: &Metrics) -> io::Result<()> {
+        //The batch is only filled again once the kernel is done with it, so errors on the way don't end the wait
+        let mut waited = Ok(());
+        while self.flights[index].as_ref().is_some_and(|f| f.pending > 0) {
+            if let Err(e) = self.enter(1) {
+                waited = waited.and(Err(e));
+            }
+        }
+        let flight = match self.flights[index].take() {
+            Some(f) => f,
+            None => return waited,
+        };
+        for (group, sent) in flight.groups.iter().zip(flight.sent.iter()) {
+            for (message, _) in sent.iter().enumerate().filter(|(_, sent)| **sent) {
+                let (first, count) = group.run(message);
+                for len in flight.lens[first..first+count].iter() {
+                    metrics.sent(*len);
+                }
+            }
+        }
+        match flight.error {
+            Some(e) => waited.and(Err(e)),
+            None => waited,
+        }
+    }
+
+    ///Wait until the kernel is done with every buffer handed to it, for when they are about to go away
+    pub(crate) fn drain(&mut self) {
+        while self.reads_pending > 0 || self.flights.iter().flatten().any(|f| f.pending > 0) {
+            let _ = self.enter(1);
+        }
+        for flight in self.flights.iter_mut() {
+            *flight = None;
+        }
+    }
+
+    fn submit_group(&mut self, index: usize, messages: Messages) -> io::Result<()> {
+        let flight = match self.flights[index].as_mut() {
+            Some(f) => f,
+            None => return Ok(()),
+        };
+        let group = flight.groups.len();
+        let count = messages.len();
+        flight.sent.push(vec![false; count]);
+        flight.groups.push(messages);
+        for message in 0..count {
+            self.submit_message(index, group, message)?;
+        }
+        Ok(())
+    }
+
+    fn submit_message(&mut self, index: usize, group: usize, message: usize) -> io::Result<()> {
+        let send = match self.flights[index].as_ref() {
+            Some(f) => opcode::SendMsg::new(types::Fd(f.fd), f.groups[group].header(message))
+                .build()
+                .user_data((index as u64) << 48 | (group as u64) << 32 | message as u64),
+            None => return Ok(()),
+        };
+        self.push(&send)?;
+        if let Some(flight) = self.flights[index].as_mut() {
+            flight.pending += 1;
+        }
+        Ok(())
+    }
+
+    ///A send of batch index finished with result
+    fn sent(&mut self, index: usize, group: usize, message: usize, result: i32) -> io::Result<()> {
+        let flight = match self.flights[index].as_mut() {
+            Some(f) => f,
+            None => return Ok(()),
+        };
+        flight.pending -= 1;
+        if result >= 0 {
+            flight.sent[group][message] = true;
+            return Ok(());
+        }
+        let e = io::Error::from_raw_os_error(-result);
+        //The socket is nonblocking for the receive loop, a full buffer is waited out like a blocking send would
+        if e.kind() == io::ErrorKind::WouldBlock {
+            thread::sleep(Duration::from_micros(100));
+            return self.submit_message(index, group, message);
+        }
+        if flight.groups[group].segmented(message) && segmentation_failed(flight.target, &e) {
+            let (first, count) = flight.groups[group].run(message);
+            //The batch's buffer is alive until its flight was waited for, and this run is in it
+            let buffer = unsafe { std::slice::from_raw_parts(flight.buffer.add(first*flight.packet_size), (count-1)*flight.packet_size + flight.lens[first+count-1]) };
+            let messages = Messages::new(flight.target, buffer, flight.packet_size, &flight.lens[first..first+count], false);
+            return self.submit_group(index, messages);
+        }
+        warn!(peer:% = flight.target, error:% = e; "Unable to send data");
+        if flight.error.is_none() {
+            flight.error = Some(e);
+        }
+        Ok(())
+    }
+
+    ///Queue entry, submitting what is queued to make room if it is full
+    fn push(&mut self, entry: &squeue::Entry) -> io::Result<()> {
+        //Entries only point at buffers the caller keeps until they complete
+        while unsafe { self.ring.submission().push(entry) }.is_err() {
+            self.enter(0)?;
+        }
+        Ok(())
+    }
+
+    ///Submit what is queued, wait for at least wait completions and handle every one there is
+    fn enter(&mut self, wait: usize) -> io::Result<()> {
+        //What completed is handled even if submitting failed, callers wait on it
+        let submitted = match self.ring.submit_and_wait(wait) {
+            Ok(_) => Ok(()),
+            //Completions have to be taken off before more can be submitted
+            Err(e) if e.raw_os_error() == Some(libc::EBUSY) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => Ok(()),
+            Err(e) => Err(e),
+        };
+        let mut completed = mem::take(&mut self.completed);
+        completed.extend(self.ring.completion().map(|c| (c.user_data(), c.result())));
+        let mut result = submitted;
+        for (user_data, status) in completed.drain(..) {
+            if user_data & READ != 0 {
+                self.reads[(user_data & !READ) as usize] = status;
+                self.reads_pending -= 1;
+                continue;
+            }
+            let handled = self.sent((user_data >> 48) as usize, (user_data >> 32 & 0xffff) as usize, (user_data & 0xffff_ffff) as usize, status);
+            result = result.and(handled);
+        }
+        self.completed = completed;
+        result
+    }
+}
@@ -0,0 +1,261 @@
+use std::ffi::OsString;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use log::warn;
+use sha2::{Digest, Sha256};
+use zstd::stream::raw::{Decoder, Operation};
+use crate::cache::FileCache;
+use crate::config::ServerConfig;
+use crate::{pack_u64_into_u8arr, unpack_u8arr_into_u64};
+
+///Appended to a file's name to find its pre-compressed variant
+pub(crate) const SUFFIX: &str = ".zst";
+///Bytes pack_info adds to a response
+pub(crate) const INFO_LEN: usize = 80;
+///Smallest packet size a session open response with FEC params and a variant's description fits in
+pub(crate) const MIN_PACKET_SIZE: usize = 56 + 24 + INFO_LEN;
+
+///What a client gets when it is sent the variant of a file, sent after the FEC params of metadata and session open responses
+///
+///stored_size: u64, Size of the variant, its chunks are what the client is sent
+///stored_hash: [u8; 32], SHA-256 of the variant
+///decoded_size: u64, Size of what the variant decodes to
+///decoded_hash: [u8; 32], SHA-256 of what the variant decodes to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct VariantInfo {
+    pub(crate) stored_size: u64,
+    pub(crate) stored_hash: [u8; 32],
+    pub(crate) decoded_size: u64,
+    pub(crate) decoded_hash: [u8; 32],
+}
+
+///Appends info to a response as u64 stored size, stored hash, u64 decoded size and decoded hash, returns how many bytes were added
+pub(crate) fn pack_info(info: &VariantInfo, buffer: &mut [u8]) -> usize {
+    buffer[0..8].copy_from_slice(&pack_u64_into_u8arr(info.stored_size));
+    buffer[8..40].copy_from_slice(&info.stored_hash);
+    buffer[40..48].copy_from_slice(&pack_u64_into_u8arr(info.decoded_size));
+    buffer[48..80].copy_from_slice(&info.decoded_hash);
+    INFO_LEN
+}
+
+///Reads what pack_info added, None if it is cut short
+pub(crate) fn unpack_info(data: &[u8]) -> Option<VariantInfo> {
+    let data = data.get(0..INFO_LEN)?;
+    let mut info = VariantInfo {
+        stored_size: unpack_u8arr_into_u64(&data[0..8]),
+        stored_hash: [0; 32],
+        decoded_size: unpack_u8arr_into_u64(&data[40..48]),
+        decoded_hash: [0; 32],
+    };
+    info.stored_hash.copy_from_slice(&data[8..40]);
+    info.decoded_hash.copy_from_slice(&data[48..80]);
+    Some(info)
+}
+
+///Path of the variant of path, None if there is none that can be served
+///Only a regular file right next to path counts, a symlink could point anywhere, and one older than path was made from an earlier version
+pub(crate) fn find(path: &Path) -> Option<PathBuf> {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(SUFFIX);
+    let variant = PathBuf::from(name);
+    let metadata = fs::symlink_metadata(&variant).ok()?;
+    if !metadata.file_type().is_file() || metadata.modified().ok()? < fs::metadata(path).ok()?.modified().ok()? {
+        return None;
+    }
+    Some(variant)
+}
+
+///The variant to send in place of the whitelisted file at path and its description, None if variants are off or there is none
+///A variant is only read whole the first time it is described, files remembers what it found
+pub(crate) fn select(path: &Path, config: &ServerConfig, files: &FileCache) -> Option<(PathBuf, VariantInfo)> {
+    if !config.compression.variants {
+        return None;
+    }
+    let variant = find(path)?;
+    match files.variant(&variant) {
+        Ok(info) => Some((variant, info)),
+        Err(e) => {
+            warn!(path:? = variant, error:% = e; "Unable to read the pre-compressed variant, sending the file itself");
+            None
+        }
+    }
+}
+
+///Reads the whole variant at path to hash it and what it decodes to
+pub(crate) fn describe(path: &Path) -> io::Result<VariantInfo> {
+    let mut file = File::open(path)?;
+    let mut decoder = StreamDecoder::new()?;
+    let mut buffer: Vec<u8> = vec![0; 1 << 16];
+    loop {
+        let bytes_read = file.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+        decoder.write(&buffer[0..bytes_read], &mut io::sink())?;
+    }
+    decoder.finish()
+}
+
+///Decodes a variant as it comes in, in order, hashing it and what it decodes to
+pub(crate) struct StreamDecoder {
+    raw: Decoder<'static>,
+    stored: Sha256,
+    stored_size: u64,
+    decoded: Sha256,
+    decoded_size: u64,
+    buffer: Vec<u8>,
+    //What's left of the current frame, 0 once it ended
+    remaining: usize,
+}
+
+impl StreamDecoder {
+    pub(crate) fn new() -> io::Result<Self> {
+        Ok(Self {
+            raw: Decoder::new()?,
+            stored: Sha256::new(),
+            stored_size: 0,
+            decoded: Sha256::new(),
+            decoded_size: 0,
+            buffer: vec![0; 1 << 16],
+            remaining: 0,
+        })
+    }
+
+    ///Decode the next part of the variant into out
+    pub(crate) fn write<W: Write>(&mut self, data: &[u8], out: &mut W) -> io::Result<()> {
+        //Nothing to decode, and zstd would start expecting another frame
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.stored.update(data);
+        self.stored_size += data.len() as u64;
+        let mut input = data;
+        loop {
+            let status = self.raw.run_on_buffers(input, &mut self.buffer)?;
+            self.output(status.bytes_written, out)?;
+            self.remaining = status.remaining;
+            input = &input[status.bytes_read..];
+            //Done once the input is used up and the decoder had room to spare
+            if input.is_empty() && status.bytes_written < self.buffer.len() {
+                return Ok(());
+            }
+        }
+    }
+
+    ///The whole variant went through write, returns what it was and what it decoded to
+    ///Fails if it ended in the middle of a frame
+    pub(crate) fn finish(self) -> io::Result<VariantInfo> {
+        if self.remaining != 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The pre-compressed variant ends in the middle of a zstd frame"));
+        }
+        Ok(VariantInfo {
+            stored_size: self.stored_size,
+            stored_hash: self.stored.finalize().into(),
+            decoded_size: self.decoded_size,
+            decoded_hash: self.decoded.finalize().into(),
+        })
+    }
+
+    fn output<W: Write>(&mut self, bytes: usize, out: &mut W) -> io::Result<()> {
+        self.decoded.update(&self.buffer[0..bytes]);
+        self.decoded_size += bytes as u64;
+        out.write_all(&self.buffer[0..bytes])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    ///An empty directory
+    fn scratch(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("basic_udp-variant-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn set_modified(path: &Path, time: SystemTime) {
+        File::options().write(true).open(path).unwrap().set_modified(time).unwrap();
+    }
+
+    fn sha256(data: &[u8]) -> [u8; 32] {
+        Sha256::digest(data).into()
+    }
+
+    #[test]
+    fn find_only_takes_fresh_regular_files() {
+        let directory = scratch("find");
+        let path = directory.join("file.bin");
+        let variant = directory.join("file.bin.zst");
+        fs::write(&path, b"file").unwrap();
+        assert_eq!(find(&path), None);
+
+        let now = SystemTime::now();
+        fs::write(&variant, b"variant").unwrap();
+        set_modified(&path, now - Duration::from_secs(60));
+        set_modified(&variant, now);
+        assert_eq!(find(&path), Some(variant.clone()));
+        //Made from an earlier version of the file
+        set_modified(&variant, now - Duration::from_secs(120));
+        assert_eq!(find(&path), None);
+
+        #[cfg(unix)]
+        {
+            let elsewhere = directory.join("elsewhere.zst");
+            fs::rename(&variant, &elsewhere).unwrap();
+            set_modified(&elsewhere, now);
+            std::os::unix::fs::symlink(&elsewhere, &variant).unwrap();
+            assert_eq!(find(&path), None);
+            fs::remove_file(&variant).unwrap();
+        }
+        fs::create_dir(&variant).unwrap();
+        assert_eq!(find(&path), None);
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn info_round_trips() {
+        let info = VariantInfo { stored_size: 12, stored_hash: [1; 32], decoded_size: 1 << 40, decoded_hash: [2; 32] };
+        let mut buffer = [0; INFO_LEN+8];
+        assert_eq!(pack_info(&info, &mut buffer), INFO_LEN);
+        assert_eq!(unpack_info(&buffer), Some(info));
+        assert_eq!(unpack_info(&buffer[0..INFO_LEN]), Some(info));
+        assert_eq!(unpack_info(&buffer[0..INFO_LEN-1]), None);
+    }
+
+    #[test]
+    fn stream_decoder_hashes_both_sides() {
+        let decoded: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        //Two frames back to back decode to both of them
+        let mut stored = zstd::stream::encode_all(&decoded[0..150_000], 3).unwrap();
+        stored.extend(zstd::stream::encode_all(&decoded[150_000..], 3).unwrap());
+
+        let mut decoder = StreamDecoder::new().unwrap();
+        let mut out: Vec<u8> = Vec::new();
+        for piece in stored.chunks(504) {
+            decoder.write(piece, &mut out).unwrap();
+        }
+        decoder.write(&[], &mut out).unwrap();
+        let expected = VariantInfo { stored_size: stored.len() as u64, stored_hash: sha256(&stored), decoded_size: decoded.len() as u64, decoded_hash: sha256(&decoded) };
+        assert_eq!(decoder.finish().unwrap(), expected);
+        assert_eq!(out, decoded);
+
+        let directory = scratch("describe");
+        fs::write(directory.join("file.bin.zst"), &stored).unwrap();
+        assert_eq!(describe(&directory.join("file.bin.zst")).unwrap(), expected);
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn stream_decoder_rejects_a_truncated_frame() {
+        let stored = zstd::stream::encode_all(&[7u8; 10_000][..], 3).unwrap();
+        let mut decoder = StreamDecoder::new().unwrap();
+        decoder.write(&stored[0..stored.len()-1], &mut io::sink()).unwrap();
+        assert_eq!(decoder.finish().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
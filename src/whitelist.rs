@@ -0,0 +1,506 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+///How long a directory listing is handed out before the directory is walked again
+const LIST_TTL: Duration = Duration::from_secs(5);
+///Most directory listings kept at once
+const LIST_ENTRIES: usize = 64;
+
+///Sorted listing of a canonical directory and when it was walked
+type Listings = HashMap<PathBuf, (Instant, Arc<Vec<String>>)>;
+
+///A single line of the whitelist file
+///
+///deny: bool, true if the rule came from a line starting with '!'
+///absolute: bool, true if the pattern is matched against the full canonical path instead of the root relative one
+///segments: Vec<String>, the pattern split on '/', "**" segments match any number of path components
+struct Rule {
+    deny: bool,
+    absolute: bool,
+    segments: Vec<String>,
+}
+
+///Set of allow and deny rules deciding which files a server is willing to hand out
+///
+///Lines are glob patterns relative to the root directory, empty lines and lines starting with '#' are skipped
+///  releases/**/*.tar.gz   any tarball anywhere below releases
+///  docs/                  everything below docs
+///  !releases/secret/      deny rule, wins over every allow rule
+///Requested names are canonicalized before matching, so "a/../b" and symlinks are resolved first
+///and nothing outside of the root can ever match a relative pattern
+pub struct Whitelist {
+    root: PathBuf,
+    rules: Vec<Rule>,
+    deny_hidden: bool,
+    listings: Mutex<Listings>,
+}
+
+impl Whitelist {
+    ///Create an empty whitelist rooted at root, nothing is allowed until rules are added
+    pub fn new(root: &Path) -> io::Result<Self> {
+        Ok(Self {
+            root: fs::canonicalize(root)?,
+            rules: Vec::new(),
+            deny_hidden: false,
+            listings: Mutex::new(HashMap::new()),
+        })
+    }
+
+    ///Refuse every path with a component starting with '.' regardless of the rules
+    pub fn set_deny_hidden(&mut self, deny_hidden: bool) {
+        self.deny_hidden = deny_hidden;
+        self.forget_listings();
+    }
+
+    ///Load a whitelist file, patterns are relative to root
+    pub fn from_file(path: &Path, root: &Path) -> io::Result<Self> {
+        let mut whitelist = Whitelist::new(root)?;
+        let reader = io::BufReader::new(fs::File::open(path)?);
+        for line in reader.lines() {
+            whitelist.add_rule(&line?);
+        }
+        Ok(whitelist)
+    }
+
+    ///Parse one whitelist line and add it to the rule set
+    pub fn add_rule(&mut self, line: &str) {
+        let mut pattern = line.trim();
+        if pattern.is_empty() || pattern.starts_with('#') {
+            return;
+        }
+        let deny = pattern.starts_with('!');
+        if deny {
+            pattern = pattern[1..].trim_start();
+        }
+        let absolute = pattern.starts_with('/');
+        while let Some(stripped) = pattern.strip_prefix("./") {
+            pattern = stripped;
+        }
+
+        let mut segments: Vec<String> = pattern
+            .split('/')
+            .filter(|s| !s.is_empty() && *s != ".")
+            .map(String::from)
+            .collect();
+        //A trailing slash names a directory, match everything below it but not a file of that name
+        if pattern.ends_with('/') {
+            segments.push(String::from("*"));
+            segments.push(String::from("**"));
+        }
+        if segments.is_empty() {
+            return;
+        }
+
+        self.rules.push(Rule {
+            deny,
+            absolute,
+            segments,
+        });
+        self.forget_listings();
+    }
+
+    ///Listings made under the old rules are wrong now
+    fn forget_listings(&mut self) {
+        match self.listings.get_mut() {
+            Ok(l) => l.clear(),
+            Err(poisoned) => poisoned.into_inner().clear(),
+        }
+    }
+
+    ///The canonical directory relative patterns are matched against
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    ///Check a requested filename against the rules
+    ///Returns the canonical path to serve if the file is allowed, None if it is denied or does not exist
+    pub fn check(&self, requested: &str) -> Option<PathBuf> {
+        let canonical = fs::canonicalize(self.root.join(requested)).ok()?;
+        if !canonical.is_file() {
+            return None;
+        }
+        if self.allows(&canonical) {
+            Some(canonical)
+        } else {
+            None
+        }
+    }
+
+    ///Every allowed file below directory, as sorted '/' separated paths relative to the root
+    ///A client pages through the same listing, so it is kept for LIST_TTL instead of walking the directory for every page
+    pub fn list(&self, directory: &str) -> Arc<Vec<String>> {
+        let start = match fs::canonicalize(self.root.join(directory)) {
+            Ok(s) if s.starts_with(&self.root) => s,
+            _ => return Arc::new(Vec::new()),
+        };
+        if let Some((walked, files)) = self.listings().get(&start) {
+            if walked.elapsed() < LIST_TTL {
+                return Arc::clone(files);
+            }
+        }
+
+        //Walked without holding the lock, so other workers aren't held up by a big directory
+        let files = Arc::new(self.walk(&start));
+        let mut listings = self.listings();
+        listings.retain(|_, (walked, _)| walked.elapsed() < LIST_TTL);
+        if listings.len() >= LIST_ENTRIES {
+            listings.clear();
+        }
+        listings.insert(start, (Instant::now(), Arc::clone(&files)));
+        files
+    }
+
+    fn listings(&self) -> MutexGuard<'_, Listings> {
+        match self.listings.lock() {
+            Ok(l) => l,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    ///Every allowed file below the canonical directory start, sorted
+    ///Symlinked directories are not descended into so a listing can't loop forever
+    fn walk(&self, start: &Path) -> Vec<String> {
+        let mut files = Vec::new();
+        let mut pending = vec![start.to_path_buf()];
+        while let Some(dir) = pending.pop() {
+            let entries = match fs::read_dir(&dir) {
+                Ok(e) => e,
+                Err(_) => continue,
+            };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                let file_type = match entry.file_type() {
+                    Ok(t) => t,
+                    Err(_) => continue,
+                };
+                if file_type.is_dir() {
+                    pending.push(path);
+                    continue;
+                }
+                let canonical = match fs::canonicalize(&path) {
+                    Ok(c) => c,
+                    Err(_) => continue,
+                };
+                if canonical.is_file() && self.allows(&canonical) {
+                    if let Ok(relative) = path.strip_prefix(&self.root) {
+                        files.push(path_components(relative).join("/"));
+                    }
+                }
+            }
+        }
+        files.sort();
+        files
+    }
+
+    ///Evaluate the rules for an already canonical path, deny rules take precedence over allow rules
+    pub fn allows(&self, canonical: &Path) -> bool {
+        let absolute = path_components(canonical);
+        let relative = canonical.strip_prefix(&self.root).ok().map(path_components);
+        //Only components below the root count as hidden, the root itself may live anywhere
+        let visible = relative.as_ref().unwrap_or(&absolute);
+        if self.deny_hidden && visible.iter().any(|c| c.starts_with('.')) {
+            return false;
+        }
+
+        let mut allowed = false;
+        for rule in self.rules.iter() {
+            let matched = if rule.absolute {
+                match_segments(&rule.segments, &absolute)
+            } else {
+                match &relative {
+                    Some(r) => match_segments(&rule.segments, r),
+                    None => false,
+                }
+            };
+            if matched {
+                if rule.deny {
+                    return false;
+                }
+                allowed = true;
+            }
+        }
+        allowed
+    }
+}
+
+fn path_components(path: &Path) -> Vec<String> {
+    path.components()
+        .filter_map(|c| match c {
+            std::path::Component::Normal(s) => Some(s.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect()
+}
+
+///Match pattern segments against path components, "**" swallows zero or more components
+///Only the last "**" is ever backtracked to, so a name costs at most pattern times path steps
+fn match_segments(pattern: &[String], path: &[String]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    //Pattern position after the last "**" and how many components it swallows so far
+    let mut star: Option<(usize, usize)> = None;
+    while n < path.len() {
+        if p < pattern.len() && pattern[p] == "**" {
+            star = Some((p+1, n));
+            p += 1;
+        } else if p < pattern.len() && match_component(pattern[p].as_bytes(), path[n].as_bytes()) {
+            p += 1;
+            n += 1;
+        } else if let Some((after, swallowed)) = star {
+            //Let the "**" swallow one more component and go on from there
+            star = Some((after, swallowed+1));
+            p = after;
+            n = swallowed+1;
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|s| s == "**")
+}
+
+///Match a single path component against a glob supporting *, ? and [abc]/[a-z]/[!abc] classes
+///Greedy like match_segments, only the last * is backtracked to
+fn match_component(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    //Pattern position after the last * and how many bytes it swallows so far
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if pattern.get(p) == Some(&b'*') {
+            star = Some((p+1, n));
+            p += 1;
+            continue;
+        }
+        if let Some(rest) = match_byte(&pattern[p..], name[n]) {
+            p = pattern.len() - rest.len();
+            n += 1;
+        } else if let Some((after, swallowed)) = star {
+            star = Some((after, swallowed+1));
+            p = after;
+            n = swallowed+1;
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+///Match a byte against the first element of pattern, anything but *
+///Returns the rest of the pattern if it matched
+fn match_byte(pattern: &[u8], c: u8) -> Option<&[u8]> {
+    match pattern.split_first()? {
+        (b'*', _) => None,
+        (b'?', rest) => Some(rest),
+        (b'[', rest) => match match_class(rest, c) {
+            Some((true, after)) => Some(after),
+            Some((false, _)) => None,
+            //No closing bracket, treat the [ as a literal
+            None if c == b'[' => Some(rest),
+            None => None,
+        },
+        (literal, rest) if *literal == c => Some(rest),
+        _ => None,
+    }
+}
+
+///Match a byte against a bracket class, pattern starts right after the '['
+///Returns whether it matched and the remainder of the pattern after the closing ']'
+fn match_class(pattern: &[u8], c: u8) -> Option<(bool, &[u8])> {
+    let mut i = 0;
+    let negate = matches!(pattern.first(), Some(b'!') | Some(b'^'));
+    if negate {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    while i < pattern.len() {
+        if pattern[i] == b']' && !first {
+            return Some((matched != negate, &pattern[i + 1..]));
+        }
+        first = false;
+        if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']' {
+            if pattern[i] <= c && c <= pattern[i + 2] {
+                matched = true;
+            }
+            i += 3;
+        } else {
+            if pattern[i] == c {
+                matched = true;
+            }
+            i += 1;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segments(path: &str) -> Vec<String> {
+        path.split('/').map(String::from).collect()
+    }
+
+    fn whitelist(root: &Path, rules: &[&str]) -> Whitelist {
+        let mut whitelist = Whitelist::new(root).unwrap();
+        for rule in rules.iter() {
+            whitelist.add_rule(rule);
+        }
+        whitelist
+    }
+
+    ///An empty directory of its own for a test, with root/dir made inside it
+    fn scratch(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("basic_udp-whitelist-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(directory.join("root/dir")).unwrap();
+        directory
+    }
+
+    #[test]
+    fn double_star_matches_zero_components() {
+        let pattern = segments("releases/**/*.tar.gz");
+        assert!(match_segments(&pattern, &segments("releases/a.tar.gz")));
+        assert!(match_segments(&pattern, &segments("releases/v1/a.tar.gz")));
+        assert!(match_segments(&pattern, &segments("releases/v1/rc/a.tar.gz")));
+        assert!(!match_segments(&pattern, &segments("other/a.tar.gz")));
+        assert!(!match_segments(&pattern, &segments("releases/a.zip")));
+    }
+
+    #[test]
+    fn trailing_slash_matches_everything_below() {
+        let root = scratch("trailing");
+        let whitelist = whitelist(&root, &["docs/"]);
+        let root = fs::canonicalize(&root).unwrap();
+        assert!(whitelist.allows(&root.join("docs/a.txt")));
+        assert!(whitelist.allows(&root.join("docs/deep/er/b.txt")));
+        assert!(!whitelist.allows(&root.join("docs")));
+        assert!(!whitelist.allows(&root.join("docsx/a.txt")));
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn classes() {
+        assert!(match_component(b"v[0-9].txt", b"v7.txt"));
+        assert!(!match_component(b"v[0-9].txt", b"vx.txt"));
+        assert!(match_component(b"[!a-z]1", b"A1"));
+        assert!(!match_component(b"[!a-z]1", b"b1"));
+        assert!(match_component(b"[^a-z]1", b"_1"));
+        //A ] right after the [ is part of the class
+        assert!(match_component(b"[]x]", b"]"));
+        assert!(match_component(b"*.[ch]", b"main.c"));
+        assert!(!match_component(b"*.[ch]", b"main.o"));
+    }
+
+    #[test]
+    fn unclosed_bracket_is_literal() {
+        assert!(match_component(b"file[1", b"file[1"));
+        assert!(!match_component(b"file[1", b"file1"));
+        assert!(!match_component(b"[", b"a"));
+        assert!(match_component(b"[", b"["));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let root = scratch("deny");
+        for rules in [["releases/**", "!releases/secret/"], ["!releases/secret/", "releases/**"]].iter() {
+            let whitelist = whitelist(&root, rules);
+            let canonical = fs::canonicalize(&root).unwrap();
+            assert!(whitelist.allows(&canonical.join("releases/a.tar.gz")));
+            assert!(!whitelist.allows(&canonical.join("releases/secret/key")));
+            assert!(!whitelist.allows(&canonical.join("releases/secret/deeper/key")));
+        }
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn check_stays_inside_the_root() {
+        let directory = scratch("escape");
+        let root = directory.join("root");
+        fs::write(directory.join("outside.txt"), b"secret").unwrap();
+        fs::write(root.join("dir/inside.txt"), b"public").unwrap();
+        let whitelist = whitelist(&root, &["**"]);
+        assert!(whitelist.check("dir/inside.txt").is_some());
+        assert!(whitelist.check("dir/../dir/inside.txt").is_some());
+        assert_eq!(whitelist.check("../outside.txt"), None);
+        assert_eq!(whitelist.check("dir/../../outside.txt"), None);
+        assert_eq!(whitelist.check("dir"), None);
+        assert_eq!(whitelist.check("missing.txt"), None);
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(directory.join("outside.txt"), root.join("dir/link.txt")).unwrap();
+            std::os::unix::fs::symlink(&directory, root.join("up")).unwrap();
+            assert_eq!(whitelist.check("dir/link.txt"), None);
+            assert_eq!(whitelist.check("up/outside.txt"), None);
+            assert!(!whitelist.list("").iter().any(|f| f.contains("outside")));
+        }
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn deny_hidden() {
+        let directory = scratch("hidden");
+        let root = directory.join("root");
+        fs::create_dir_all(root.join(".git")).unwrap();
+        fs::write(root.join(".git/config"), b"").unwrap();
+        fs::write(root.join("dir/.env"), b"").unwrap();
+        fs::write(root.join("dir/visible.txt"), b"").unwrap();
+        let mut whitelist = whitelist(&root, &["**"]);
+        assert!(whitelist.check(".git/config").is_some());
+        assert!(whitelist.check("dir/.env").is_some());
+        whitelist.set_deny_hidden(true);
+        assert_eq!(whitelist.check(".git/config"), None);
+        assert_eq!(whitelist.check("dir/.env"), None);
+        assert!(whitelist.check("dir/visible.txt").is_some());
+        assert_eq!(*whitelist.list(""), vec!["dir/visible.txt".to_string()]);
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn listing_is_kept_until_the_rules_change() {
+        let directory = scratch("listing");
+        let root = directory.join("root");
+        fs::write(root.join("dir/a.txt"), b"").unwrap();
+        let mut whitelist = whitelist(&root, &["dir/"]);
+        assert_eq!(*whitelist.list("dir"), vec!["dir/a.txt".to_string()]);
+        //Pages of the same listing come from the one walk
+        fs::write(root.join("dir/b.txt"), b"").unwrap();
+        assert_eq!(*whitelist.list("dir/"), vec!["dir/a.txt".to_string()]);
+        whitelist.add_rule("!dir/a.txt");
+        assert_eq!(*whitelist.list("dir"), vec!["dir/b.txt".to_string()]);
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn adversarial_names_stay_linear() {
+        let started = std::time::Instant::now();
+        let dashes = "-".repeat(1024);
+        assert!(!match_component(b"*-*-*.tar.gz", dashes.as_bytes()));
+        assert!(!match_component(b"*a*a*a*a*a*b", "a".repeat(1024).as_bytes()));
+        assert!(match_component(b"*-*-*.tar.gz", format!("{}.tar.gz", dashes).as_bytes()));
+        let pattern = segments("releases/**/**/**/*-*-*.tar.gz");
+        let mut path = segments(&"-/".repeat(500));
+        path.insert(0, String::from("releases"));
+        path.push(dashes.clone());
+        assert!(!match_segments(&pattern, &path));
+        path.push(String::from("a-b-c.tar.gz"));
+        assert!(match_segments(&pattern, &path));
+        //Backtracking into every split would take minutes
+        assert!(started.elapsed() < std::time::Duration::from_secs(5), "{:?}", started.elapsed());
+    }
+
+    #[test]
+    fn stars_backtrack() {
+        assert!(match_component(b"a*b*c", b"aXbYbZc"));
+        assert!(match_component(b"*.tar.gz", b"x.tar.tar.gz"));
+        assert!(!match_component(b"*.tar.gz", b"x.tar.gzz"));
+        assert!(match_component(b"**", b""));
+        assert!(match_component(b"a?c*", b"abc"));
+        assert!(!match_component(b"a?c", b"ac"));
+        assert!(match_segments(&segments("a/**/b/**/c"), &segments("a/x/b/y/b/z/c")));
+        assert!(!match_segments(&segments("a/**/b/c"), &segments("a/b/c/d")));
+    }
+}